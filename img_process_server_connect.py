@@ -11,6 +11,24 @@ SERVER_PIPE_NAME = "img_process_server"
 READ_BUFFER_SIZE = 4069
 READ_TIMEOUT = 60
 MAX_RETRY = 3
+STATUS_OK = 0
+STATUS_ERROR = 1
+STATUS_RATE_LIMITED = 2
+STATUS_IMAGE_FAILED = 3
+
+
+class ImageProcessServerError(Exception):
+    pass
+
+
+class RateLimitedError(ImageProcessServerError):
+    pass
+
+
+class ImageFailedError(ImageProcessServerError):
+    """One image of a gets failed to load, the replies of the other images still follow"""
+    pass
+
 
 def connect_to_pipe(name: str):
     while True:
@@ -39,10 +57,12 @@ def connect_to_pipe(name: str):
 def read_msg(handle, buffer_size) -> bytearray:
     try:
         while True:
-            msg_length_bytes = win32file.ReadFile(handle, 4)[1]
-            if msg_length_bytes == None:
+            status_bytes = win32file.ReadFile(handle, 1)[1]
+            if status_bytes == None:
                 time.sleep(0.01)
                 continue
+            status = status_bytes[0]
+            msg_length_bytes = win32file.ReadFile(handle, 4)[1]
             msg_length = int.from_bytes(msg_length_bytes, "big", signed=False)
             msg = bytearray()
             #print(msg_length)
@@ -50,6 +70,12 @@ def read_msg(handle, buffer_size) -> bytearray:
                 remaining_bytes =  msg_length - len(msg)
                 resp = win32file.ReadFile(handle, min(buffer_size, remaining_bytes))[1]
                 msg.extend(resp)
+            if status == STATUS_ERROR:
+                raise ImageProcessServerError(msg.decode(encoding="utf-8"))
+            if status == STATUS_RATE_LIMITED:
+                raise RateLimitedError(msg.decode(encoding="utf-8"))
+            if status == STATUS_IMAGE_FAILED:
+                raise ImageFailedError(msg.decode(encoding="utf-8"))
             return msg
     except pywintypes.error as e:
         if e.args[0] == 2:
@@ -70,34 +96,61 @@ def write_msg(handle, data):
             raise Exception("broken pipe, bye bye")
 
 
+def encode_command(args: list) -> bytes:
+    # Every argument is length prefixed, so they can contain any character
+    encoded_args = [str(arg).encode(encoding="utf-8") for arg in args]
+    data = struct.pack(">I", len(encoded_args))
+    for encoded_arg in encoded_args:
+        data += struct.pack(">I", len(encoded_arg)) + encoded_arg
+    return data
+
+
 class ImageProcessServerConnect:
 
-    def __init__(self, cache_dir: str, threaded_reads: bool, working_dir = "./"):
+    def __init__(self, cache_dir: str, threaded_reads: bool, working_dir = "./", thread_count: int = None):
         subprocess.Popen(["img_process_server.exe"])
         self.handle = connect_to_pipe(SERVER_PIPE_NAME)
-        self.send_command("setup", [cache_dir, working_dir, str(threaded_reads).lower()])
+        setup_options = [f"threads={thread_count}"] if thread_count else []
+        self.send_command("setup", [cache_dir, working_dir, str(threaded_reads).lower()] + setup_options)
+        read_msg(self.handle, READ_BUFFER_SIZE)
         self.current_command = None
 
     def send_command(self, command_type : str, args : list):
-        command_string = command_type + "|" + "|".join([str(arg) for arg in args])
-        self.current_command = command_string
-        write_msg(self.handle, command_string.encode(encoding="utf-8"))
+        self.current_command = command_type
+        write_msg(self.handle, encode_command([command_type] + args))
+
 
+    def shutdown(self, clear_cache: bool = True):
+        self.send_command("shutdown", ["clear_cache"] if clear_cache else [])
+        read_msg(self.handle, READ_BUFFER_SIZE)
+        win32file.CloseHandle(self.handle)
 
-    def _ask_for_images(self, paths : list, width : int, height : int, output_images: list):
-        self.send_command("gets", [width, height] + paths)
-        for _ in paths:
-            data = read_msg(self.handle, READ_BUFFER_SIZE)
-            image = cv2.imdecode(np.frombuffer(data, dtype=np.uint8), cv2.IMREAD_COLOR)
-            output_images.append(image)
+    def _ask_for_images(self, paths : list, width : int, height : int, options : list, output_images: list, errors: list):
+        self.send_command("gets", [width, height] + options + ["--"] + paths)
+        try:
+            for _ in paths:
+                try:
+                    data = read_msg(self.handle, READ_BUFFER_SIZE)
+                except ImageFailedError as e:
+                    # The error takes the place of the image, so the other images stay at the index of their path
+                    output_images.append(e)
+                    continue
+                image = cv2.imdecode(np.frombuffer(data, dtype=np.uint8), cv2.IMREAD_COLOR)
+                output_images.append(image)
+        except ImageProcessServerError as e:
+            # Raising it here would only end the thread, so ask_for_images raises it
+            errors.append(e)
         self.current_command = None
 
-    def ask_for_images(self, paths : list, width : int, height : int) -> list:
+    def ask_for_images(self, paths : list, width : int, height : int, output_format : str = None) -> list:
+        """Returns the images in the order of the paths, images which failed to load are an ImageFailedError with the error message of the server"""
+        options = [output_format] if output_format else []
         while self.current_command != None:
             time.sleep(0.1)
         output_images = []
+        errors = []
         for _ in range(0, MAX_RETRY):
-            thread = Thread(target=self._ask_for_images, args=(paths, width, height, output_images))
+            thread = Thread(target=self._ask_for_images, args=(paths, width, height, options, output_images, errors))
             thread.start()
             thread.join(timeout=READ_TIMEOUT)
             if thread.is_alive():
@@ -111,6 +164,8 @@ class ImageProcessServerConnect:
         else:
             raise TimeoutError("Could not get images. Max retries reached")
         self.current_command = None
+        if errors:
+            raise errors[0]
         return output_images
 
 
@@ -0,0 +1,985 @@
+//! The cache of encoded images, decoded source images and information computed from them
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::anyhow;
+use image::{DynamicImage, ImageFormat};
+use sysinfo::{System, SystemExt, RefreshKind};
+use crate::EncodedImage;
+use crate::remote::RemoteSource;
+
+const EVICTION_TARGET: f64 = 0.9;
+const MAX_CACHED_PATHS: usize = 1024;
+/// How long a reading of the available memory is reused
+const MEMORY_REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How an image cached on disk is stored
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum DiskFormat {
+    /// The encoded image, as it is sent
+    Raw,
+    /// A BMP image stored as PNG, to take up less space. It is encoded as BMP again when it is read
+    Png
+}
+
+impl DiskFormat {
+    const ALL: [DiskFormat; 2] = [Self::Png, Self::Raw];
+
+    /// Images are stored in a subdirectory of the cache dir per format, named like this and with this extension
+    fn name(self) -> &'static str {
+        match self {
+            Self::Raw => "raw",
+            Self::Png => "png"
+        }
+    }
+
+    /// Previous versions stored the images directly in the cache dir, raw images with this extension no matter their format
+    fn legacy_extension(self) -> &'static str {
+        match self {
+            Self::Raw => "bmp",
+            Self::Png => "png"
+        }
+    }
+}
+
+enum CacheType {
+    OnDisk(u32, DiskFormat),
+    InMemory(Arc<Vec<u8>>),
+    /// Compressed with LZ4, with the uncompressed size prepended
+    Compressed(Vec<u8>)
+}
+
+impl CacheType {
+    /// How many bytes of memory the image takes up
+    fn memory_size(&self) -> usize {
+        match self {
+            Self::OnDisk(..) => 0,
+            Self::InMemory(img_bytes) => img_bytes.len(),
+            Self::Compressed(compressed_bytes) => compressed_bytes.len()
+        }
+    }
+}
+
+/// An encoded image prepared to be cached on disk
+pub struct DiskImage<'a> {
+    bytes: Cow<'a, [u8]>,
+    format: DiskFormat
+}
+
+impl<'a> DiskImage<'a> {
+    /// BMP images with 24 or 32 bits per pixel are compressed as PNG, they are encoded as BMP again to exactly the same bytes.
+    /// Other images are already compressed, or are palette images, which would be read back with a different palette, so they are stored as they are
+    pub fn new(img_bytes: &'a [u8]) -> anyhow::Result<Self> {
+        let is_true_color_bmp = img_bytes.starts_with(b"BM") && matches!(img_bytes.get(28), Some(24 | 32));
+        if !is_true_color_bmp {
+            return Ok(Self {bytes: Cow::Borrowed(img_bytes), format: DiskFormat::Raw});
+        }
+        let img = image::load_from_memory_with_format(img_bytes, ImageFormat::Bmp)?;
+        let mut png_bytes = Vec::new();
+        img.write_to(&mut png_bytes, ImageFormat::Png)?;
+        Ok(Self {bytes: Cow::Owned(png_bytes), format: DiskFormat::Png})
+    }
+}
+
+/// Creates the cache dir if it is missing and checks that files can be written to it, so caching on disk doesn't fail later
+pub fn prepare_cache_dir(cache_dir: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(cache_dir).map_err(|e| anyhow!("Cannot create cache dir {} : {}", cache_dir.display(), e))?;
+    let test_path = cache_dir.join(".write_test");
+    std::fs::write(&test_path, []).map_err(|e| anyhow!("Cache dir {} is not writable : {}", cache_dir.display(), e))?;
+    std::fs::remove_file(&test_path)?;
+    for format in DiskFormat::ALL {
+        let format_dir = cache_dir.join(format.name());
+        std::fs::create_dir_all(&format_dir).map_err(|e| anyhow!("Cannot create cache dir {} : {}", format_dir.display(), e))?;
+    }
+    Ok(())
+}
+
+fn read_disk_image(path: &Path, format: DiskFormat) -> anyhow::Result<Vec<u8>> {
+    let bytes = std::fs::read(path)?;
+    match format {
+        DiskFormat::Raw => Ok(bytes),
+        DiskFormat::Png => {
+            let img = image::load_from_memory_with_format(&bytes, ImageFormat::Png)?;
+            let mut bmp_bytes = Vec::new();
+            img.write_to(&mut bmp_bytes, ImageFormat::Bmp)?;
+            Ok(bmp_bytes)
+        }
+    }
+}
+
+struct CacheEntry {
+    cache_type: CacheType,
+    /// The width and height of the encoded image, so they don't have to be read from its header
+    dimensions: (u32, u32),
+    /// Value of `ImageCache::use_counter` when this entry was last used
+    last_used: AtomicU64,
+    /// When the local source file was modified, `None` for images over HTTP
+    modified: Option<SystemTime>,
+    /// Identifies the source bytes and how they were processed, see `get_content_key`
+    content_key: Option<u64>
+}
+
+type CachedImages = HashMap<String, CacheEntry>;
+/// Maps content keys to the key of an image processed from that content
+type ContentKeys = HashMap<u64, String>;
+
+/// A decoded source image, so it can be resized and encoded again without reading and decoding it
+struct DecodedEntry {
+    img: Arc<DynamicImage>,
+    /// The EXIF orientation, which is not applied to `img` yet
+    orientation: u16,
+    last_used: AtomicU64,
+    modified: Option<SystemTime>
+}
+
+type DecodedImages = HashMap<String, DecodedEntry>;
+
+/// Information computed from a source image, like its blurhash
+struct InfoEntry {
+    value: String,
+    modified: Option<SystemTime>
+}
+
+/// A path which could not be loaded, so it isn't loaded again right away
+struct FailedEntry {
+    message: String,
+    failed_at: Instant
+}
+
+type FailedPaths = HashMap<String, FailedEntry>;
+
+/// Keyed by the path and the name of the information, including its parameters
+type CachedInfos = HashMap<(String, String), InfoEntry>;
+/// Maps to the `ImageCache::use_counter` value when the request was last made
+type CachedPaths = HashMap<u64, AtomicU64>;
+
+/// How many images are cached and how often they were found in the cache
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub entries: usize,
+    /// How many of the entries are cached on disk
+    pub disk_entries: usize,
+    pub decoded_entries: usize,
+    /// Total size of all in memory images, including the decoded ones
+    pub memory_bytes: usize,
+    pub hits: u64,
+    pub misses: u64
+}
+
+pub struct ImageCache {
+    /// Images cached on disk are stored in this directory, named by their cache id
+    cache_dir: PathBuf,
+    /// Disk cache ids are never reused, so removed entries can't collide with new ones
+    next_cache_id: u32,
+    images: CachedImages,
+    /// So images with the same content, but another path, don't have to be processed again
+    contents: ContentKeys,
+    /// Decoded source images by path, only used if `cache_decoded` was set up
+    decoded: DecodedImages,
+    infos: CachedInfos,
+    failed: FailedPaths,
+    /// How to ask the servers of images over HTTP, whether they changed, by URL
+    remote_sources: HashMap<String, RemoteSource>,
+    /// Keys of `gets` requests, for which all images are cached, see `get_paths_key`
+    paths: CachedPaths,
+    /// Total size of all in memory images, including the decoded ones
+    memory_bytes: usize,
+    /// Once the in memory images exceed this, the least recently used ones are evicted
+    max_memory_bytes: usize,
+    /// Nothing is written to or read from `cache_dir`, so it may be read-only or missing
+    memory_only: bool,
+    use_counter: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64
+}
+
+impl ImageCache {
+    pub fn new(cache_dir: PathBuf, capacity: usize, max_memory_bytes: usize, memory_only: bool) -> Self {
+        Self {
+            cache_dir,
+            next_cache_id: 0,
+            images: HashMap::with_capacity(capacity),
+            contents: Default::default(),
+            decoded: Default::default(),
+            infos: Default::default(),
+            failed: Default::default(),
+            remote_sources: Default::default(),
+            paths: Default::default(),
+            memory_bytes: 0,
+            max_memory_bytes,
+            memory_only,
+            use_counter: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0)
+        }
+    }
+
+    fn next_use(&self) -> u64 {
+        self.use_counter.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn disk_cache_path(&self, cache_id: u32, format: DiskFormat) -> PathBuf {
+        self.cache_dir.join(format.name()).join(format!("{}.{}", cache_id, format.name()))
+    }
+
+    fn legacy_disk_cache_path(&self, cache_id: u32, format: DiskFormat) -> PathBuf {
+        self.cache_dir.join(format!("{}.{}", cache_id, format.legacy_extension()))
+    }
+
+    /// Moves an image a previous version cached directly in the cache dir into the directory of its format, returns false if there is none
+    fn move_legacy_disk_image(&self, cache_id: u32, format: DiskFormat) -> bool {
+        let legacy_path = self.legacy_disk_cache_path(cache_id, format);
+        legacy_path.exists() && std::fs::rename(legacy_path, self.disk_cache_path(cache_id, format)).is_ok()
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.cache_dir.join("index.txt")
+    }
+
+    fn insert(&mut self, cache_key: String, cache_type: CacheType, dimensions: (u32, u32), modified: Option<SystemTime>, content_key: Option<u64>) -> anyhow::Result<()> {
+        self.memory_bytes += cache_type.memory_size();
+        let entry = CacheEntry {cache_type, dimensions, last_used: AtomicU64::new(self.next_use()), modified, content_key};
+        if let Some(replaced_entry) = self.images.insert(cache_key.clone(), entry) {
+            self.remove_entry(&cache_key, replaced_entry)?;
+        }
+        if let Some(content_key) = content_key {
+            self.contents.insert(content_key, cache_key);
+        }
+        Ok(())
+    }
+
+    /// Caches the image in memory, evicting the least recently used images if the memory budget is exceeded.
+    /// Images with the same content share their bytes, but each counts towards the budget
+    pub fn insert_in_memory(&mut self, cache_key: String, img: EncodedImage, modified: Option<SystemTime>, content_key: Option<u64>) -> anyhow::Result<()> {
+        self.insert(cache_key, CacheType::InMemory(img.bytes), (img.width, img.height), modified, content_key)?;
+        self.evict()
+    }
+
+    /// Like `insert_in_memory`, for an image compressed by `lz4_flex::compress_prepend_size`, which is decompressed when it is read
+    pub fn insert_compressed(&mut self, cache_key: String, compressed_bytes: Vec<u8>, dimensions: (u32, u32), modified: Option<SystemTime>, content_key: Option<u64>) -> anyhow::Result<()> {
+        self.insert(cache_key, CacheType::Compressed(compressed_bytes), dimensions, modified, content_key)?;
+        self.evict()
+    }
+
+    pub fn insert_on_disk(&mut self, cache_key: String, img: DiskImage, dimensions: (u32, u32), modified: Option<SystemTime>, content_key: Option<u64>) -> anyhow::Result<()> {
+        let cache_id = self.next_cache_id;
+        self.next_cache_id += 1;
+        std::fs::write(self.disk_cache_path(cache_id, img.format), &img.bytes)?;
+        let line = index_line(cache_id, modified, dimensions, &cache_key);
+        self.insert(cache_key, CacheType::OnDisk(cache_id, img.format), dimensions, modified, content_key)?;
+        // So the image is still found after a crash. A later line of the same key replaces this one when the index is loaded
+        let Some(line) = line else {return Ok(())};
+        let mut index = std::fs::OpenOptions::new().create(true).append(true).open(self.index_path())?;
+        index.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn insert_decoded(&mut self, path: String, img: Arc<DynamicImage>, orientation: u16, modified: Option<SystemTime>) -> anyhow::Result<()> {
+        self.memory_bytes += img.as_bytes().len();
+        let entry = DecodedEntry {img, orientation, last_used: AtomicU64::new(self.next_use()), modified};
+        if let Some(replaced_entry) = self.decoded.insert(path, entry) {
+            self.memory_bytes -= replaced_entry.img.as_bytes().len();
+        }
+        self.evict()
+    }
+
+    pub fn insert_info(&mut self, path: String, info_name: String, value: String, modified: Option<SystemTime>) {
+        self.infos.insert((path, info_name), InfoEntry {value, modified});
+    }
+
+    pub fn get_remote_source(&self, url: &str) -> Option<RemoteSource> {
+        self.remote_sources.get(url).cloned()
+    }
+
+    pub fn insert_remote_source(&mut self, url: String, source: RemoteSource) {
+        self.remote_sources.insert(url, source);
+    }
+
+    /// Entries of local files, which were modified since they were cached, count as a miss
+    pub fn get(&self, cache_key: &str, modified: Option<SystemTime>) -> anyhow::Result<Option<EncodedImage>> {
+        let Some(entry) = self.images.get(cache_key).filter(|entry| entry.modified == modified) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        };
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        entry.last_used.store(self.next_use(), Ordering::Relaxed);
+        Ok(Some(self.read_entry(entry)?))
+    }
+
+    /// Like `get`, without counting as a hit, a miss or a use
+    pub fn peek(&self, cache_key: &str, modified: Option<SystemTime>) -> anyhow::Result<Option<EncodedImage>> {
+        self.images.get(cache_key)
+            .filter(|entry| entry.modified == modified)
+            .map(|entry| self.read_entry(entry))
+            .transpose()
+    }
+
+    fn read_entry(&self, entry: &CacheEntry) -> anyhow::Result<EncodedImage> {
+        let bytes = match &entry.cache_type {
+            CacheType::OnDisk(cache_id, format) => Arc::new(read_disk_image(&self.disk_cache_path(*cache_id, *format), *format)?),
+            CacheType::InMemory(img_bytes) => img_bytes.clone(),
+            CacheType::Compressed(compressed_bytes) => Arc::new(lz4_flex::decompress_size_prepended(compressed_bytes)
+                .map_err(|e| anyhow!("Cannot decompress cached image : {}", e))?)
+        };
+        let (width, height) = entry.dimensions;
+        Ok(EncodedImage {bytes, width, height})
+    }
+
+    /// The image processed from the same content as described by `content_key`, no matter its path
+    pub fn get_by_content(&self, content_key: u64) -> anyhow::Result<Option<EncodedImage>> {
+        let entry = self.contents.get(&content_key)
+            .and_then(|cache_key| self.images.get(cache_key))
+            .filter(|entry| entry.content_key == Some(content_key));
+        let Some(entry) = entry else {return Ok(None)};
+        entry.last_used.store(self.next_use(), Ordering::Relaxed);
+        Ok(Some(self.read_entry(entry)?))
+    }
+
+    /// Like `get`, without counting as a use
+    pub fn contains(&self, cache_key: &str, modified: Option<SystemTime>) -> bool {
+        self.images.get(cache_key).is_some_and(|entry| entry.modified == modified)
+    }
+
+    /// Like `contains`, no matter when the source file was modified
+    pub fn contains_key(&self, cache_key: &str) -> bool {
+        self.images.contains_key(cache_key)
+    }
+
+    pub fn contains_decoded(&self, path: &str, modified: Option<SystemTime>) -> bool {
+        self.decoded.get(path).is_some_and(|entry| entry.modified == modified)
+    }
+
+    pub fn get_decoded(&self, path: &str, modified: Option<SystemTime>) -> Option<(Arc<DynamicImage>, u16)> {
+        let entry = self.decoded.get(path).filter(|entry| entry.modified == modified)?;
+        entry.last_used.store(self.next_use(), Ordering::Relaxed);
+        Some((entry.img.clone(), entry.orientation))
+    }
+
+    pub fn get_info(&self, path: &str, info_name: &str, modified: Option<SystemTime>) -> Option<String> {
+        let info_key = (path.to_string(), info_name.to_string());
+        self.infos.get(&info_key)
+            .filter(|entry| entry.modified == modified)
+            .map(|entry| entry.value.clone())
+    }
+
+    /// The error message of `path`, if loading it failed less than `ttl` ago
+    pub fn get_failure(&self, path: &str, ttl: Duration) -> Option<String> {
+        self.failed.get(path)
+            .filter(|entry| entry.failed_at.elapsed() < ttl)
+            .map(|entry| entry.message.clone())
+    }
+
+    /// Remembers that loading `path` failed, forgetting the failures older than `ttl`
+    pub fn insert_failure(&mut self, path: String, message: String, ttl: Duration) {
+        self.failed.retain(|_, entry| entry.failed_at.elapsed() < ttl);
+        self.failed.insert(path, FailedEntry {message, failed_at: Instant::now()});
+    }
+
+    fn remove_decoded(&mut self, path: &str) -> bool {
+        let Some(entry) = self.decoded.remove(path) else {return false};
+        self.memory_bytes -= entry.img.as_bytes().len();
+        true
+    }
+
+    fn remove_entry(&mut self, cache_key: &str, entry: CacheEntry) -> anyhow::Result<()> {
+        if let Some(content_key) = entry.content_key {
+            // Only forget the content, if it maps to this image and not to another one with the same content
+            if self.contents.get(&content_key).is_some_and(|content_cache_key| content_cache_key == cache_key) {
+                self.contents.remove(&content_key);
+            }
+        }
+        self.memory_bytes -= entry.cache_type.memory_size();
+        if let CacheType::OnDisk(cache_id, format) = entry.cache_type {
+            let path = self.disk_cache_path(cache_id, format);
+            match std::fs::remove_file(&path) {
+                // The file might have been deleted by someone else
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(anyhow!("Cannot remove {} : {}", path.display(), e)),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes all of the entries, even if the files of some of them can't be removed. Those are only counted in the error
+    fn remove_entries(&mut self, entries: Vec<(String, CacheEntry)>) -> anyhow::Result<()> {
+        let mut failed = 0;
+        let mut first_error = None;
+        for (cache_key, entry) in entries {
+            if let Err(e) = self.remove_entry(&cache_key, entry) {
+                failed += 1;
+                first_error.get_or_insert(e);
+            }
+        }
+        match first_error {
+            Some(e) => Err(anyhow!("Cannot remove {} of the images cached on disk : {}", failed, e)),
+            None => Ok(())
+        }
+    }
+
+    pub fn is_fully_cached(&self, paths_key: u64) -> bool {
+        match self.paths.get(&paths_key) {
+            Some(last_used) => {
+                last_used.store(self.next_use(), Ordering::Relaxed);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Remembers that all images of a `gets` request are cached, forgetting the least recently used request if there are too many
+    pub fn set_fully_cached(&mut self, paths_key: u64) {
+        if self.paths.len() >= MAX_CACHED_PATHS && !self.paths.contains_key(&paths_key) {
+            let least_recently_used = self.paths.iter()
+                .min_by_key(|(_, last_used)| last_used.load(Ordering::Relaxed))
+                .map(|(paths_key, _)| *paths_key);
+            if let Some(least_recently_used) = least_recently_used {
+                self.paths.remove(&least_recently_used);
+            }
+        }
+        let last_used = AtomicU64::new(self.next_use());
+        self.paths.insert(paths_key, last_used);
+    }
+
+    pub fn cached_paths_count(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Writes the keys of the images cached on disk to the index file, so they can be loaded again after a restart.
+    /// Images are also appended to it when they are cached on disk, this also drops the lines of the removed ones
+    pub fn write_index(&self) -> anyhow::Result<()> {
+        if self.memory_only {return Ok(());}
+        let mut index = String::new();
+        for (cache_key, entry) in &self.images {
+            let CacheType::OnDisk(cache_id, _) = entry.cache_type else {continue};
+            let Some(line) = index_line(cache_id, entry.modified, entry.dimensions, cache_key) else {continue};
+            index.push_str(&line);
+        }
+        std::fs::write(self.index_path(), index)?;
+        Ok(())
+    }
+
+    /// Loads the images cached on disk by a previous run, skipping those whose file is gone.
+    /// Files which aren't in the index, like the ones of a run which crashed before writing it, are removed
+    pub fn load_index(&mut self) -> anyhow::Result<()> {
+        if self.memory_only {return Ok(());}
+        let index = match std::fs::read_to_string(self.index_path()) {
+            Ok(index) => index,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into())
+        };
+        for line in index.lines() {
+            let mut parts = line.splitn(3, '\t');
+            let (Some(cache_id), Some(modified), Some(rest)) = (parts.next(), parts.next(), parts.next()) else {continue};
+            // Indexes of previous versions don't have the size, it is read from the file instead
+            let (dimensions, cache_key) = match rest.split_once('\t').and_then(|(size, cache_key)| Some((parse_size(size)?, cache_key))) {
+                Some((dimensions, cache_key)) => (Some(dimensions), cache_key),
+                None => (None, rest)
+            };
+            let Ok(cache_id) = cache_id.parse::<u32>() else {continue};
+            // Ids of lines, whose file is gone, aren't used again either, so a line left in the index never names the file of a new image
+            self.next_cache_id = self.next_cache_id.max(cache_id.saturating_add(1));
+            let modified = match modified {
+                "-" => None,
+                nanos => {
+                    let Ok(nanos) = nanos.parse::<u64>() else {continue};
+                    Some(SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos))
+                }
+            };
+            // The format isn't part of the index, but the directory of the file tells it
+            let format = DiskFormat::ALL.into_iter()
+                .find(|format| self.disk_cache_path(cache_id, *format).exists())
+                .or_else(|| DiskFormat::ALL.into_iter().find(|format| self.move_legacy_disk_image(cache_id, *format)));
+            let Some(format) = format else {continue};
+            let Some(dimensions) = dimensions.or_else(|| image::image_dimensions(self.disk_cache_path(cache_id, format)).ok()) else {continue};
+            self.insert(cache_key.to_string(), CacheType::OnDisk(cache_id, format), dimensions, modified, None)?;
+        }
+        self.remove_orphaned_files();
+        // Drops the lines of the images, which weren't loaded, and of those replaced by a later line
+        self.write_index()
+    }
+
+    /// Removes the files in the directories of the formats, which belong to no cached image
+    fn remove_orphaned_files(&self) {
+        let cached : HashSet<(u32, DiskFormat)> = self.images.values()
+            .filter_map(|entry| match entry.cache_type {
+                CacheType::OnDisk(cache_id, format) => Some((cache_id, format)),
+                _ => None
+            })
+            .collect();
+        for format in DiskFormat::ALL {
+            let Ok(files) = std::fs::read_dir(self.cache_dir.join(format.name())) else {continue};
+            for file in files.flatten() {
+                let file_name = file.file_name();
+                let cache_id = file_name.to_str()
+                    .and_then(|file_name| file_name.strip_suffix(format.name())?.strip_suffix('.')?.parse::<u32>().ok());
+                // Other files weren't written by the cache
+                let Some(cache_id) = cache_id else {continue};
+                if !cached.contains(&(cache_id, format)) {
+                    let _ = std::fs::remove_file(file.path());
+                }
+            }
+        }
+    }
+
+    /// Moves the images cached on disk into `cache_dir`, and loads the images a previous run cached there.
+    /// Images which can't be moved are removed from the cache. The cache is locked while this runs, so no image is read from the old directory anymore
+    pub fn set_cache_dir(&mut self, cache_dir: PathBuf) -> anyhow::Result<()> {
+        if cache_dir == self.cache_dir {return Ok(());}
+        if self.memory_only {
+            // There are no images on disk to move
+            self.cache_dir = cache_dir;
+            return Ok(());
+        }
+        // Otherwise every image would fail to move
+        prepare_cache_dir(&cache_dir)?;
+        let old_index_path = self.index_path();
+        let disk_keys : Vec<_> = self.images.iter()
+            .filter(|(_, entry)| matches!(entry.cache_type, CacheType::OnDisk(..)))
+            .map(|(cache_key, _)| cache_key.clone())
+            .collect();
+        let old_entries : Vec<_> = disk_keys.into_iter()
+            .map(|cache_key| {
+                let entry = self.images.remove(&cache_key).unwrap();
+                let CacheType::OnDisk(cache_id, format) = entry.cache_type else {unreachable!()};
+                (cache_key, self.disk_cache_path(cache_id, format), format, entry)
+            })
+            .collect();
+        self.cache_dir = cache_dir;
+        self.next_cache_id = 0;
+        self.load_index()?;
+        for (cache_key, old_path, format, entry) in old_entries {
+            // The images cached in the new directory are newer
+            if self.images.contains_key(&cache_key) {
+                let _ = std::fs::remove_file(old_path);
+                continue;
+            }
+            // Files of images, which aren't in the index, are not overwritten
+            while self.disk_cache_path(self.next_cache_id, format).exists() {
+                self.next_cache_id += 1;
+            }
+            let cache_id = self.next_cache_id;
+            let new_path = self.disk_cache_path(cache_id, format);
+            // Renaming fails across file systems
+            let moved = std::fs::rename(&old_path, &new_path).is_ok()
+                || (std::fs::copy(&old_path, &new_path).is_ok() && std::fs::remove_file(&old_path).is_ok());
+            if !moved {
+                let _ = std::fs::remove_file(old_path);
+                continue;
+            }
+            self.next_cache_id += 1;
+            self.insert(cache_key, CacheType::OnDisk(cache_id, format), entry.dimensions, entry.modified, entry.content_key)?;
+        }
+        // Some of the requests might not be fully cached anymore
+        self.paths.clear();
+        self.write_index()?;
+        match std::fs::remove_file(old_index_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(())
+        }
+    }
+
+    /// Removes the images of `path`, either of all sizes or only of `size`, returns how many were removed
+    pub fn remove_path(&mut self, path: &str, size: Option<(u32, u32)>) -> anyhow::Result<usize> {
+        if size.is_none() {
+            self.remove_decoded(path);
+            self.failed.remove(path);
+            self.infos.retain(|(info_path, _), _| info_path != path);
+            self.remote_sources.remove(path);
+        }
+        let size = size.map(|(width, height)| format!("{}x{}", width, height));
+        let cache_keys : Vec<_> = self.images.keys()
+            .filter(|cache_key| match split_cache_key(cache_key) {
+                Some((key_path, key_size)) => key_path == path && size.as_deref().is_none_or(|size| size == key_size),
+                None => false
+            })
+            .cloned()
+            .collect();
+        let removed = cache_keys.len();
+        if removed > 0 {
+            // Some of the requests might not be fully cached anymore
+            self.paths.clear();
+        }
+        let entries = cache_keys.into_iter().map(|cache_key| {
+            let entry = self.images.remove(&cache_key).unwrap();
+            (cache_key, entry)
+        }).collect();
+        self.remove_entries(entries)?;
+        Ok(removed)
+    }
+
+    /// Removes only the images cached on disk, the ones in memory are kept. Returns how many were removed
+    pub fn clear_disk(&mut self) -> anyhow::Result<usize> {
+        let disk_keys : Vec<_> = self.images.iter()
+            .filter(|(_, entry)| matches!(entry.cache_type, CacheType::OnDisk(..)))
+            .map(|(cache_key, _)| cache_key.clone())
+            .collect();
+        let removed = disk_keys.len();
+        if removed > 0 {
+            // Some of the requests might not be fully cached anymore
+            self.paths.clear();
+        }
+        let entries = disk_keys.into_iter().map(|cache_key| {
+            let entry = self.images.remove(&cache_key).unwrap();
+            (cache_key, entry)
+        }).collect();
+        self.remove_entries(entries)?;
+        Ok(removed)
+    }
+
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        self.paths.clear();
+        let decoded_paths : Vec<_> = self.decoded.keys().cloned().collect();
+        for path in decoded_paths {
+            self.remove_decoded(&path);
+        }
+        self.infos.clear();
+        self.failed.clear();
+        self.remote_sources.clear();
+        let entries = self.images.drain().collect();
+        self.remove_entries(entries)
+    }
+
+    /// Evicts in memory images, if they exceed the memory budget.
+    /// Images not used for longer and larger images are evicted first, so a few large images are evicted instead of many small ones
+    fn evict(&mut self) -> anyhow::Result<()> {
+        if self.memory_bytes <= self.max_memory_bytes {return Ok(());}
+        // Evict a bit more than needed, so this doesn't have to run on every insert once the cache is full
+        let target_bytes = (self.max_memory_bytes as f64 * EVICTION_TARGET) as usize;
+        // The last value is whether the key is a path of a decoded image
+        let mut in_memory : Vec<_> = self.images.iter()
+            .filter(|(_, entry)| !matches!(entry.cache_type, CacheType::OnDisk(..)))
+            .map(|(cache_key, entry)| (entry.last_used.load(Ordering::Relaxed), entry.cache_type.memory_size(), cache_key.clone(), false))
+            .chain(self.decoded.iter().map(|(path, entry)| (entry.last_used.load(Ordering::Relaxed), entry.img.as_bytes().len(), path.clone(), true)))
+            .collect();
+        let now = self.use_counter.load(Ordering::Relaxed);
+        // How many uses ago the image was last used, times its size
+        in_memory.sort_unstable_by_key(|(last_used, size, _, _)| std::cmp::Reverse((now.saturating_sub(*last_used) as u128 + 1) * *size as u128));
+        let mut remaining_bytes = self.memory_bytes;
+        for (_, size, key, is_decoded) in in_memory {
+            if remaining_bytes <= target_bytes {break;}
+            remaining_bytes -= size;
+            if is_decoded {
+                self.remove_decoded(&key);
+            } else {
+                let entry = self.images.remove(&key).unwrap();
+                self.remove_entry(&key, entry)?;
+            }
+        }
+        // Some of the requests might not be fully cached anymore. Only images in memory were evicted, which aren't in the index, so it stays the same
+        self.paths.clear();
+        Ok(())
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.images.len(),
+            disk_entries: self.images.values().filter(|entry| matches!(entry.cache_type, CacheType::OnDisk(..))).count(),
+            decoded_entries: self.decoded.len(),
+            memory_bytes: self.memory_bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed)
+        }
+    }
+}
+
+/// A line of the index file: the cache id, the modified time in nanoseconds since the unix epoch (or `-`), the size as `widthxheight` and the cache key, separated by tabs.
+/// `None` if the image can't be written to the index
+fn index_line(cache_id: u32, modified: Option<SystemTime>, dimensions: (u32, u32), cache_key: &str) -> Option<String> {
+    // A line break would split the entry
+    if cache_key.contains('\n') {return None;}
+    let modified = match modified.map(|modified| modified.duration_since(SystemTime::UNIX_EPOCH)) {
+        Some(Ok(since_epoch)) => since_epoch.as_nanos().to_string(),
+        Some(Err(_)) => return None,
+        None => "-".to_string()
+    };
+    let (width, height) = dimensions;
+    Some(format!("{}\t{}\t{}x{}\t{}\n", cache_id, modified, width, height, cache_key))
+}
+
+/// Splits a key created by `get_cache_key` into its path and size.
+/// The path can contain `|`, but the size and the escaped options can't, so the key is split from the end
+fn split_cache_key(cache_key: &str) -> Option<(&str, &str)> {
+    let mut parts = cache_key.rsplitn(3, '|');
+    let _options = parts.next()?;
+    let size = parts.next()?;
+    Some((parts.next()?, size))
+}
+
+/// Parses a size written as `widthxheight`
+fn parse_size(size: &str) -> Option<(u32, u32)> {
+    let (width, height) = size.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+pub struct MemoryReading {
+    system: System,
+    available_memory: u64,
+    refreshed_at: Instant
+}
+
+impl MemoryReading {
+    pub fn new() -> Self {
+        let system = System::new_with_specifics(RefreshKind::new().with_memory());
+        Self {available_memory: system.available_memory(), system, refreshed_at: Instant::now()}
+    }
+
+    /// The available memory in bytes, refreshed at most every `MEMORY_REFRESH_INTERVAL`
+    pub fn available_memory(&mut self) -> u64 {
+        if self.refreshed_at.elapsed() >= MEMORY_REFRESH_INTERVAL {
+            self.system.refresh_memory();
+            self.available_memory = self.system.available_memory();
+            self.refreshed_at = Instant::now();
+        }
+        self.available_memory
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::*;
+    use super::*;
+
+    fn memory_image(len: usize) -> EncodedImage {
+        EncodedImage::new(vec![0; len], 1, 1)
+    }
+
+    /// A cache of images on disk in the directory of the test
+    fn disk_cache(name: &str) -> ImageCache {
+        let cache_dir = test_dir(name);
+        prepare_cache_dir(&cache_dir).unwrap();
+        ImageCache::new(cache_dir, 0, usize::MAX, false)
+    }
+
+    fn insert_on_disk(cache: &mut ImageCache, cache_key: &str, img_bytes: &[u8]) {
+        cache.insert_on_disk(cache_key.to_string(), DiskImage::new(img_bytes).unwrap(), (1, 1), None, None).unwrap();
+    }
+
+    fn cached_bytes(cache: &ImageCache, cache_key: &str) -> Vec<u8> {
+        cache.get(cache_key, None).unwrap().unwrap().bytes.to_vec()
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_images_past_the_budget() {
+        let mut cache = ImageCache::new(test_dir("evicts_the_least_recently_used_images_past_the_budget"), 0, 250, true);
+        for cache_key in ["a|1x1|", "b|1x1|", "c|1x1|"] {
+            cache.insert_in_memory(cache_key.to_string(), memory_image(100), None, None).unwrap();
+        }
+        assert!(!cache.contains_key("a|1x1|"));
+        assert!(cache.contains_key("c|1x1|"));
+        assert!(cache.stats().memory_bytes <= 250);
+    }
+
+    #[test]
+    fn never_reuses_the_file_of_a_removed_image() {
+        let mut cache = disk_cache("never_reuses_the_file_of_a_removed_image");
+        insert_on_disk(&mut cache, "a|1x1|", b"first");
+        insert_on_disk(&mut cache, "b|1x1|", b"second");
+        cache.remove_path("a", None).unwrap();
+        insert_on_disk(&mut cache, "c|1x1|", b"third");
+        assert_eq!(cached_bytes(&cache, "b|1x1|"), b"second");
+        cache.clear().unwrap();
+        insert_on_disk(&mut cache, "d|1x1|", b"fourth");
+        insert_on_disk(&mut cache, "e|1x1|", b"fifth");
+        assert_eq!(cached_bytes(&cache, "d|1x1|"), b"fourth");
+        assert_eq!(cached_bytes(&cache, "e|1x1|"), b"fifth");
+    }
+
+    #[test]
+    fn forgets_the_least_recently_used_batches_past_the_limit() {
+        let mut cache = ImageCache::new(test_dir("forgets_the_least_recently_used_batches_past_the_limit"), 0, usize::MAX, true);
+        for paths_key in 0..MAX_CACHED_PATHS as u64 * 3 {
+            cache.set_fully_cached(paths_key);
+            // The first batch is used again and again, so it is never the least recently used one
+            assert!(cache.is_fully_cached(0));
+        }
+        assert_eq!(cache.cached_paths_count(), MAX_CACHED_PATHS);
+        assert!(!cache.is_fully_cached(1));
+        assert!(cache.is_fully_cached(MAX_CACHED_PATHS as u64 * 3 - 1));
+    }
+
+    /// A cache of the same directory like a new run would create it
+    fn restart(cache: &ImageCache) -> ImageCache {
+        let mut restarted_cache = ImageCache::new(cache.cache_dir.clone(), 0, usize::MAX, false);
+        restarted_cache.load_index().unwrap();
+        restarted_cache
+    }
+
+    #[test]
+    fn loads_the_images_cached_on_disk_by_the_previous_run() {
+        let mut cache = disk_cache("loads_the_images_cached_on_disk_by_the_previous_run");
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        cache.insert_on_disk("a|1x1|".to_string(), DiskImage::new(b"first").unwrap(), (3, 2), Some(modified), None).unwrap();
+        insert_on_disk(&mut cache, "b|1x1|", b"second");
+        // Without writing the index, like a run which crashed
+        let orphan_path = cache.disk_cache_path(77, DiskFormat::Raw);
+        std::fs::write(&orphan_path, b"orphan").unwrap();
+        let mut cache = restart(&cache);
+        let img = cache.get("a|1x1|", Some(modified)).unwrap().unwrap();
+        assert_eq!((img.bytes.as_slice(), img.width, img.height), (&b"first"[..], 3, 2));
+        assert_eq!(cached_bytes(&cache, "b|1x1|"), b"second");
+        assert!(!orphan_path.exists());
+        cache.remove_path("a", None).unwrap();
+        cache.write_index().unwrap();
+        let cache = restart(&cache);
+        assert!(!cache.contains_key("a|1x1|"));
+        assert_eq!(cached_bytes(&cache, "b|1x1|"), b"second");
+        assert_eq!(cache.stats().disk_entries, 1);
+    }
+
+    #[test]
+    fn never_reuses_the_id_of_an_index_line_whose_file_is_gone() {
+        let mut cache = disk_cache("never_reuses_the_id_of_an_index_line_whose_file_is_gone");
+        insert_on_disk(&mut cache, "a|1x1|", b"first");
+        insert_on_disk(&mut cache, "b|1x1|", b"second");
+        std::fs::remove_file(disk_file(&cache, "b|1x1|")).unwrap();
+        let mut cache = restart(&cache);
+        assert!(!cache.contains_key("b|1x1|"));
+        insert_on_disk(&mut cache, "c|1x1|", b"third");
+        // Without writing the index, like a run which crashed
+        let cache = restart(&cache);
+        assert!(!cache.contains_key("b|1x1|"));
+        assert_eq!(cached_bytes(&cache, "a|1x1|"), b"first");
+        assert_eq!(cached_bytes(&cache, "c|1x1|"), b"third");
+        let index = std::fs::read_to_string(cache.index_path()).unwrap();
+        assert!(!index.contains("b|1x1|"), "{}", index);
+    }
+
+    #[test]
+    fn reads_the_available_memory_faster_than_refreshing_it() {
+        let mut memory = MemoryReading::new();
+        let instant = Instant::now();
+        for _ in 0..1000 {
+            assert!(memory.available_memory() > 0);
+        }
+        let reading_time = instant.elapsed() / 1000;
+        let instant = Instant::now();
+        for _ in 0..50 {
+            assert!(System::new_with_specifics(RefreshKind::new().with_memory()).available_memory() > 0);
+        }
+        let refreshing_time = instant.elapsed() / 50;
+        assert!(reading_time * 10 < refreshing_time, "{:?} per reading, {:?} per refresh", reading_time, refreshing_time);
+    }
+
+    #[test]
+    fn stores_bmps_on_disk_as_smaller_pngs() {
+        let mut cache = disk_cache("stores_bmps_on_disk_as_smaller_pngs");
+        let bmp_bytes = encode(&solid_image(64, 64, [30, 60, 90]), ImageFormat::Bmp);
+        insert_on_disk(&mut cache, "a|64x64|", &bmp_bytes);
+        let stored_bytes = std::fs::read(cache.disk_cache_path(0, DiskFormat::Png)).unwrap();
+        assert!(stored_bytes.starts_with(b"\x89PNG"));
+        assert!(stored_bytes.len() * 10 < bmp_bytes.len(), "{} bytes stored of {}", stored_bytes.len(), bmp_bytes.len());
+        assert_eq!(cached_bytes(&cache, "a|64x64|"), bmp_bytes);
+    }
+
+    #[test]
+    fn drops_only_the_images_cached_on_disk() {
+        let mut cache = disk_cache("drops_only_the_images_cached_on_disk");
+        insert_on_disk(&mut cache, "a|1x1|", b"first");
+        insert_on_disk(&mut cache, "b|1x1|", b"second");
+        cache.insert_in_memory("c|1x1|".to_string(), memory_image(10), None, None).unwrap();
+        cache.set_fully_cached(1);
+        let raw_dir = cache.cache_dir.join(DiskFormat::Raw.name());
+        assert_eq!(std::fs::read_dir(&raw_dir).unwrap().count(), 2);
+
+        assert_eq!(cache.clear_disk().unwrap(), 2);
+        assert_eq!(std::fs::read_dir(&raw_dir).unwrap().count(), 0);
+        assert!(!cache.contains_key("a|1x1|") && !cache.contains_key("b|1x1|"));
+        assert_eq!(cached_bytes(&cache, "c|1x1|"), vec![0; 10]);
+        // The batch might have had images on disk
+        assert!(!cache.is_fully_cached(1));
+        assert_eq!((cache.stats().entries, cache.stats().disk_entries), (1, 0));
+        assert_eq!(cache.clear_disk().unwrap(), 0);
+    }
+
+    #[test]
+    fn evicts_a_few_large_images_instead_of_many_small_ones() {
+        let mut cache = ImageCache::new(test_dir("evicts_a_few_large_images_instead_of_many_small_ones"), 0, 1000, true);
+        let small_keys : Vec<String> = (0..10).map(|i| format!("small{}|1x1|", i)).collect();
+        for cache_key in &small_keys {
+            cache.insert_in_memory(cache_key.clone(), memory_image(50), None, None).unwrap();
+        }
+        cache.insert_in_memory("large|1x1|".to_string(), memory_image(450), None, None).unwrap();
+        assert_eq!(cache.stats().memory_bytes, 950);
+        // Exceeds the budget, so at least 150 bytes are freed to get to 90% of it
+        cache.insert_in_memory("new|1x1|".to_string(), memory_image(100), None, None).unwrap();
+        assert!(!cache.contains_key("large|1x1|"));
+        assert!(small_keys.iter().all(|cache_key| cache.contains_key(cache_key)));
+        assert!(cache.contains_key("new|1x1|"));
+        assert_eq!(cache.stats().memory_bytes, 600);
+    }
+
+    #[test]
+    fn stores_every_disk_format_in_its_own_directory() {
+        let mut cache = disk_cache("stores_every_disk_format_in_its_own_directory");
+        let img = solid_image(8, 8, [30, 60, 90]);
+        let bmp_bytes = encode(&img, ImageFormat::Bmp);
+        let jpeg_bytes = encode(&img, ImageFormat::Jpeg);
+        insert_on_disk(&mut cache, "a|8x8|", &bmp_bytes);
+        insert_on_disk(&mut cache, "a|8x8|jpeg", &jpeg_bytes);
+        let cached_files = |cache: &ImageCache| -> Vec<PathBuf> {
+            DiskFormat::ALL.iter()
+                .flat_map(|format| std::fs::read_dir(cache.cache_dir.join(format.name())).unwrap())
+                .map(|entry| entry.unwrap().path())
+                .collect()
+        };
+        let files = cached_files(&cache);
+        assert_eq!(files.len(), 2);
+        assert_ne!(files[0].parent(), files[1].parent());
+        assert!(files.iter().all(|file| file.extension().unwrap() == file.parent().unwrap().file_name().unwrap()));
+        assert_eq!(cached_bytes(&cache, "a|8x8|"), bmp_bytes);
+        assert_eq!(cached_bytes(&cache, "a|8x8|jpeg"), jpeg_bytes);
+        // Both are found again on the next run
+        let mut cache = restart(&cache);
+        assert_eq!(cached_bytes(&cache, "a|8x8|jpeg"), jpeg_bytes);
+        assert_eq!(cache.clear_disk().unwrap(), 2);
+        assert!(cached_files(&cache).is_empty());
+        insert_on_disk(&mut cache, "a|8x8|", &bmp_bytes);
+        insert_on_disk(&mut cache, "a|8x8|jpeg", &jpeg_bytes);
+        cache.clear().unwrap();
+        assert!(cached_files(&cache).is_empty());
+    }
+
+    fn disk_file(cache: &ImageCache, cache_key: &str) -> PathBuf {
+        let CacheType::OnDisk(cache_id, format) = cache.images[cache_key].cache_type else {panic!("{} isn't cached on disk", cache_key)};
+        cache.disk_cache_path(cache_id, format)
+    }
+
+    #[test]
+    fn clears_the_rest_of_the_cache_if_a_file_is_already_deleted() {
+        let mut cache = disk_cache("clears_the_rest_of_the_cache_if_a_file_is_already_deleted");
+        let cache_keys = ["a|1x1|", "b|1x1|", "c|1x1|"];
+        for cache_key in cache_keys {
+            insert_on_disk(&mut cache, cache_key, cache_key.as_bytes());
+        }
+        let files : Vec<_> = cache_keys.iter().map(|cache_key| disk_file(&cache, cache_key)).collect();
+        std::fs::remove_file(&files[1]).unwrap();
+        cache.clear().unwrap();
+        assert!(cache.images.is_empty());
+        for file in files {
+            assert!(!file.exists(), "{} is still cached", file.display());
+        }
+    }
+
+    #[test]
+    fn clears_the_rest_of_the_cache_if_a_file_cannot_be_removed() {
+        let mut cache = disk_cache("clears_the_rest_of_the_cache_if_a_file_cannot_be_removed");
+        let cache_keys = ["a|1x1|", "b|1x1|", "c|1x1|"];
+        for cache_key in cache_keys {
+            insert_on_disk(&mut cache, cache_key, cache_key.as_bytes());
+        }
+        let files : Vec<_> = cache_keys.iter().map(|cache_key| disk_file(&cache, cache_key)).collect();
+        // A directory can't be removed like a file
+        std::fs::remove_file(&files[1]).unwrap();
+        std::fs::create_dir_all(files[1].join("locked")).unwrap();
+        let error = cache.clear().unwrap_err().to_string();
+        assert!(error.contains("Cannot remove 1 of the images"), "{}", error);
+        assert!(cache.images.is_empty());
+        assert!(!files[0].exists());
+        assert!(!files[2].exists());
+    }
+}
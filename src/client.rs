@@ -0,0 +1,283 @@
+//! A client for the PictoCrab server, speaking the same protocol as the server
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use crate::{ImageOptions, ReplyFormat};
+
+/// The name of the pipe or socket the server listens on
+pub const PIPE_NAME: &str = "img_process_server";
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+const STATUS_RATE_LIMITED: u8 = 2;
+const STATUS_IMAGE_FAILED: u8 = 3;
+/// The highest protocol version the client speaks
+const PROTOCOL_VERSION: u32 = 3;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// Reading from or writing to the server failed
+    Io(io::Error),
+    /// The server replied with an error message
+    Server(String),
+    /// The command was not run, because more commands were sent than the `rate_limit` of the server allows
+    RateLimited(String),
+    /// One image of a `gets` couldn't be loaded, the server still sends the others
+    ImageFailed(String),
+    /// The server replied with something, that is not part of the protocol
+    InvalidReply(String)
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Cannot talk to the server : {}", e),
+            Self::Server(message) => write!(f, "Server error : {}", message),
+            Self::RateLimited(message) => write!(f, "{}", message),
+            Self::ImageFailed(message) => write!(f, "Image error : {}", message),
+            Self::InvalidReply(message) => write!(f, "Invalid reply : {}", message)
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+/// A reply with everything the agreed on protocol version tells about it
+#[derive(Clone, Debug)]
+pub struct Reply {
+    /// `None` before protocol version 2
+    pub format: Option<ReplyFormat>,
+    /// The width and height of the image, `None` before protocol version 3 and for replies without an image
+    pub dimensions: Option<(u32, u32)>,
+    pub body: Vec<u8>
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::ffi::OsStr;
+    use std::io;
+    use interprocess::os::windows::named_pipe::DuplexBytePipeStream;
+
+    pub type LocalStream = DuplexBytePipeStream;
+
+    /// Connects to the named pipe `\\.\pipe\{name}`
+    pub fn connect_local(name: &str) -> io::Result<LocalStream> {
+        DuplexBytePipeStream::connect(OsStr::new(name))
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use std::io;
+    use interprocess::local_socket::{LocalSocketStream, NameTypeSupport};
+
+    pub type LocalStream = LocalSocketStream;
+
+    /// Connects to the socket the server binds for `name`, in the abstract namespace if supported, otherwise at `/tmp/{name}.sock`
+    pub fn connect_local(name: &str) -> io::Result<LocalStream> {
+        let socket_name = match NameTypeSupport::query() {
+            NameTypeSupport::OnlyPaths => format!("/tmp/{}.sock", name),
+            NameTypeSupport::OnlyNamespaced | NameTypeSupport::Both => format!("@{}", name)
+        };
+        LocalSocketStream::connect(socket_name)
+    }
+}
+
+pub use platform::LocalStream;
+
+/// Sends commands to a server and reads its replies, one command at a time
+pub struct PictoClient<S: Read + Write> {
+    stream: S,
+    /// Version 1 until `negotiate_protocol` agreed on a higher one
+    protocol_version: u32
+}
+
+impl PictoClient<LocalStream> {
+    /// Connects to a server running on this machine, on its default pipe or socket
+    pub fn connect() -> ClientResult<Self> {
+        Self::connect_local(PIPE_NAME)
+    }
+
+    pub fn connect_local(name: &str) -> ClientResult<Self> {
+        Self::handshake(platform::connect_local(name)?)
+    }
+}
+
+impl PictoClient<TcpStream> {
+    /// Connects to a server started with `--tcp address`
+    pub fn connect_tcp(address: &str) -> ClientResult<Self> {
+        let stream = TcpStream::connect(address)?;
+        // Commands are written in small pieces, which should not wait for each other
+        stream.set_nodelay(true)?;
+        Self::handshake(stream)
+    }
+}
+
+impl<S: Read + Write> PictoClient<S> {
+    /// Uses an already connected stream, speaking protocol version 1 until `negotiate_protocol` is called
+    pub fn new(stream: S) -> Self {
+        Self {stream, protocol_version: 1}
+    }
+
+    /// Uses an already connected stream and agrees on a protocol version with the server right away
+    pub fn handshake(stream: S) -> ClientResult<Self> {
+        let mut client = Self::new(stream);
+        client.negotiate_protocol()?;
+        Ok(client)
+    }
+
+    /// The protocol version agreed on with the server
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    /// Agrees on the highest protocol version both the client and the server speak and returns it.
+    /// From version 2 on every reply tells the format of its body, see `get_with_format`, from version 3 on also the size of images, see `get_with_dimensions`
+    pub fn negotiate_protocol(&mut self) -> ClientResult<u32> {
+        self.send_command(&["protocol".to_string(), PROTOCOL_VERSION.to_string()])?;
+        self.protocol_version = match self.read_reply() {
+            Ok(body) => String::from_utf8_lossy(&body).parse()
+                .ok()
+                .filter(|version| (1..=PROTOCOL_VERSION).contains(version))
+                .ok_or(ClientError::InvalidReply(format!("Unknown protocol version {}", String::from_utf8_lossy(&body))))?,
+            // Servers from before the protocol command only speak version 1
+            Err(ClientError::Server(_)) => 1,
+            Err(e) => return Err(e)
+        };
+        Ok(self.protocol_version)
+    }
+
+    /// Sends a command, with every argument length prefixed, so they can contain any character
+    fn send_command<A: AsRef<str>>(&mut self, args: &[A]) -> ClientResult<()> {
+        let mut data = Vec::new();
+        data.extend((args.len() as u32).to_be_bytes());
+        for arg in args {
+            let arg = arg.as_ref().as_bytes();
+            data.extend((arg.len() as u32).to_be_bytes());
+            data.extend(arg);
+        }
+        let mut message = Vec::with_capacity(data.len() + 4);
+        message.extend((data.len() as u32).to_be_bytes());
+        message.extend(data);
+        self.stream.write_all(&message)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    fn read_reply(&mut self) -> ClientResult<Vec<u8>> {
+        self.read_described_reply().map(|reply| reply.body)
+    }
+
+    fn read_described_reply(&mut self) -> ClientResult<Reply> {
+        let mut status = [0u8; 1];
+        self.stream.read_exact(&mut status)?;
+        let format = if self.protocol_version >= 2 {
+            let mut tag = [0u8; 1];
+            self.stream.read_exact(&mut tag)?;
+            Some(ReplyFormat::from_tag(tag[0]).ok_or(ClientError::InvalidReply(format!("Unknown format {}", tag[0])))?)
+        } else {
+            None
+        };
+        let dimensions = if self.protocol_version >= 3 {
+            let mut dimensions = [0u8; 8];
+            self.stream.read_exact(&mut dimensions)?;
+            let width = u32::from_be_bytes([dimensions[0], dimensions[1], dimensions[2], dimensions[3]]);
+            let height = u32::from_be_bytes([dimensions[4], dimensions[5], dimensions[6], dimensions[7]]);
+            Some((width, height)).filter(|_| format != Some(ReplyFormat::None))
+        } else {
+            None
+        };
+        let mut length = [0u8; 4];
+        self.stream.read_exact(&mut length)?;
+        let mut body = vec![0u8; u32::from_be_bytes(length) as usize];
+        self.stream.read_exact(&mut body)?;
+        match status[0] {
+            STATUS_OK => Ok(Reply {format, dimensions, body}),
+            STATUS_ERROR => Err(ClientError::Server(String::from_utf8_lossy(&body).into_owned())),
+            STATUS_RATE_LIMITED => Err(ClientError::RateLimited(String::from_utf8_lossy(&body).into_owned())),
+            STATUS_IMAGE_FAILED => Err(ClientError::ImageFailed(String::from_utf8_lossy(&body).into_owned())),
+            status => Err(ClientError::InvalidReply(format!("Unknown status {}", status)))
+        }
+    }
+
+    /// Runs a command, which replies with an empty body once it is done
+    fn run_command<A: AsRef<str>>(&mut self, args: &[A]) -> ClientResult<()> {
+        self.send_command(args)?;
+        self.read_reply().map(|_| ())
+    }
+
+    /// Configures the server, `options` are setup options like `threads=4`
+    pub fn setup(&mut self, cache_dir: &str, working_dir: &str, threaded_reads: bool, options: &[&str]) -> ClientResult<()> {
+        let mut args = vec!["setup", cache_dir, working_dir, if threaded_reads {"true"} else {"false"}];
+        args.extend(options);
+        self.run_command(&args)
+    }
+
+    /// Returns the encoded image at `path` resized to `width`x`height`
+    pub fn get(&mut self, path: &str, width: u32, height: u32, options: &ImageOptions) -> ClientResult<Vec<u8>> {
+        let mut args = vec!["get".to_string(), path.to_string(), width.to_string(), height.to_string()];
+        args.extend(options.to_args());
+        self.send_command(&args)?;
+        self.read_reply()
+    }
+
+    /// Like `get`, but also returns the format of the image, if the protocol version is at least 2
+    pub fn get_with_format(&mut self, path: &str, width: u32, height: u32, options: &ImageOptions) -> ClientResult<(Option<ReplyFormat>, Vec<u8>)> {
+        let mut args = vec!["get".to_string(), path.to_string(), width.to_string(), height.to_string()];
+        args.extend(options.to_args());
+        self.send_command(&args)?;
+        self.read_described_reply().map(|reply| (reply.format, reply.body))
+    }
+
+    /// Like `get`, but also returns the format and the width and height of the image, as far as the protocol version tells them.
+    /// The size can differ from the requested one with options like `resize=fit` or `no_upscale`
+    pub fn get_with_dimensions(&mut self, path: &str, width: u32, height: u32, options: &ImageOptions) -> ClientResult<Reply> {
+        let mut args = vec!["get".to_string(), path.to_string(), width.to_string(), height.to_string()];
+        args.extend(options.to_args());
+        self.send_command(&args)?;
+        self.read_described_reply()
+    }
+
+    /// Returns the encoded images at `paths`, in the same order.
+    /// Images which couldn't be loaded are `ClientError::ImageFailed`, only errors of the whole command fail all of them
+    pub fn gets(&mut self, paths: &[&str], width: u32, height: u32, options: &ImageOptions) -> ClientResult<Vec<ClientResult<Vec<u8>>>> {
+        let mut args = vec!["gets".to_string(), width.to_string(), height.to_string()];
+        args.extend(options.to_args());
+        args.push("--".to_string());
+        args.extend(paths.iter().map(|path| path.to_string()));
+        self.send_command(&args)?;
+        let mut images = Vec::with_capacity(paths.len());
+        for _ in paths {
+            match self.read_reply() {
+                Err(e @ ClientError::ImageFailed(_)) => images.push(Err(e)),
+                // No more replies follow an error of the command
+                Err(e) => return Err(e),
+                Ok(img_bytes) => images.push(Ok(img_bytes))
+            }
+        }
+        Ok(images)
+    }
+
+    pub fn clear_cache(&mut self) -> ClientResult<()> {
+        self.run_command(&["clear_cache"])
+    }
+
+    /// Sends the TCP token of the server, which TCP clients need to set up, clear or stop it
+    pub fn auth(&mut self, token: &str) -> ClientResult<()> {
+        self.run_command(&["auth", token])
+    }
+}
@@ -0,0 +1,126 @@
+//! Lets requests of an image, which another request is loading already, wait for its result instead of loading it again
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use anyhow::anyhow;
+use crate::EncodedImage;
+
+/// The result of a load, errors are shared as their message
+type SharedResult = Result<EncodedImage, String>;
+
+#[derive(Default)]
+struct Load {
+    result: Mutex<Option<SharedResult>>,
+    done: Condvar
+}
+
+impl Load {
+    fn wait(&self) -> anyhow::Result<EncodedImage> {
+        let result = self.result.lock().expect("Cannot lock load");
+        let result = self.done.wait_while(result, |result| result.is_none()).expect("Cannot lock load");
+        result.clone().unwrap().map_err(|message| anyhow!("{}", message))
+    }
+
+    fn finish(&self, result: SharedResult) {
+        *self.result.lock().expect("Cannot lock load") = Some(result);
+        self.done.notify_all();
+    }
+}
+
+/// The images being loaded, by cache key
+#[derive(Default)]
+pub struct Loads {
+    loading: Mutex<HashMap<String, Arc<Load>>>
+}
+
+pub enum Claim<'a> {
+    /// No other request is loading the image, the guard has to be finished with the result
+    Load(LoadGuard<'a>),
+    /// Another request loaded the image
+    Waited(anyhow::Result<EncodedImage>)
+}
+
+impl Loads {
+    /// Either claims loading the image of `cache_key` or waits for the request, which claimed it already
+    pub fn claim(&self, cache_key: &str) -> Claim<'_> {
+        let load = {
+            let mut loading = self.loading.lock().expect("Cannot lock loads");
+            match loading.get(cache_key) {
+                Some(load) => load.clone(),
+                None => {
+                    let load = Arc::new(Load::default());
+                    loading.insert(cache_key.to_string(), load.clone());
+                    return Claim::Load(LoadGuard {loads: self, cache_key: cache_key.to_string(), load});
+                }
+            }
+        };
+        Claim::Waited(load.wait())
+    }
+}
+
+/// Unregisters the load once dropped. If it wasn't finished, because loading returned early or panicked, the waiting requests fail
+pub struct LoadGuard<'a> {
+    loads: &'a Loads,
+    cache_key: String,
+    load: Arc<Load>
+}
+
+impl LoadGuard<'_> {
+    /// Passes the result to the waiting requests
+    pub fn finish(self, result: &anyhow::Result<EncodedImage>) {
+        self.load.finish(result.as_ref().cloned().map_err(|e| format!("{:#}", e)));
+    }
+}
+
+impl Drop for LoadGuard<'_> {
+    fn drop(&mut self) {
+        self.loads.loading.lock().expect("Cannot lock loads").remove(&self.cache_key);
+        let mut result = self.load.result.lock().expect("Cannot lock load");
+        if result.is_none() {
+            *result = Some(Err(format!("Loading {} failed in another request", self.cache_key)));
+            self.load.done.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    /// Claims `cache_key` on another thread once the load of this thread is registered, and returns what it waited for
+    fn wait_on_other_thread(loads: &Loads, cache_key: &str, finish: impl FnOnce()) -> anyhow::Result<EncodedImage> {
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| match loads.claim(cache_key) {
+                Claim::Waited(result) => result,
+                Claim::Load(_) => panic!("Claimed {} twice", cache_key)
+            });
+            // So the other thread waits, before the load finishes
+            std::thread::sleep(Duration::from_millis(100));
+            finish();
+            waiter.join().unwrap()
+        })
+    }
+
+    #[test]
+    fn passes_the_result_to_waiting_requests() {
+        let loads = Loads::default();
+        let Claim::Load(guard) = loads.claim("a|1x1|") else {panic!("Waited for a load nobody claimed")};
+        let result = wait_on_other_thread(&loads, "a|1x1|", || guard.finish(&Ok(EncodedImage::new(b"image".to_vec(), 1, 1))));
+        assert_eq!(result.unwrap().bytes.to_vec(), b"image");
+        // The load is unregistered, once it is finished
+        assert!(matches!(loads.claim("a|1x1|"), Claim::Load(_)));
+    }
+
+    #[test]
+    fn fails_waiting_requests_if_the_load_fails() {
+        let loads = Loads::default();
+        let Claim::Load(guard) = loads.claim("a|1x1|") else {panic!("Waited for a load nobody claimed")};
+        let result = wait_on_other_thread(&loads, "a|1x1|", || guard.finish(&Err(anyhow!("Cannot decode a"))));
+        assert_eq!(result.unwrap_err().to_string(), "Cannot decode a");
+
+        let Claim::Load(guard) = loads.claim("b|1x1|") else {panic!("Waited for a load nobody claimed")};
+        let result = wait_on_other_thread(&loads, "b|1x1|", || std::mem::drop(guard));
+        assert_eq!(result.unwrap_err().to_string(), "Loading b|1x1| failed in another request");
+    }
+}
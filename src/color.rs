@@ -0,0 +1,84 @@
+//! Finds the dominant colors of an image with median cut
+
+use image::RgbImage;
+
+/// The pixels are split into this many boxes, the most populated ones are the dominant colors
+const BOX_COUNT: usize = 16;
+/// Colors closer than this are counted as the same color
+const MERGE_DISTANCE: u32 = 24;
+
+fn distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+    (0..3).map(|channel| a[channel].abs_diff(b[channel]) as u32).sum()
+}
+
+fn channel_range(pixels: &[[u8; 3]], channel: usize) -> u8 {
+    let (min, max) = pixels.iter().fold((u8::MAX, u8::MIN), |(min, max), pixel| (min.min(pixel[channel]), max.max(pixel[channel])));
+    max.saturating_sub(min)
+}
+
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    (0..3).map(|channel| (channel, channel_range(pixels, channel)))
+        .max_by_key(|(_, range)| *range)
+        .unwrap()
+}
+
+fn average(pixels: &[[u8; 3]]) -> [u8; 3] {
+    let mut sums = [0u64; 3];
+    for pixel in pixels {
+        for channel in 0..3 {
+            sums[channel] += pixel[channel] as u64;
+        }
+    }
+    sums.map(|sum| (sum / pixels.len() as u64) as u8)
+}
+
+/// Returns up to `count` dominant colors, the most common first
+pub fn dominant_colors(img: &RgbImage, count: usize) -> Vec<[u8; 3]> {
+    let pixels : Vec<[u8; 3]> = img.pixels().map(|pixel| pixel.0).collect();
+    if pixels.is_empty() {return Vec::new();}
+    let mut boxes = vec![pixels];
+    while boxes.len() < BOX_COUNT.max(count) {
+        // Split the box with the widest range of colors at the median of that channel
+        let Some((index, channel)) = boxes.iter().enumerate()
+            .filter(|(_, pixels)| pixels.len() > 1)
+            .map(|(index, pixels)| (index, widest_channel(pixels)))
+            .filter(|(_, (_, range))| *range > 0)
+            .max_by_key(|(_, (_, range))| *range)
+            .map(|(index, (channel, _))| (index, channel)) else {break};
+        let mut pixels = boxes.swap_remove(index);
+        pixels.sort_unstable_by_key(|pixel| pixel[channel]);
+        let upper = pixels.split_off(pixels.len() / 2);
+        boxes.push(pixels);
+        boxes.push(upper);
+    }
+    // Large areas of one color get split into several boxes, those are merged again
+    let mut colors : Vec<([u8; 3], usize)> = Vec::new();
+    for pixels in &boxes {
+        let color = average(pixels);
+        match colors.iter_mut().find(|(other, _)| distance(color, *other) <= MERGE_DISTANCE) {
+            Some((_, pixel_count)) => *pixel_count += pixels.len(),
+            None => colors.push((color, pixels.len()))
+        }
+    }
+    colors.sort_by_key(|(_, pixel_count)| std::cmp::Reverse(*pixel_count));
+    colors.into_iter().take(count).map(|(color, _)| color).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+    use super::*;
+
+    #[test]
+    fn finds_the_most_common_color_first() {
+        let img = RgbImage::from_fn(32, 32, |x, y| match (x, y) {
+            (0..=3, _) => Rgb([0, 0, 255]),
+            // Slightly different reds, like in a photo
+            _ => Rgb([230 + (x % 3) as u8 * 10, (y % 4) as u8 * 5, 10])
+        });
+        let colors = dominant_colors(&img, 2);
+        let [red, green, blue] = colors[0];
+        assert!(red >= 220 && green <= 30 && blue <= 30, "{:?}", colors);
+        assert_eq!(colors.get(1).map(|color| color[2] > 200), Some(true), "{:?}", colors);
+    }
+}
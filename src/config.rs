@@ -0,0 +1,177 @@
+//! The configuration read at startup, from a config file and from environment variables
+
+use std::path::Path;
+use anyhow::anyhow;
+use picto_crab::SetupOptions;
+use picto_crab::options::parse_value;
+
+const DEFAULT_PIPE_NAME: &str = "img_process_server";
+const CONFIG_PATH_ENV: &str = "PICTOCRAB_CONFIG";
+const PIPE_NAME_ENV: &str = "PICTOCRAB_PIPE";
+const TCP_ADDRESS_ENV: &str = "PICTOCRAB_TCP";
+const TCP_TOKEN_ENV: &str = "PICTOCRAB_TCP_TOKEN";
+const CACHE_DIR_ENV: &str = "PICTOCRAB_CACHE_DIR";
+const THREADS_ENV: &str = "PICTOCRAB_THREADS";
+const MIN_AVAILABLE_MEMORY_ENV: &str = "PICTOCRAB_MIN_AVAILABLE_MEMORY";
+
+/// The config file has a `key=value` per line. Environment variables override the file, command line arguments override both
+pub struct StartupConfig {
+    pub pipe_name: String,
+    /// Listens over TCP instead of on the pipe, if set
+    pub tcp_address: Option<String>,
+    /// TCP clients have to send it with `auth` before they may change the server, they never may without it
+    pub tcp_token: Option<String>,
+    /// The server is set up before the first client connects, if set
+    pub cache_dir: Option<String>,
+    pub working_dir: String,
+    pub threaded_reads: bool,
+    /// Every `setup` parses its options after these, so it can override them
+    pub setup_options: Vec<String>
+}
+
+impl Default for StartupConfig {
+    fn default() -> Self {
+        Self {
+            pipe_name: DEFAULT_PIPE_NAME.to_string(),
+            tcp_address: None,
+            tcp_token: None,
+            cache_dir: None,
+            working_dir: ".".to_string(),
+            threaded_reads: false,
+            setup_options: Vec::new()
+        }
+    }
+}
+
+/// The argument after `name`, like the address of `--tcp address`
+fn get_arg_value(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return args.next();
+        }
+    }
+    None
+}
+
+fn get_env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+impl StartupConfig {
+    /// Reads the config file at `--config path` or `PICTOCRAB_CONFIG`, if one is given, and the environment variables
+    pub fn load() -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        if let Some(config_path) = get_arg_value("--config").or_else(|| get_env(CONFIG_PATH_ENV)) {
+            config.read_file(Path::new(&config_path))?;
+        }
+        if let Some(pipe_name) = get_env(PIPE_NAME_ENV) {
+            config.pipe_name = pipe_name;
+        }
+        if let Some(address) = get_arg_value("--tcp").or_else(|| get_env(TCP_ADDRESS_ENV)) {
+            config.tcp_address = Some(address);
+        }
+        if let Some(token) = get_env(TCP_TOKEN_ENV) {
+            config.tcp_token = Some(token);
+        }
+        if let Some(cache_dir) = get_env(CACHE_DIR_ENV) {
+            config.cache_dir = Some(cache_dir);
+        }
+        if let Some(threads) = get_env(THREADS_ENV) {
+            config.setup_options.push(format!("threads={}", threads));
+        }
+        if let Some(min_available_memory) = get_env(MIN_AVAILABLE_MEMORY_ENV) {
+            config.setup_options.push(format!("min_available_memory={}", min_available_memory));
+        }
+        // Otherwise invalid options would only fail the first setup
+        config.parse_setup_options(&[])?;
+        Ok(config)
+    }
+
+    fn read_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let text = std::fs::read_to_string(path).map_err(|e| anyhow!("Cannot read config {} : {}", path.display(), e))?;
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {continue;}
+            let (key, value) = line.split_once('=').ok_or(anyhow!("Invalid config line : {}", line))?;
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "pipe_name" => self.pipe_name = value.to_string(),
+                "tcp" => self.tcp_address = Some(value.to_string()),
+                "tcp_token" => self.tcp_token = Some(value.to_string()).filter(|token| !token.is_empty()),
+                "cache_dir" => self.cache_dir = Some(value.to_string()),
+                "working_dir" => self.working_dir = value.to_string(),
+                "threaded_reads" => self.threaded_reads = parse_value("threaded reads", value)?,
+                // Everything else is a setup option, like `threads=4`
+                _ => self.setup_options.push(format!("{}={}", key, value))
+            }
+        }
+        Ok(())
+    }
+
+    /// The setup options of the config followed by `args`, so `args` override them
+    pub fn setup_option_args<'a>(&'a self, args: &[&'a str]) -> Vec<&'a str> {
+        self.setup_options.iter().map(String::as_str).chain(args.iter().copied()).collect()
+    }
+
+    /// The setup options of the config with `args` applied on top of them
+    pub fn parse_setup_options(&self, args: &[&str]) -> anyhow::Result<SetupOptions> {
+        SetupOptions::parse(&self.setup_option_args(args))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use super::*;
+
+    /// A config file of these lines, in a directory named after the test
+    fn write_config(name: &str, lines: &[&str]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join("picto-crab-config-tests").join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pictocrab.conf");
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_the_config_file() {
+        let path = write_config("reads_the_config_file", &["# The pipe of the tests", "pipe_name = configured_pipe", "", "cache_dir=cache", "threads=3", "min_available_memory=1024"]);
+        let mut config = StartupConfig::default();
+        config.read_file(&path).unwrap();
+        assert_eq!(config.pipe_name, "configured_pipe");
+        assert_eq!(config.cache_dir.as_deref(), Some("cache"));
+        let setup_options = config.parse_setup_options(&[]).unwrap();
+        assert_eq!((setup_options.thread_count, setup_options.min_available_memory), (3, 1024));
+        // A setup overrides the config
+        assert_eq!(config.parse_setup_options(&["threads=5"]).unwrap().thread_count, 5);
+    }
+
+    #[test]
+    fn fails_to_read_an_invalid_config_file() {
+        let path = write_config("fails_to_read_an_invalid_config_file", &["pipe_name"]);
+        let error = StartupConfig::default().read_file(&path).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid config line : pipe_name");
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn listens_on_the_configured_pipe() {
+        use interprocess::local_socket::{LocalSocketStream, NameTypeSupport};
+        let pipe_name = format!("picto_crab_test_{}", std::process::id());
+        let path = write_config("listens_on_the_configured_pipe", &[&format!("pipe_name={}", pipe_name)]);
+        let mut config = StartupConfig::default();
+        config.read_file(&path).unwrap();
+        let listener = crate::bind_listener(&config).unwrap();
+        let socket_name = match NameTypeSupport::query() {
+            NameTypeSupport::OnlyPaths => format!("/tmp/{}.sock", pipe_name),
+            NameTypeSupport::OnlyNamespaced | NameTypeSupport::Both => format!("@{}", pipe_name)
+        };
+        let mut client = LocalSocketStream::connect(socket_name).unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut connection = listener.accept().unwrap();
+        let mut received = [0; 4];
+        connection.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"ping");
+    }
+}
@@ -0,0 +1,81 @@
+//! Just enough EXIF parsing to find the orientation of JPEG and PNG images
+
+const ORIENTATION_TAG: u16 = 0x0112;
+
+fn read_u16(data: &[u8], offset: usize, little_endian: bool) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?.try_into().ok()?;
+    Some(if little_endian {u16::from_le_bytes(bytes)} else {u16::from_be_bytes(bytes)})
+}
+
+fn read_u32(data: &[u8], offset: usize, little_endian: bool) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?.try_into().ok()?;
+    Some(if little_endian {u32::from_le_bytes(bytes)} else {u32::from_be_bytes(bytes)})
+}
+
+/// Finds the orientation tag in the first IFD of TIFF data
+fn tiff_orientation(tiff: &[u8]) -> Option<u16> {
+    let little_endian = match tiff.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None
+    };
+    if read_u16(tiff, 2, little_endian)? != 42 {return None;}
+    let ifd_offset = read_u32(tiff, 4, little_endian)? as usize;
+    let entry_count = read_u16(tiff, ifd_offset, little_endian)? as usize;
+    (0..entry_count)
+        .map(|i| ifd_offset + 2 + i * 12)
+        .find(|&entry_offset| read_u16(tiff, entry_offset, little_endian) == Some(ORIENTATION_TAG))
+        // The value is a short stored in the first bytes of the value field
+        .and_then(|entry_offset| read_u16(tiff, entry_offset + 8, little_endian))
+}
+
+/// Returns the EXIF orientation (1 to 8) of a JPEG or PNG image, if it has one
+pub fn orientation(img_bytes: &[u8]) -> Option<u16> {
+    if let Some(tiff) = png_exif(img_bytes) {
+        return tiff_orientation(tiff).filter(|orientation| (1..=8).contains(orientation));
+    }
+    if img_bytes.get(..2)? != [0xFF, 0xD8] {return None;}
+    let mut offset = 2;
+    loop {
+        let marker = img_bytes.get(offset..offset + 2)?;
+        // The image data starts at the start of scan marker, the metadata is before it
+        if marker[0] != 0xFF || marker[1] == 0xDA {return None;}
+        let length = read_u16(img_bytes, offset + 2, false)? as usize;
+        let segment = img_bytes.get(offset + 4..offset + 2 + length)?;
+        if marker[1] == 0xE1 && segment.starts_with(b"Exif\0\0") {
+            return tiff_orientation(&segment[6..]).filter(|orientation| (1..=8).contains(orientation));
+        }
+        offset += 2 + length;
+    }
+}
+
+/// The TIFF data of the `eXIf` chunk of a PNG image, if it has one
+pub fn png_exif(img_bytes: &[u8]) -> Option<&[u8]> {
+    if !img_bytes.starts_with(b"\x89PNG\r\n\x1a\n") {return None;}
+    let mut offset = 8;
+    loop {
+        let length = read_u32(img_bytes, offset, false)? as usize;
+        let chunk_type = img_bytes.get(offset + 4..offset + 8)?;
+        if chunk_type == b"IEND" {return None;}
+        if chunk_type == b"eXIf" {
+            return img_bytes.get(offset + 8..offset + 8 + length);
+        }
+        // The length, type and CRC are not part of the length
+        offset += 12 + length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ImageFormat;
+    use crate::test_util::*;
+    use super::*;
+
+    #[test]
+    fn finds_the_orientation_of_jpegs() {
+        let jpeg_bytes = encode(&solid_image(8, 4, [255, 0, 0]), ImageFormat::Jpeg);
+        assert_eq!(orientation(&jpeg_bytes), None);
+        assert_eq!(orientation(&with_jpeg_orientation(&jpeg_bytes, 6)), Some(6));
+        assert_eq!(orientation(&with_jpeg_orientation(&jpeg_bytes, 9)), None);
+    }
+}
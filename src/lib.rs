@@ -0,0 +1,1482 @@
+//! The image loading, processing and caching of PictoCrab, usable without the server
+
+use std::collections::HashMap;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use anyhow::anyhow;
+use image::{AnimationDecoder, DynamicImage, GenericImageView, ImageFormat};
+use image::codecs::gif::GifDecoder;
+use reqwest::StatusCode;
+use reqwest::blocking::Response;
+use reqwest::header::{IF_MODIFIED_SINCE, IF_NONE_MATCH};
+use tracing::{trace, warn};
+
+mod cache;
+mod coalesce;
+mod process;
+mod exif;
+mod color;
+mod phash;
+mod palette;
+mod remote;
+pub mod metrics;
+pub mod options;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(test)]
+mod test_util;
+
+pub use cache::CacheStats;
+pub use metrics::{Metrics, Stage, TimingSummary};
+pub use options::{CropRect, ImageOptions, Mask, OutputFormat, ResizeFilter, ResizeMode, SetupOptions, Sharpen, Tint};
+use cache::{DiskImage, ImageCache, MemoryReading};
+use coalesce::{Claim, Loads};
+use remote::{DownloadSlots, HttpClients, HttpConfig, RemoteSource};
+
+pub const MAX_DOMINANT_COLORS: usize = 16;
+/// ICO files can't store larger images
+pub const MAX_ICON_SIZE: u32 = 256;
+
+/// What `PictoServer::resolve_source` found out about the source of an image
+type ResolvedSource = (Option<PathBuf>, Option<SystemTime>, Option<Vec<u8>>);
+
+/// The format of the body of a reply, which is sent before its length to clients speaking protocol version 2
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyFormat {
+    /// Text, JSON and error messages
+    None = 0,
+    Bmp = 1,
+    Png = 2,
+    Jpeg = 3,
+    Webp = 4,
+    Ico = 5
+}
+
+impl ReplyFormat {
+    /// The format of encoded image bytes, from their magic bytes
+    pub fn of_image(img_bytes: &[u8]) -> Self {
+        match image::guess_format(img_bytes) {
+            Ok(ImageFormat::Bmp) => Self::Bmp,
+            Ok(ImageFormat::Png) => Self::Png,
+            Ok(ImageFormat::Jpeg) => Self::Jpeg,
+            Ok(ImageFormat::WebP) => Self::Webp,
+            Ok(ImageFormat::Ico) => Self::Ico,
+            _ => Self::None
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        [Self::None, Self::Bmp, Self::Png, Self::Jpeg, Self::Webp, Self::Ico].into_iter().find(|format| *format as u8 == tag)
+    }
+}
+
+/// An encoded image with its width and height, which are kept with it, so they don't have to be read from its header
+#[derive(Clone, Debug)]
+pub struct EncodedImage {
+    pub bytes: Arc<Vec<u8>>,
+    pub width: u32,
+    pub height: u32
+}
+
+impl EncodedImage {
+    pub fn new(bytes: Vec<u8>, width: u32, height: u32) -> Self {
+        Self {bytes: Arc::new(bytes), width, height}
+    }
+}
+
+/// Loads, processes and caches images, shared by all threads using it
+pub struct PictoServer {
+    /// If false, local images are read one at a time. Can be changed while images are loaded
+    threaded_reads: AtomicBool,
+    /// A single client is shared by all threads, so connections to the same host get reused
+    http_clients: HttpClients,
+    max_redirects: usize,
+    /// If set, local images can only be read from within this directory
+    read_root: Option<PathBuf>,
+    max_pixels: u64,
+    max_output_bytes: usize,
+    /// Compress the images cached in memory
+    compress_memory: bool,
+    /// Images are never cached on disk
+    memory_only: bool,
+    min_available_memory: AtomicU64,
+    cache_decoded: bool,
+    /// How many images of a batch are downloaded at the same time
+    http_concurrency: usize,
+    /// Shared by all threads, so together they download at most `http_concurrency` images at a time
+    download_slots: DownloadSlots,
+    /// Paths which failed to load, fail right away for this long
+    failure_ttl: Duration,
+    /// How long images over HTTP are used without revalidating them, if their server doesn't tell
+    http_max_age: Option<Duration>,
+    /// Hosts images may be fetched from, if empty only public addresses are allowed
+    allowed_hosts: Vec<String>,
+    /// Hashes the contents of source images with random keys, so nobody can make two images collide on purpose
+    content_hasher: RandomState,
+    cache: RwLock<ImageCache>,
+    /// So requests of the same image at the same time only load it once
+    loads: Loads,
+    metrics: Metrics,
+    /// Created once, refreshing it is much cheaper than creating it
+    memory: Mutex<MemoryReading>,
+    /// Decoded watermark images by path, the one set up with `setup` has an empty path. They are not read again, if they change
+    watermarks: RwLock<HashMap<String, Arc<DynamicImage>>>
+}
+
+fn get_cache_key(path: &str, width: u32, height: u32, options: &ImageOptions) -> String {
+    format!("{}|{}x{}|{}", path, width, height, escape_key_options(options))
+}
+
+/// The options in a cache key. Options like `watermark` can contain `|`, which is escaped, so the key is still split at the size
+fn escape_key_options(options: &ImageOptions) -> String {
+    options.to_string().replace('%', "%25").replace('|', "%7C")
+}
+
+/// Removes the `.` and `..` of an absolute path without reading the file system, like `canonicalize` would for paths without links
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized_path = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {},
+            std::path::Component::ParentDir => {
+                normalized_path.pop();
+            },
+            component => normalized_path.push(component)
+        }
+    }
+    normalized_path
+}
+
+/// Identifies a `gets` request. A collision only means the images are loaded without the threads
+fn get_paths_key(width: u32, height: u32, options: &ImageOptions, paths: &[&str]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (width, height, options.to_string(), paths).hash(&mut hasher);
+    hasher.finish()
+}
+
+fn describe_fetch_error(url : &str, err : reqwest::Error) -> anyhow::Error {
+    if err.is_timeout() {
+        anyhow!("Timed out getting {}", url)
+    } else {
+        anyhow!("Error with path {} getting : {}", url, err)
+    }
+}
+
+/// The path, size and format of a source image, so errors show which image of a batch failed
+fn describe_source(path : &str, raw_img_bytes : &[u8]) -> String {
+    let format = match image::guess_format(raw_img_bytes) {
+        Ok(format) => format!("{:?}", format).to_lowercase(),
+        Err(_) => "unknown format".to_string()
+    };
+    format!("{} ({} bytes, {})", path, raw_img_bytes.len(), format)
+}
+
+/// Decodes a single frame of a GIF, drawn over the frames before it, like it is shown when animated
+fn decode_gif_frame(raw_img_bytes : &[u8], frame : u32) -> anyhow::Result<DynamicImage> {
+    let mut frames = GifDecoder::new(Cursor::new(raw_img_bytes))?.into_frames();
+    match frames.nth(frame as usize) {
+        Some(decoded_frame) => Ok(DynamicImage::ImageRgba8(decoded_frame?.into_buffer())),
+        None => Err(anyhow!("GIF has less than {} frames", frame as u64 + 1))
+    }
+}
+
+impl PictoServer {
+    /// Caches images on disk in `cache_dir` and loads those cached there by a previous run.
+    /// Relative paths, like the read root, are relative to the current working directory
+    pub fn new(cache_dir: &str, threaded_reads: bool, options: &SetupOptions) -> anyhow::Result<Self> {
+        let read_root = options.read_root.as_deref().map(Path::canonicalize).transpose()?;
+        let http_concurrency = options.http_pool_size.unwrap_or(options.thread_count).max(1);
+        let http_clients = HttpClients::new(HttpConfig {
+            pool_size: http_concurrency,
+            idle_timeout: options.http_idle_timeout,
+            timeout: options.http_timeout
+        })?;
+        // Absolute, so changing the working directory later doesn't move the cache
+        let cache_dir = std::path::absolute(cache_dir)?;
+        if !options.memory_only {
+            cache::prepare_cache_dir(&cache_dir)?;
+        }
+        let mut cache = ImageCache::new(cache_dir, 300000, options.max_memory_bytes, options.memory_only);
+        if let Err(e) = cache.load_index() {
+            warn!("Cannot load the cache index: {:#}", e);
+        }
+        let mut watermarks = HashMap::new();
+        if let Some(watermark_path) = &options.watermark {
+            let watermark = image::open(watermark_path).map_err(|e| anyhow!("Cannot load watermark {} : {}", watermark_path.display(), e))?;
+            watermarks.insert(String::new(), Arc::new(watermark));
+        }
+        Ok(Self {
+            threaded_reads: AtomicBool::new(threaded_reads),
+            http_clients,
+            max_redirects: options.max_redirects,
+            read_root,
+            max_pixels: options.max_pixels,
+            max_output_bytes: options.max_output_bytes,
+            compress_memory: options.compress_memory,
+            memory_only: options.memory_only,
+            min_available_memory: AtomicU64::new(options.min_available_memory),
+            cache_decoded: options.cache_decoded,
+            http_concurrency,
+            download_slots: DownloadSlots::new(http_concurrency),
+            failure_ttl: options.failure_ttl,
+            http_max_age: options.http_max_age,
+            allowed_hosts: options.allowed_hosts.clone(),
+            content_hasher: RandomState::new(),
+            cache: RwLock::new(cache),
+            loads: Loads::default(),
+            metrics: Metrics::default(),
+            memory: Mutex::new(MemoryReading::new()),
+            watermarks: RwLock::new(watermarks)
+        })
+    }
+
+    fn is_memory_low(&self) -> bool {
+        self.memory.lock().expect("Cannot lock system").available_memory() < self.min_available_memory.load(Ordering::Relaxed)
+    }
+
+    fn cache_img(&self, cache_key : String, img : EncodedImage, modified : Option<SystemTime>, content_key : Option<u64>) -> anyhow::Result<()> {
+        let instant = std::time::Instant::now();
+        let memory_low = self.is_memory_low();
+        if memory_low && self.memory_only {
+            // Caching it in memory would only make memory lower, and it can't be cached on disk
+            trace!(memory_low, "Not cached");
+            return Ok(());
+        }
+        if memory_low {
+            // Compressing the image takes a while, so it is done before locking the cache
+            let disk_img = DiskImage::new(&img.bytes)?;
+            self.cache.write().expect("Cannot write to cache").insert_on_disk(cache_key, disk_img, (img.width, img.height), modified, content_key)?;
+        } else {
+            // PNG and JPEG images are compressed already, so they are only stored compressed if that makes them smaller
+            let compressed_bytes = if self.compress_memory {
+                Some(lz4_flex::compress_prepend_size(&img.bytes)).filter(|compressed_bytes| compressed_bytes.len() < img.bytes.len())
+            } else {
+                None
+            };
+            let mut unlocked_cache = self.cache.write().expect("Cannot write to cache");
+            match compressed_bytes {
+                Some(compressed_bytes) => unlocked_cache.insert_compressed(cache_key, compressed_bytes, (img.width, img.height), modified, content_key)?,
+                None => unlocked_cache.insert_in_memory(cache_key, img, modified, content_key)?
+            }
+        }
+        trace!(nanos = instant.elapsed().as_nanos() as u64, memory_low, "Cached image");
+        Ok(())
+    }
+
+    /// Caches a decoded source image, unless memory is low, then only the encoded images are cached
+    fn cache_decoded(&self, path : &str, img : Arc<DynamicImage>, orientation : u16, modified : Option<SystemTime>) -> anyhow::Result<()> {
+        if self.is_memory_low() {return Ok(());}
+        self.cache.write().expect("Cannot write to cache").insert_decoded(path.to_string(), img, orientation, modified)
+    }
+
+    /// Requests the image at `url`, only if it changed since the cached version, if one is given.
+    /// Follows at most `max_redirects` redirects, each of them checked like the URL itself, otherwise an allowed host could redirect to any other
+    fn send_request(&self, url : &str, cached : Option<&RemoteSource>) -> anyhow::Result<Response> {
+        let mut parsed_url = reqwest::Url::parse(url).map_err(|e| anyhow!("Invalid URL {} : {}", url, e))?;
+        let mut redirects = 0;
+        loop {
+            let addresses = remote::check_url(&parsed_url, &self.allowed_hosts)?;
+            let mut request = self.http_clients.client(&parsed_url, addresses)?.get(parsed_url.clone());
+            if let Some(cached) = cached {
+                if let Some(etag) = &cached.etag {
+                    request = request.header(IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = &cached.last_modified {
+                    request = request.header(IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+            let response = request.send().map_err(|err| describe_fetch_error(url, err))?;
+            if let Some(target) = remote::redirect_target(&parsed_url, response.status(), response.headers())? {
+                redirects += 1;
+                if redirects > self.max_redirects {
+                    return Err(anyhow!("Error getting {} : Too many redirects, at most {} are followed", url, self.max_redirects));
+                }
+                parsed_url = target;
+                continue;
+            }
+            if response.status() == StatusCode::NOT_MODIFIED {return Ok(response);}
+            return response.error_for_status().map_err(|err| anyhow!("Error getting : {}", err));
+        }
+    }
+
+    fn fetch_url(&self, url : &str) -> anyhow::Result<Vec<u8>> {
+        let _slot = self.download_slots.acquire();
+        let response = self.send_request(url, None)?;
+        let source = RemoteSource::from_headers(response.headers(), None, self.http_max_age);
+        let body = response.bytes().map_err(|err| describe_fetch_error(url, err))?;
+        let mut unlocked_cache = self.cache.write().expect("Cannot write to cache");
+        // Once known, the version only changes when revalidating
+        if unlocked_cache.get_remote_source(url).is_none() {
+            unlocked_cache.insert_remote_source(url.to_string(), source);
+        }
+        Ok(body.to_vec())
+    }
+
+    /// Returns the version, the image at `url` is cached with. If it isn't fresh anymore, the server is asked whether it changed.
+    /// If it did, the changed image is returned too
+    fn revalidate(&self, url : &str) -> anyhow::Result<(Option<SystemTime>, Option<Vec<u8>>)> {
+        let Some(cached) = self.cache.read().expect("Cannot read from cache").get_remote_source(url) else {return Ok((None, None))};
+        if cached.is_fresh() {return Ok((cached.version, None));}
+        let _slot = self.download_slots.acquire();
+        let response = self.send_request(url, Some(&cached))?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            let version = cached.version;
+            self.cache.write().expect("Cannot write to cache").insert_remote_source(url.to_string(), cached.revalidated(response.headers()));
+            trace!(url, "Image over HTTP didn't change");
+            return Ok((version, None));
+        }
+        // No image was cached with this version yet, so the images of the old one count as modified
+        let version = Some(SystemTime::now());
+        let source = RemoteSource::from_headers(response.headers(), version, self.http_max_age);
+        let body = response.bytes().map_err(|err| describe_fetch_error(url, err))?;
+        self.cache.write().expect("Cannot write to cache").insert_remote_source(url.to_string(), source);
+        Ok((version, Some(body.to_vec())))
+    }
+
+    /// Decodes the image, of animated GIFs only the frame with this index
+    fn decode_image(&self, raw_img_bytes : &[u8], frame : u32) -> anyhow::Result<DynamicImage> {
+        let reader = image::io::Reader::new(Cursor::new(raw_img_bytes)).with_guessed_format()?;
+        let format = reader.format();
+        // Only the header is read here, so huge images are rejected before anything gets allocated for them
+        let (width, height) = reader.into_dimensions()?;
+        if width as u64 * height as u64 > self.max_pixels {
+            return Err(anyhow!("Image is too large ({}x{}), at most {} pixels are allowed", width, height, self.max_pixels));
+        }
+        let instant = std::time::Instant::now();
+        let img = match format {
+            // Decoding the frames explicitly always gives the whole first frame, like it is shown when animated
+            Some(ImageFormat::Gif) => decode_gif_frame(raw_img_bytes, frame)?,
+            _ if frame > 0 => return Err(anyhow!("Only GIFs have frames, frame {} was requested", frame)),
+            _ => image::load_from_memory(raw_img_bytes)?
+        };
+        trace!(nanos = instant.elapsed().as_nanos() as u64, "Decoded image");
+        self.metrics.record(Stage::Decode, instant.elapsed());
+        Ok(img)
+    }
+
+    /// Makes sure local paths don't escape the read root, if one is set.
+    /// Paths outside of it fail the same, whether they exist or not, so clients can't find out which files exist outside of it
+    fn resolve_local_path(&self, path : &str) -> anyhow::Result<PathBuf> {
+        let Some(read_root) = &self.read_root else {return Ok(PathBuf::from(path))};
+        let canonical_path = match std::fs::canonicalize(path) {
+            Ok(canonical_path) => canonical_path,
+            Err(_) if !normalize_path(&std::path::absolute(path)?).starts_with(read_root) => return Err(anyhow!("Path {} is outside of the root directory", path)),
+            Err(err) => return Err(anyhow!("Cannot read {} : {}", path, err))
+        };
+        if !canonical_path.starts_with(read_root) {
+            return Err(anyhow!("Path {} is outside of the root directory", path));
+        }
+        Ok(canonical_path)
+    }
+
+    /// Reads the image at a local path or URL, unless it was already downloaded
+    fn read_image(&self, path : &str, local_path : Option<PathBuf>, downloaded : Option<anyhow::Result<Vec<u8>>>) -> anyhow::Result<Vec<u8>> {
+        let instant = std::time::Instant::now();
+        let raw_img_bytes = if let Some(local_path) = local_path {
+            let read = if !self.threaded_reads.load(Ordering::Relaxed) {
+                // Just get the write guard first, which will prevent any other threads from reading images at the same time
+                // This can improve performance, if reading off hard drives, because the seek head then doesn't have to move as much
+                let _guard = self.cache.write().expect("Could not get write lock");
+                std::fs::read(local_path)
+                // guard gets dropped here
+            } else {
+                std::fs::read(local_path)
+            };
+            read.map_err(|err| anyhow!("Cannot read {} : {}", path, err))?
+        } else {
+            match downloaded {
+                Some(raw_img_bytes) => raw_img_bytes?,
+                None => self.fetch_url(path)?
+            }
+        };
+        self.metrics.record(Stage::Read, instant.elapsed());
+        trace!(path, nanos = instant.elapsed().as_nanos() as u64, "Read image");
+        Ok(raw_img_bytes)
+    }
+
+    /// Identifies the image processed from these source bytes, no matter which path they were read from
+    fn get_content_key(&self, raw_img_bytes : &[u8], width : u32, height : u32, options : &ImageOptions) -> u64 {
+        self.content_hasher.hash_one((raw_img_bytes, width, height, options.to_string()))
+    }
+
+    /// Fails with the previous error, if loading `path` failed recently, without reading it again
+    fn check_failure(&self, path : &str) -> anyhow::Result<()> {
+        match self.cache.read().expect("Cannot read from cache").get_failure(path, self.failure_ttl) {
+            Some(message) => Err(anyhow!("{}", message)),
+            None => Ok(())
+        }
+    }
+
+    /// Returns the local path and when it was modified, or for images over HTTP their version and the image, if revalidating it downloaded a changed one
+    fn resolve_source(&self, path : &str) -> anyhow::Result<ResolvedSource> {
+        if remote::is_url(path)? {
+            let (version, changed) = self.revalidate(path)?;
+            return Ok((None, version, changed));
+        }
+        let local_path = self.resolve_local_path(path)?;
+        // Files which can't be read count as modified, the error is returned once they are read
+        let modified = std::fs::metadata(&local_path).and_then(|metadata| metadata.modified()).ok();
+        Ok((Some(local_path), modified, None))
+    }
+
+    /// Remembers that loading `path` failed, so it fails right away for a while
+    fn remember_failure<T>(&self, path : &str, result : anyhow::Result<T>) -> anyhow::Result<T> {
+        if let Err(e) = &result {
+            if !self.failure_ttl.is_zero() {
+                let message = format!("{:#}", e);
+                self.cache.write().expect("Cannot write to cache").insert_failure(path.to_string(), message, self.failure_ttl);
+            }
+        }
+        result
+    }
+
+    /// Only the first frame is cached
+    fn get_cached_decoded(&self, path : &str, modified : Option<SystemTime>, frame : u32) -> Option<(Arc<DynamicImage>, u16)> {
+        if !self.cache_decoded || frame != 0 {return None;}
+        self.cache.read().expect("Cannot read from cache").get_decoded(path, modified)
+    }
+
+    /// Decodes a frame of a source image, returns it and its EXIF orientation
+    fn decode_source(&self, path : &str, raw_img_bytes : &[u8], modified : Option<SystemTime>, frame : u32) -> anyhow::Result<(Arc<DynamicImage>, u16)> {
+        let decoded = self.decode_image(raw_img_bytes, frame)
+            .map_err(|e| anyhow!("Cannot decode {} : {:#}", describe_source(path, raw_img_bytes), e));
+        let img = if frame == 0 {
+            self.remember_failure(path, decoded)?
+        } else {
+            // Requesting a frame, which doesn't exist, doesn't make the other frames fail
+            decoded?
+        };
+        let img = Arc::new(img);
+        let orientation = exif::orientation(raw_img_bytes).unwrap_or(1);
+        if self.cache_decoded && frame == 0 {
+            self.cache_decoded(path, img.clone(), orientation, modified)?;
+        }
+        Ok((img, orientation))
+    }
+
+    /// Returns the decoded image and its EXIF orientation, from the cache if decoded images are cached
+    fn get_source_image(&self, path : &str, local_path : Option<PathBuf>, modified : Option<SystemTime>, downloaded : Option<anyhow::Result<Vec<u8>>>, frame : u32) -> anyhow::Result<(Arc<DynamicImage>, u16)> {
+        if let Some(decoded) = self.get_cached_decoded(path, modified, frame) {
+            return Ok(decoded);
+        }
+        let raw_img_bytes = self.remember_failure(path, self.read_image(path, local_path, downloaded))?;
+        self.decode_source(path, &raw_img_bytes, modified, frame)
+    }
+
+    /// Returns information computed from the upright image at `path`, like its blurhash.
+    /// `info_name` identifies the information and its parameters in the cache
+    fn get_image_info(&self, path : &str, info_name : &str, compute : impl FnOnce(&DynamicImage) -> anyhow::Result<String>) -> anyhow::Result<String> {
+        self.check_failure(path)?;
+        let (local_path, modified, changed) = self.resolve_source(path)?;
+        if let Some(info) = self.cache.read().expect("Cannot read from cache").get_info(path, info_name, modified) {
+            return Ok(info);
+        }
+        let (img, orientation) = self.get_source_image(path, local_path, modified, changed.map(Ok), 0)?;
+        let info = match process::orient_image(&img, orientation) {
+            Some(oriented_img) => compute(&oriented_img)?,
+            None => compute(&img)?
+        };
+        self.cache.write().expect("Cannot write to cache").insert_info(path.to_string(), info_name.to_string(), info.clone(), modified);
+        Ok(info)
+    }
+
+    /// Returns the encoded image at `path`, processed with the options, from the cache if possible
+    pub fn fetch(&self, path : &str, width : u32, height : u32, options : &ImageOptions) -> anyhow::Result<EncodedImage> {
+        self.fetch_downloaded(path, width, height, options, None)
+    }
+
+    fn fetch_downloaded(&self, path : &str, width : u32, height : u32, options : &ImageOptions, downloaded : Option<anyhow::Result<Vec<u8>>>) -> anyhow::Result<EncodedImage> {
+        self.check_failure(path)?;
+        let cache_key = get_cache_key(path, width, height, options);
+        let (local_path, modified, changed) = self.resolve_source(path)?;
+        if let Some(img) = self.cache.read().expect("Cannot read from cache").get(&cache_key, modified)? {
+            return Ok(img);
+        }
+        let guard = match self.loads.claim(&cache_key) {
+            Claim::Load(guard) => guard,
+            Claim::Waited(result) => return result
+        };
+        // The request, which loaded it before, might have finished between reading the cache and claiming it.
+        // That read counted already, so this one doesn't count as another miss
+        let cached = self.cache.read().expect("Cannot read from cache").peek(&cache_key, modified);
+        let result = match cached {
+            Ok(Some(img)) => Ok(img),
+            Ok(None) => self.load_uncached(path, cache_key.clone(), width, height, options, local_path, modified, changed.map(Ok).or(downloaded)),
+            Err(e) => Err(e)
+        };
+        guard.finish(&result);
+        result
+    }
+
+    /// Loads the image like `fetch` would, once it isn't cached. Only one request of the same image runs it at a time
+    #[allow(clippy::too_many_arguments)]
+    fn load_uncached(&self, path : &str, cache_key : String, width : u32, height : u32, options : &ImageOptions, local_path : Option<PathBuf>, modified : Option<SystemTime>, downloaded : Option<anyhow::Result<Vec<u8>>>) -> anyhow::Result<EncodedImage> {
+        let (img, orientation, content_key) = match self.get_cached_decoded(path, modified, options.frame) {
+            Some((img, orientation)) => (img, orientation, None),
+            None => {
+                let raw_img_bytes = self.remember_failure(path, self.read_image(path, local_path, downloaded))?;
+                // Another path with the same content might have been processed like this already
+                let content_key = self.get_content_key(&raw_img_bytes, width, height, options);
+                let same_content = self.cache.read().expect("Cannot read from cache").get_by_content(content_key)?;
+                if let Some(img) = same_content {
+                    self.cache_img(cache_key, img.clone(), modified, Some(content_key))?;
+                    return Ok(img);
+                }
+                if process::is_unchanged_source(&raw_img_bytes, width, height, options) {
+                    trace!(path, "Returning the source image without processing it");
+                    return self.cache_output(cache_key, EncodedImage::new(raw_img_bytes, width, height), modified, Some(content_key));
+                }
+                let (img, orientation) = self.decode_source(path, &raw_img_bytes, modified, options.frame)?;
+                (img, orientation, Some(content_key))
+            }
+        };
+
+        let encoded_img = self.process_and_encode(path, &img, orientation, width, height, options)?;
+        self.cache_output(cache_key, encoded_img, modified, content_key)
+    }
+
+    /// The decoded image of `options.watermark`, which is only read the first time it is used
+    fn get_watermark(&self, options : &ImageOptions) -> anyhow::Result<Option<Arc<DynamicImage>>> {
+        let Some(watermark_path) = &options.watermark else {return Ok(None)};
+        if let Some(watermark) = self.watermarks.read().expect("Cannot read watermarks").get(watermark_path) {
+            return Ok(Some(watermark.clone()));
+        }
+        if watermark_path.is_empty() {
+            return Err(anyhow!("No watermark was set up"));
+        }
+        let local_path = self.resolve_local_path(watermark_path)?;
+        let raw_img_bytes = std::fs::read(&local_path).map_err(|e| anyhow!("Cannot read watermark {} : {}", watermark_path, e))?;
+        let watermark = Arc::new(self.decode_image(&raw_img_bytes, 0)
+            .map_err(|e| anyhow!("Cannot decode watermark {} : {:#}", describe_source(watermark_path, &raw_img_bytes), e))?);
+        self.watermarks.write().expect("Cannot write watermarks").insert(watermark_path.clone(), watermark.clone());
+        Ok(Some(watermark))
+    }
+
+    /// Like `process::process_image`, with the watermark of the options
+    fn process_image(&self, img : &DynamicImage, orientation : u16, width : u32, height : u32, options : &ImageOptions) -> anyhow::Result<DynamicImage> {
+        let pixels = process::max_processed_pixels(img.width(), img.height(), orientation, width, height, options);
+        if pixels > self.max_pixels {
+            return Err(anyhow!("Processed image is too large ({} pixels), at most {} pixels are allowed", pixels, self.max_pixels));
+        }
+        let watermark = self.get_watermark(options)?;
+        process::process_image(img, orientation, width, height, options, watermark.as_deref())
+    }
+
+    /// Processes a decoded source image with the options and encodes it, `path` names the image in errors
+    fn process_and_encode(&self, path : &str, img : &DynamicImage, orientation : u16, width : u32, height : u32, options : &ImageOptions) -> anyhow::Result<EncodedImage> {
+        let instant = std::time::Instant::now();
+        let img = self.process_image(img, orientation, width, height, options)?;
+        let processed_in = instant.elapsed();
+        let encoded_img_bytes = process::encode_image(&img, options)
+            .map_err(|e| anyhow!("Cannot encode {} as {} : {:#}", path, options.format.name(), e))?;
+        self.metrics.record(Stage::Process, processed_in);
+        self.metrics.record(Stage::Encode, instant.elapsed() - processed_in);
+        trace!(path, nanos = instant.elapsed().as_nanos() as u64, "Processed image");
+        Ok(EncodedImage::new(encoded_img_bytes, img.width(), img.height()))
+    }
+
+    fn check_output_size(&self, img_bytes : &[u8]) -> anyhow::Result<()> {
+        if img_bytes.len() > self.max_output_bytes {
+            return Err(anyhow!("Encoded image is too large ({} bytes), at most {} bytes are allowed", img_bytes.len(), self.max_output_bytes));
+        }
+        Ok(())
+    }
+
+    /// Caches and returns an encoded image, unless it has more than `max_output_bytes`
+    fn cache_output(&self, cache_key : String, img : EncodedImage, modified : Option<SystemTime>, content_key : Option<u64>) -> anyhow::Result<EncodedImage> {
+        self.check_output_size(&img.bytes)?;
+        self.cache_img(cache_key, img.clone(), modified, content_key)?;
+        Ok(img)
+    }
+
+    /// Processes the bytes of an encoded image like `fetch`, without reading it from a path or caching it
+    pub fn process_bytes(&self, raw_img_bytes : &[u8], width : u32, height : u32, options : &ImageOptions) -> anyhow::Result<EncodedImage> {
+        let img = self.decode_image(raw_img_bytes, options.frame)
+            .map_err(|e| anyhow!("Cannot decode {} : {:#}", describe_source("image", raw_img_bytes), e))?;
+        let orientation = exif::orientation(raw_img_bytes).unwrap_or(1);
+        let encoded_img = self.process_and_encode("image", &img, orientation, width, height, options)?;
+        self.check_output_size(&encoded_img.bytes)?;
+        Ok(encoded_img)
+    }
+
+    /// Like `fetch` for every path, in the same order.
+    /// The images over HTTP, which aren't cached yet, are downloaded at the same time first, so waiting for one server doesn't delay the others
+    pub fn fetch_batch(&self, paths : &[&str], width : u32, height : u32, options : &ImageOptions) -> anyhow::Result<Vec<EncodedImage>> {
+        let mut images = Vec::with_capacity(paths.len());
+        self.fetch_batch_each(paths, width, height, options, |img| {
+            images.push(img);
+            Ok(())
+        })?;
+        Ok(images)
+    }
+
+    /// Like `fetch_batch`, but passes every image to `on_image` as soon as it is loaded, instead of collecting them.
+    /// Stops at the first image, which fails to load or for which `on_image` fails
+    pub fn fetch_batch_each(&self, paths : &[&str], width : u32, height : u32, options : &ImageOptions, mut on_image : impl FnMut(EncodedImage) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        self.fetch_batch_results(paths, width, height, options, |result| on_image(result?))
+    }
+
+    /// Like `fetch_batch_each`, but passes the images, which failed to load, to `on_image` as well, instead of stopping.
+    /// Only stops once `on_image` fails
+    pub fn fetch_batch_results(&self, paths : &[&str], width : u32, height : u32, options : &ImageOptions, mut on_image : impl FnMut(anyhow::Result<EncodedImage>) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        let mut downloads = self.download_uncached(paths, width, height, options);
+        for path in paths {
+            on_image(self.fetch_downloaded(path, width, height, options, downloads.remove(*path)))?;
+        }
+        Ok(())
+    }
+
+    /// Caches the images like `fetch_batch`, but images that fail to load don't stop the others, returns how many were loaded
+    pub fn preload_batch(&self, paths : &[&str], width : u32, height : u32, options : &ImageOptions) -> usize {
+        self.preload_batch_each(paths, width, height, options, |_, _| {})
+    }
+
+    /// Like `preload_batch`, but calls `on_image` with every path and whether it was loaded, once it is done
+    pub fn preload_batch_each(&self, paths : &[&str], width : u32, height : u32, options : &ImageOptions, mut on_image : impl FnMut(&str, bool)) -> usize {
+        let mut downloads = self.download_uncached(paths, width, height, options);
+        paths.iter()
+            .filter(|path| {
+                let loaded = self.fetch_downloaded(path, width, height, options, downloads.remove(**path)).is_ok();
+                on_image(path, loaded);
+                loaded
+            })
+            .count()
+    }
+
+    /// Returns an ICO file with the image at `path` processed with the options at every size, from the cache if possible
+    pub fn ico(&self, path : &str, sizes : &[u32], options : &ImageOptions) -> anyhow::Result<EncodedImage> {
+        if sizes.is_empty() {
+            return Err(anyhow!("Missing argument : sizes"));
+        }
+        if let Some(size) = sizes.iter().find(|size| !(1..=MAX_ICON_SIZE).contains(*size)) {
+            return Err(anyhow!("Icon sizes have to be between 1 and {}, got {}", MAX_ICON_SIZE, size));
+        }
+        self.check_failure(path)?;
+        let sizes_name = sizes.iter().map(u32::to_string).collect::<Vec<_>>().join(",");
+        let cache_key = format!("{}|ico={}|{}", path, sizes_name, escape_key_options(options));
+        let (local_path, modified, changed) = self.resolve_source(path)?;
+        if let Some(ico) = self.cache.read().expect("Cannot read from cache").get(&cache_key, modified)? {
+            return Ok(ico);
+        }
+        let (img, orientation) = self.get_source_image(path, local_path, modified, changed.map(Ok), options.frame)?;
+        // ICO files can contain PNG images, which keep the transparency. They have to be 32 bit RGBA images, so they are never reduced to a palette
+        let mut png_options = options.clone();
+        png_options.format = OutputFormat::Png;
+        png_options.colors = None;
+        let icons = sizes.iter()
+            .map(|size| {
+                let icon_img = DynamicImage::ImageRgba8(self.process_image(&img, orientation, *size, *size, &png_options)?.to_rgba8());
+                Ok((icon_img.width(), icon_img.height(), process::encode_image(&icon_img, &png_options)?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let ico_bytes = process::pack_ico(&icons);
+        // An ICO file has the size of its largest icon
+        let (width, height, _) = icons.iter().max_by_key(|(width, height, _)| width * height).unwrap();
+        self.cache_output(cache_key, EncodedImage::new(ico_bytes, *width, *height), modified, None)
+    }
+
+    /// Combines the images at `paths`, processed with the options, into a grid with `columns` columns of `cell_width`x`cell_height` cells.
+    /// The cells of images, which can't be loaded, stay blank
+    pub fn montage(&self, paths : &[&str], columns : u32, cell_width : u32, cell_height : u32, options : &ImageOptions) -> anyhow::Result<EncodedImage> {
+        let rows = (paths.len() as u64).div_ceil(columns as u64);
+        let pixels = columns as u64 * cell_width as u64 * rows * cell_height as u64;
+        if pixels > self.max_pixels {
+            return Err(anyhow!("Montage is too large ({} pixels), at most {} pixels are allowed", pixels, self.max_pixels));
+        }
+        let mut downloads = self.download_uncached(paths, cell_width, cell_height, options);
+        let cells : Vec<_> = paths.iter()
+            .map(|path| {
+                let downloaded = downloads.remove(*path);
+                let cell = self.check_failure(path)
+                    .and_then(|_| self.resolve_source(path))
+                    .and_then(|(local_path, modified, changed)| self.get_source_image(path, local_path, modified, changed.map(Ok).or(downloaded), options.frame))
+                    .and_then(|(img, orientation)| self.process_image(&img, orientation, cell_width, cell_height, options));
+                match cell {
+                    Ok(cell) => Some(cell),
+                    Err(e) => {
+                        warn!("Leaving the montage cell of {} blank: {:#}", path, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        let montage_img = process::compose_grid(&cells, columns, cell_width, cell_height);
+        let encoded_img_bytes = process::encode_image(&montage_img, options)?;
+        self.check_output_size(&encoded_img_bytes)?;
+        Ok(EncodedImage::new(encoded_img_bytes, montage_img.width(), montage_img.height()))
+    }
+
+    /// Downloads the images over HTTP, which aren't cached, with at most as many at a time, as HTTP connections are kept open, counting the downloads of other threads
+    fn download_uncached(&self, paths : &[&str], width : u32, height : u32, options : &ImageOptions) -> HashMap<String, anyhow::Result<Vec<u8>>> {
+        let mut urls : Vec<_> = {
+            let unlocked_cache = self.cache.read().expect("Cannot read from cache");
+            paths.iter()
+                .filter(|path| matches!(remote::is_url(path), Ok(true)))
+                .filter(|path| unlocked_cache.get_failure(path, self.failure_ttl).is_none())
+                .filter(|path| {
+                    // Images, which aren't fresh anymore, are revalidated instead, when they are requested
+                    let version = unlocked_cache.get_remote_source(path).and_then(|source| source.version);
+                    !(unlocked_cache.contains(&get_cache_key(path, width, height, options), version)
+                        || self.cache_decoded && unlocked_cache.contains_decoded(path, version))
+                })
+                .copied()
+                .collect()
+        };
+        urls.sort_unstable();
+        urls.dedup();
+        // A single download doesn't need another thread
+        if urls.len() < 2 {return HashMap::new();}
+        let next_url = AtomicUsize::new(0);
+        let downloads = Mutex::new(HashMap::with_capacity(urls.len()));
+        std::thread::scope(|scope| {
+            for _ in 0..self.http_concurrency.min(urls.len()) {
+                scope.spawn(|| {
+                    while let Some(url) = urls.get(next_url.fetch_add(1, Ordering::Relaxed)) {
+                        let raw_img_bytes = self.fetch_url(url);
+                        downloads.lock().expect("Cannot lock downloads").insert(url.to_string(), raw_img_bytes);
+                    }
+                });
+            }
+        });
+        downloads.into_inner().expect("Cannot lock downloads")
+    }
+
+    /// Returns `width,height,format` of the upright image at `path`, only reading its header instead of decoding it
+    pub fn dimensions(&self, path : &str) -> anyhow::Result<String> {
+        self.check_failure(path)?;
+        let (local_path, modified, changed) = self.resolve_source(path)?;
+        if let Some(dimensions) = self.cache.read().expect("Cannot read from cache").get_info(path, "dimensions", modified) {
+            return Ok(dimensions);
+        }
+        let raw_img_bytes = self.remember_failure(path, self.read_image(path, local_path, changed.map(Ok)))?;
+        let reader = image::io::Reader::new(Cursor::new(&raw_img_bytes)).with_guessed_format()?;
+        let format = reader.format().ok_or(anyhow!("Unknown image format of {}", path))?;
+        let (width, height) = reader.into_dimensions()?;
+        let (width, height) = process::oriented_dimensions(width, height, exif::orientation(&raw_img_bytes).unwrap_or(1));
+        let dimensions = format!("{},{},{}", width, height, format!("{:?}", format).to_lowercase());
+        self.cache.write().expect("Cannot write to cache").insert_info(path.to_string(), "dimensions".to_string(), dimensions.clone(), modified);
+        Ok(dimensions)
+    }
+
+    /// Returns the [BlurHash](https://blurha.sh) of the image at `path`, with 1 to 9 components per axis
+    pub fn blurhash(&self, path : &str, components_x : u32, components_y : u32) -> anyhow::Result<String> {
+        let info_name = format!("blurhash={}x{}", components_x, components_y);
+        self.get_image_info(path, &info_name, |img| process::get_blurhash(img, components_x, components_y))
+    }
+
+    /// Returns up to `count` dominant colors of the image at `path` as comma separated `RRGGBB` hex colors, the most common first
+    pub fn dominant_colors(&self, path : &str, count : usize) -> anyhow::Result<String> {
+        if !(1..=MAX_DOMINANT_COLORS).contains(&count) {
+            return Err(anyhow!("Color count has to be between 1 and {}, got {}", MAX_DOMINANT_COLORS, count));
+        }
+        let info_name = format!("dominant_color={}", count);
+        self.get_image_info(path, &info_name, |img| Ok(process::get_dominant_colors(img, count)))
+    }
+
+    /// Returns the 64 bit perceptual hash of the image at `path` as 16 hex digits
+    pub fn phash(&self, path : &str) -> anyhow::Result<String> {
+        self.get_image_info(path, "phash", |img| Ok(process::get_phash(img)))
+    }
+
+    /// Whether the image is cached, without reading anything. It might be loaded again, if the local file was modified since it was cached
+    pub fn is_cached(&self, path : &str, width : u32, height : u32, options : &ImageOptions) -> bool {
+        self.cache.read().expect("Cannot read from cache").contains_key(&get_cache_key(path, width, height, options))
+    }
+
+    /// Whether all images of this batch were already fetched together and are still cached
+    pub fn is_batch_cached(&self, width : u32, height : u32, options : &ImageOptions, paths : &[&str]) -> bool {
+        let unlocked_cache = self.cache.read().expect("Cannot read from cache");
+        let instant = std::time::Instant::now();
+        let all_cached = unlocked_cache.is_fully_cached(get_paths_key(width, height, options, paths));
+        trace!(nanos = instant.elapsed().as_nanos() as u64, cached_batches = unlocked_cache.cached_paths_count(), all_cached, "Checked batch");
+        all_cached
+    }
+
+    /// Remembers that all images of this batch are cached, so `is_batch_cached` returns true until one of them is removed
+    pub fn set_batch_cached(&self, width : u32, height : u32, options : &ImageOptions, paths : &[&str]) {
+        self.cache.write().expect("Cannot write to cache").set_fully_cached(get_paths_key(width, height, options, paths));
+    }
+
+    /// Removes the cached images of `path`, of every size or only of `size`, returns how many were removed
+    pub fn remove(&self, path : &str, size : Option<(u32, u32)>) -> anyhow::Result<usize> {
+        let mut unlocked_cache = self.cache.write().expect("Cannot write to cache");
+        let removed = unlocked_cache.remove_path(path, size);
+        // The images are removed from the cache, even if some of their files couldn't be
+        if !matches!(removed, Ok(0)) {
+            unlocked_cache.write_index()?;
+        }
+        removed
+    }
+
+    /// Removes the images cached on disk to free disk space, keeping the ones in memory. Returns how many were removed
+    pub fn drop_disk_cache(&self) -> anyhow::Result<usize> {
+        let mut unlocked_cache = self.cache.write().expect("Cannot write to cache");
+        let removed = unlocked_cache.clear_disk();
+        if !matches!(removed, Ok(0)) {
+            unlocked_cache.write_index()?;
+        }
+        removed
+    }
+
+    pub fn clear_cache(&self) -> anyhow::Result<()> {
+        let mut unlocked_cache = self.cache.write().expect("Cannot write to cache");
+        let cleared = unlocked_cache.clear();
+        unlocked_cache.write_index()?;
+        cleared
+    }
+
+    /// Moves the images cached on disk to `cache_dir`, relative to the current working directory
+    pub fn set_cache_dir(&self, cache_dir : &str) -> anyhow::Result<()> {
+        let cache_dir = std::path::absolute(cache_dir)?;
+        self.cache.write().expect("Cannot write to cache").set_cache_dir(cache_dir)
+    }
+
+    /// Switches between reading local images at the same time and one at a time, reads already running aren't affected
+    pub fn set_threaded_reads(&self, threaded_reads : bool) {
+        self.threaded_reads.store(threaded_reads, Ordering::Relaxed);
+    }
+
+    /// Images loaded from now on are cached on disk instead of in memory, while less memory is available
+    pub fn set_min_available_memory(&self, min_available_memory : u64) {
+        self.min_available_memory.store(min_available_memory, Ordering::Relaxed);
+    }
+
+    /// The directory local images are read from, if reading them is restricted to one
+    pub fn read_root(&self) -> Option<&Path> {
+        self.read_root.as_deref()
+    }
+
+    /// How long the stages of loading images took so far
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.read().expect("Cannot read from cache").stats()
+    }
+
+    /// Writes the index of the images cached on disk, so the next run can load them again
+    pub fn write_index(&self) -> anyhow::Result<()> {
+        self.cache.read().expect("Cannot read from cache").write_index()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use image::{GenericImageView, ImageFormat};
+    use crate::test_util::*;
+    use super::*;
+
+    #[test]
+    fn caches_every_size_of_a_path_separately() {
+        let dir = test_dir("caches_every_size_of_a_path_separately");
+        let path = write_image(&dir, "source.bmp", &solid_image(200, 100, [255, 0, 0]), ImageFormat::Bmp);
+        let server = server(&dir, &setup_options());
+        let options = ImageOptions::default();
+        for _ in 0..2 {
+            assert_eq!(decode(&server.fetch(&path, 64, 32, &options).unwrap().bytes).dimensions(), (64, 32));
+            assert_eq!(decode(&server.fetch(&path, 20, 10, &options).unwrap().bytes).dimensions(), (20, 10));
+        }
+        assert_eq!(server.cache_stats().entries, 2);
+    }
+
+    #[test]
+    fn fails_if_the_connection_is_dropped_in_the_middle_of_the_body() {
+        let dir = test_dir("fails_if_the_connection_is_dropped_in_the_middle_of_the_body");
+        let img_bytes = encode(&solid_image(8, 8, [0, 0, 255]), ImageFormat::Png);
+        let http_server = HttpServer::new(move |_| {
+            let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", img_bytes.len()).into_bytes();
+            response.extend_from_slice(&img_bytes[..img_bytes.len() / 2]);
+            response
+        });
+        let server = server(&dir, &http_options());
+        let error = server.fetch(&http_server.url("/image.png"), 4, 4, &ImageOptions::default()).unwrap_err();
+        assert!(format!("{:#}", error).contains("image.png"), "{:#}", error);
+    }
+
+    #[test]
+    fn caches_every_quality_of_a_path_separately() {
+        let low_quality_key = get_cache_key("a.png", 8, 8, &image_options(&["jpeg", "quality=50"]));
+        assert_ne!(low_quality_key, get_cache_key("a.png", 8, 8, &image_options(&["jpeg", "quality=90"])));
+        assert!(ImageOptions::parse(&["quality=0"]).is_err());
+        assert!(ImageOptions::parse(&["quality=101"]).is_err());
+    }
+
+    #[test]
+    fn reuses_the_connection_to_a_host() {
+        let dir = test_dir("reuses_the_connection_to_a_host");
+        let img_bytes = encode(&solid_image(8, 8, [0, 255, 0]), ImageFormat::Png);
+        let http_server = HttpServer::new(move |_| http_response(&img_bytes));
+        let server = server(&dir, &http_options());
+        for i in 0..50 {
+            server.fetch(&http_server.url(&format!("/image{}.png", i)), 4, 4, &ImageOptions::default()).unwrap();
+        }
+        assert_eq!(http_server.connection_count(), 1);
+    }
+
+    #[test]
+    fn fails_fetching_from_a_slow_host_after_the_timeout() {
+        let dir = test_dir("fails_fetching_from_a_slow_host_after_the_timeout");
+        let img_bytes = encode(&solid_image(8, 8, [0, 255, 0]), ImageFormat::Png);
+        let http_server = HttpServer::new(move |head| {
+            if head.contains("/slow") {
+                std::thread::sleep(Duration::from_secs(3));
+            }
+            http_response(&img_bytes)
+        });
+        let server = server(&dir, &SetupOptions {http_timeout: Duration::from_millis(300), ..http_options()});
+        let (slow_url, fast_url) = (http_server.url("/slow.png"), http_server.url("/fast.png"));
+        let start = Instant::now();
+        let mut results = Vec::new();
+        server.fetch_batch_results(&[&slow_url, &fast_url], 4, 4, &ImageOptions::default(), |result| {
+            results.push(result);
+            Ok(())
+        }).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2), "{:?}", start.elapsed());
+        assert!(results[0].is_err());
+        assert!(results[1].is_ok());
+    }
+
+    #[test]
+    fn only_reads_files_in_the_root_directory() {
+        let dir = test_dir("only_reads_files_in_the_root_directory");
+        let root = dir.join("root");
+        std::fs::create_dir(&root).unwrap();
+        let img = solid_image(8, 8, [255, 0, 0]);
+        let inside_path = write_image(&root, "inside.bmp", &img, ImageFormat::Bmp);
+        let outside_path = write_image(&dir, "outside.bmp", &img, ImageFormat::Bmp);
+        let server = server(&dir, &SetupOptions {read_root: Some(root.clone()), ..setup_options()});
+        let options = ImageOptions::default();
+        // Relative to the working directory of the tests
+        let working_dir = std::env::current_dir().unwrap();
+        let common_count = working_dir.components().zip(Path::new(&inside_path).components()).take_while(|(a, b)| a == b).count();
+        let mut relative_path : PathBuf = working_dir.components().skip(common_count).map(|_| "..").collect();
+        relative_path.extend(Path::new(&inside_path).components().skip(common_count));
+        assert!(server.fetch(relative_path.to_str().unwrap(), 4, 4, &options).is_ok());
+        let escaping_path = format!("{}/../outside.bmp", root.to_str().unwrap());
+        assert!(format!("{}", server.fetch(&escaping_path, 4, 4, &options).unwrap_err()).contains("outside of the root directory"));
+        assert!(format!("{}", server.fetch(&outside_path, 4, 4, &options).unwrap_err()).contains("outside of the root directory"));
+    }
+
+    #[test]
+    fn rejects_images_declaring_too_many_pixels_before_decoding_them() {
+        let dir = test_dir("rejects_images_declaring_too_many_pixels_before_decoding_them");
+        // A BMP header of a 50000x50000 image without any pixels
+        let mut bmp_bytes = b"BM".to_vec();
+        for value in [54u32, 0, 54, 40, 50000, 50000] {
+            bmp_bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bmp_bytes.extend_from_slice(&1u16.to_le_bytes());
+        bmp_bytes.extend_from_slice(&24u16.to_le_bytes());
+        bmp_bytes.extend_from_slice(&[0; 24]);
+        let path = dir.join("bomb.bmp");
+        std::fs::write(&path, bmp_bytes).unwrap();
+        let server = server(&dir, &setup_options());
+        let error = server.fetch(path.to_str().unwrap(), 4, 4, &ImageOptions::default()).unwrap_err();
+        assert!(format!("{:#}", error).contains("too large (50000x50000)"), "{:#}", error);
+    }
+
+    #[test]
+    fn rejects_requests_of_too_many_pixels_before_processing_them() {
+        let dir = test_dir("rejects_requests_of_too_many_pixels_before_processing_them");
+        let server = server(&dir, &SetupOptions {max_pixels: 10_000, ..setup_options()});
+        let path = write_image(&dir, "image.png", &solid_image(80, 80, [255, 0, 0]), ImageFormat::Png);
+        let start = Instant::now();
+        let error = server.fetch(&path, 60000, 60000, &ImageOptions::default()).unwrap_err();
+        assert!(format!("{:#}", error).contains("too large (3600000000 pixels), at most 10000"), "{:#}", error);
+        // Padding and rotating make the image larger than the requested size
+        let error = server.fetch(&path, 4, 60000, &image_options(&["resize=fit", "pad=ffffff"])).unwrap_err();
+        assert!(format!("{:#}", error).contains("too large (240000 pixels)"), "{:#}", error);
+        let error = server.fetch(&path, 8, 8, &image_options(&["rotate=45"])).unwrap_err();
+        assert!(format!("{:#}", error).contains("too large (12769 pixels)"), "{:#}", error);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(server.fetch(&path, 100, 100, &ImageOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn keeps_the_cache_keys_of_watermark_paths_containing_commas_apart() {
+        let watermark_options = ImageOptions {watermark: Some("a,mask=circle".to_string()), ..Default::default()};
+        let mask_options = ImageOptions {watermark: Some("a".to_string()), mask: Some(Mask::Circle), ..Default::default()};
+        assert_ne!(get_cache_key("image.png", 8, 8, &watermark_options), get_cache_key("image.png", 8, 8, &mask_options));
+        let escaped_options = ImageOptions {watermark: Some("a%2Cmask=circle".to_string()), ..Default::default()};
+        assert_ne!(get_cache_key("image.png", 8, 8, &watermark_options), get_cache_key("image.png", 8, 8, &escaped_options));
+    }
+
+    #[test]
+    fn keeps_batches_of_concatenating_to_the_same_paths_apart() {
+        let dir = test_dir("keeps_batches_of_concatenating_to_the_same_paths_apart");
+        let options = ImageOptions::default();
+        assert_ne!(get_paths_key(8, 8, &options, &["ab", "c"]), get_paths_key(8, 8, &options, &["a", "bc"]));
+        assert_ne!(get_paths_key(8, 8, &options, &["a"]), get_paths_key(8, 4, &options, &["a"]));
+        let server = server(&dir, &setup_options());
+        server.set_batch_cached(8, 8, &options, &["ab", "c"]);
+        assert!(!server.is_batch_cached(8, 8, &options, &["a", "bc"]));
+    }
+
+    #[test]
+    fn removes_only_the_images_of_one_path() {
+        let dir = test_dir("removes_only_the_images_of_one_path");
+        let removed_path = write_image(&dir, "removed.bmp", &solid_image(8, 8, [255, 0, 0]), ImageFormat::Bmp);
+        let kept_path = write_image(&dir, "kept.bmp", &solid_image(8, 8, [0, 0, 255]), ImageFormat::Bmp);
+        let server = server(&dir, &setup_options());
+        let options = ImageOptions::default();
+        for path in [&removed_path, &kept_path] {
+            server.fetch(path, 4, 4, &options).unwrap();
+            server.fetch(path, 2, 2, &options).unwrap();
+        }
+        assert_eq!(server.remove(&removed_path, Some((2, 2))).unwrap(), 1);
+        assert_eq!(server.remove(&removed_path, None).unwrap(), 1);
+        assert!(!server.is_cached(&removed_path, 4, 4, &options));
+        let hits = server.cache_stats().hits;
+        server.fetch(&kept_path, 4, 4, &options).unwrap();
+        assert_eq!(server.cache_stats().hits, hits + 1);
+        assert_eq!(server.cache_stats().entries, 2);
+    }
+
+    #[test]
+    fn loads_a_rewritten_file_again() {
+        let dir = test_dir("loads_a_rewritten_file_again");
+        let path = write_image(&dir, "source.bmp", &solid_image(8, 8, [255, 0, 0]), ImageFormat::Bmp);
+        let server = server(&dir, &setup_options());
+        let options = ImageOptions::default();
+        assert_eq!(decode(&server.fetch(&path, 4, 4, &options).unwrap().bytes).to_rgb8().get_pixel(2, 2).0, [255, 0, 0]);
+        write_image(&dir, "source.bmp", &solid_image(8, 8, [0, 0, 255]), ImageFormat::Bmp);
+        // The file system might not tell writes this close apart
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert_eq!(decode(&server.fetch(&path, 4, 4, &options).unwrap().bytes).to_rgb8().get_pixel(2, 2).0, [0, 0, 255]);
+    }
+
+    #[test]
+    fn caches_on_disk_below_the_min_available_memory() {
+        let dir = test_dir("caches_on_disk_below_the_min_available_memory");
+        let path = write_image(&dir, "source.bmp", &solid_image(8, 8, [255, 0, 0]), ImageFormat::Bmp);
+        let options = ImageOptions::default();
+        let disk_server = server(&dir.join("disk"), &SetupOptions {min_available_memory: u64::MAX, ..setup_options()});
+        disk_server.fetch(&path, 4, 4, &options).unwrap();
+        assert_eq!(disk_server.cache_stats().disk_entries, 1);
+        let memory_server = server(&dir.join("memory"), &setup_options());
+        memory_server.fetch(&path, 4, 4, &options).unwrap();
+        assert_eq!(memory_server.cache_stats().disk_entries, 0);
+    }
+
+    #[test]
+    fn caches_images_without_upscaling_separately() {
+        let dir = test_dir("caches_images_without_upscaling_separately");
+        let path = write_image(&dir, "icon.png", &solid_image(32, 32, [0, 255, 0]), ImageFormat::Png);
+        let server = server(&dir, &setup_options());
+        assert_eq!(server.fetch(&path, 256, 256, &image_options(&["no_upscale"])).unwrap().width, 32);
+        assert_eq!(server.fetch(&path, 256, 256, &ImageOptions::default()).unwrap().width, 256);
+    }
+
+    #[test]
+    fn rotates_jpegs_by_their_orientation() {
+        let dir = test_dir("rotates_jpegs_by_their_orientation");
+        let path = dir.join("rotated.jpg");
+        std::fs::write(&path, with_jpeg_orientation(&encode(&solid_image(200, 100, [255, 0, 0]), ImageFormat::Jpeg), 6)).unwrap();
+        let path = path.to_str().unwrap();
+        let server = server(&dir, &setup_options());
+        let img = server.fetch(path, 100, 100, &image_options(&["resize=fit"])).unwrap();
+        assert_eq!((img.width, img.height), (50, 100));
+        assert_eq!(decode(&img.bytes).dimensions(), (50, 100));
+        let img = server.fetch(path, 100, 100, &image_options(&["resize=fit", "ignore_orientation"])).unwrap();
+        assert_eq!((img.width, img.height), (100, 50));
+    }
+
+    #[test]
+    fn fetches_batches_processes_bytes_and_clears_the_cache() {
+        let dir = test_dir("fetches_batches_processes_bytes_and_clears_the_cache");
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255]];
+        let paths : Vec<String> = colors.iter().enumerate()
+            .map(|(i, color)| write_image(&dir, &format!("{}.png", i), &solid_image(16, 8, *color), ImageFormat::Png))
+            .collect();
+        let paths : Vec<&str> = paths.iter().map(String::as_str).collect();
+        let server = server(&dir, &setup_options());
+        let options = ImageOptions::default();
+        let images = server.fetch_batch(&paths, 8, 4, &options).unwrap();
+        let batch_colors : Vec<[u8; 3]> = images.iter().map(|img| decode(&img.bytes).to_rgb8().get_pixel(0, 0).0).collect();
+        assert_eq!(batch_colors, colors);
+        let img = server.process_bytes(&encode(&solid_image(16, 8, [9, 9, 9]), ImageFormat::Bmp), 4, 2, &image_options(&["png"])).unwrap();
+        assert_eq!(decode(&img.bytes).dimensions(), (4, 2));
+        assert_eq!(server.cache_stats().entries, 3);
+        server.clear_cache().unwrap();
+        assert_eq!(server.cache_stats().entries, 0);
+    }
+
+    #[test]
+    fn fetches_the_urls_of_a_batch_in_parallel() {
+        let dir = test_dir("fetches_the_urls_of_a_batch_in_parallel");
+        let img_bytes = encode(&solid_image(8, 8, [0, 255, 0]), ImageFormat::Png);
+        let http_server = HttpServer::new(move |_| {
+            std::thread::sleep(Duration::from_millis(500));
+            http_response(&img_bytes)
+        });
+        let server = server(&dir, &http_options());
+        let urls : Vec<String> = (0..4).map(|i| http_server.url(&format!("/{}.png", i))).collect();
+        let urls : Vec<&str> = urls.iter().map(String::as_str).collect();
+        let start = Instant::now();
+        assert_eq!(server.fetch_batch(&urls, 4, 4, &ImageOptions::default()).unwrap().len(), 4);
+        // Fetching them one after another would take 2 seconds
+        assert!(start.elapsed() < Duration::from_millis(1200), "{:?}", start.elapsed());
+    }
+
+    #[test]
+    fn fails_right_away_for_a_while_after_a_failure() {
+        let dir = test_dir("fails_right_away_for_a_while_after_a_failure");
+        let path = dir.join("late.bmp").to_str().unwrap().to_string();
+        let server = server(&dir, &SetupOptions {failure_ttl: Duration::from_millis(500), ..setup_options()});
+        let options = ImageOptions::default();
+        let error = server.fetch(&path, 4, 4, &options).unwrap_err().to_string();
+        write_image(&dir, "late.bmp", &solid_image(8, 8, [255, 0, 0]), ImageFormat::Bmp);
+        // The file isn't read again, so it still fails like before
+        let start = Instant::now();
+        assert_eq!(server.fetch(&path, 4, 4, &options).unwrap_err().to_string(), error);
+        assert!(start.elapsed() < Duration::from_millis(50), "{:?}", start.elapsed());
+        std::thread::sleep(Duration::from_millis(600));
+        assert!(server.fetch(&path, 4, 4, &options).is_ok());
+    }
+
+    #[test]
+    fn fails_instead_of_returning_images_over_the_output_limit() {
+        let dir = test_dir("fails_instead_of_returning_images_over_the_output_limit");
+        let path = write_image(&dir, "large.png", &solid_image(512, 512, [255, 0, 0]), ImageFormat::Png);
+        let server = server(&dir, &SetupOptions {max_output_bytes: 10_000, ..setup_options()});
+        let options = ImageOptions::default();
+        let error = server.fetch(&path, 512, 512, &options).unwrap_err().to_string();
+        assert!(error.starts_with("Encoded image is too large ("), "{}", error);
+        assert!(!server.is_cached(&path, 512, 512, &options));
+        assert!(server.fetch(&path, 32, 32, &options).is_ok());
+    }
+
+    #[test]
+    fn encodes_the_same_content_of_two_paths_once() {
+        let dir = test_dir("encodes_the_same_content_of_two_paths_once");
+        let img = solid_image(16, 16, [255, 0, 0]);
+        let first_path = write_image(&dir, "first.png", &img, ImageFormat::Png);
+        let copied_path = write_image(&dir, "copied.png", &img, ImageFormat::Png);
+        let server = server(&dir, &setup_options());
+        let options = ImageOptions::default();
+        let first_img = server.fetch(&first_path, 8, 8, &options).unwrap();
+        let copied_img = server.fetch(&copied_path, 8, 8, &options).unwrap();
+        assert_eq!(first_img.bytes, copied_img.bytes);
+        assert_eq!(server.metrics().summary(Stage::Encode).count, 1);
+        server.fetch(&copied_path, 4, 4, &options).unwrap();
+        assert_eq!(server.metrics().summary(Stage::Encode).count, 2);
+    }
+
+    #[test]
+    fn caches_images_compressed_in_memory() {
+        let dir = test_dir("caches_images_compressed_in_memory");
+        let path = write_image(&dir, "source.png", &solid_image(64, 64, [30, 60, 90]), ImageFormat::Png);
+        let options = ImageOptions::default();
+        let server = server(&dir.join("compressed"), &SetupOptions {compress_memory: true, ..setup_options()});
+        let img = server.fetch(&path, 64, 64, &options).unwrap();
+        assert!(server.cache_stats().memory_bytes * 10 < img.bytes.len(), "{} bytes cached of {}", server.cache_stats().memory_bytes, img.bytes.len());
+        assert_eq!(server.fetch(&path, 64, 64, &options).unwrap().bytes, img.bytes);
+        assert_eq!(server.cache_stats().hits, 1);
+    }
+
+    #[test]
+    fn montages_images_into_a_grid() {
+        let dir = test_dir("montages_images_into_a_grid");
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 0]];
+        let paths : Vec<String> = colors.iter().enumerate()
+            .map(|(i, color)| write_image(&dir, &format!("{}.png", i), &solid_image(30, 20, *color), ImageFormat::Png))
+            .collect();
+        let server = server(&dir, &setup_options());
+        let options = image_options(&["png"]);
+        let quadrants = [(5, 5), (15, 5), (5, 15), (15, 15)];
+        let paths : Vec<&str> = paths.iter().map(String::as_str).collect();
+        let montage_img = decode(&server.montage(&paths, 2, 10, 10, &options).unwrap().bytes).to_rgba8();
+        assert_eq!(montage_img.dimensions(), (20, 20));
+        for ((x, y), [red, green, blue]) in quadrants.into_iter().zip(colors) {
+            assert_eq!(montage_img.get_pixel(x, y).0, [red, green, blue, 255]);
+        }
+        let missing_path = dir.join("missing.png");
+        let montage_img = decode(&server.montage(&[paths[0], missing_path.to_str().unwrap()], 2, 10, 10, &options).unwrap().bytes).to_rgba8();
+        assert_eq!(montage_img.get_pixel(15, 5).0, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn checks_every_redirect_before_following_it() {
+        let dir = test_dir("checks_every_redirect_before_following_it");
+        let http_server = HttpServer::new(|head| {
+            let location = if head.starts_with("GET /away") {"http://blocked.example.com/a.png"} else {"/loop"};
+            format!("HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n", location).into_bytes()
+        });
+        let server = server(&dir, &SetupOptions {max_redirects: 3, ..http_options()});
+        let options = ImageOptions::default();
+        let error = server.fetch(&http_server.url("/away"), 4, 4, &options).unwrap_err().to_string();
+        assert_eq!(error, "Host blocked.example.com is not allowed");
+        let error = server.fetch(&http_server.url("/loop"), 4, 4, &options).unwrap_err().to_string();
+        assert!(error.ends_with("Too many redirects, at most 3 are followed"), "{}", error);
+    }
+
+    #[test]
+    fn switches_between_reading_at_the_same_time_and_one_at_a_time() {
+        let dir = test_dir("switches_between_reading_at_the_same_time_and_one_at_a_time");
+        let server = PictoServer::new(dir.join("cache").to_str().unwrap(), false, &setup_options()).unwrap();
+        let options = image_options(&[]);
+        for (i, threaded_reads) in [false, true, false].into_iter().enumerate() {
+            server.set_threaded_reads(threaded_reads);
+            let path = write_image(&dir, &format!("{}.png", i), &solid_image(4, 4, [i as u8, 0, 0]), ImageFormat::Png);
+            let read_count = server.metrics().summary(Stage::Read).count;
+            // Reads one at a time wait for the write lock of the cache, which can't be had while it is read
+            let unlocked_cache = server.cache.read().unwrap();
+            std::thread::scope(|scope| {
+                let fetching = scope.spawn(|| server.fetch(&path, 2, 2, &options));
+                let instant = Instant::now();
+                while server.metrics().summary(Stage::Read).count == read_count && instant.elapsed() < Duration::from_millis(300) {
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+                assert_eq!(server.metrics().summary(Stage::Read).count > read_count, threaded_reads);
+                std::mem::drop(unlocked_cache);
+                fetching.join().unwrap().unwrap();
+            });
+        }
+    }
+
+    #[test]
+    fn reads_the_dimensions_from_the_header_only() {
+        let dir = test_dir("reads_the_dimensions_from_the_header_only");
+        let server = server(&dir, &setup_options());
+        // Decoding the cut off pixels would fail
+        let png_bytes = encode(&noise_image(300, 200), ImageFormat::Png);
+        let png_path = dir.join("cut_off.png");
+        std::fs::write(&png_path, &png_bytes[..png_bytes.len() / 2]).unwrap();
+        let png_path = png_path.to_str().unwrap();
+        assert_eq!(server.dimensions(png_path).unwrap(), "300,200,png");
+        assert!(server.fetch(png_path, 30, 20, &image_options(&[])).is_err());
+
+        let jpeg_path = dir.join("rotated.jpg");
+        std::fs::write(&jpeg_path, with_jpeg_orientation(&encode(&solid_image(30, 20, [0, 0, 255]), ImageFormat::Jpeg), 6)).unwrap();
+        assert_eq!(server.dimensions(jpeg_path.to_str().unwrap()).unwrap(), "20,30,jpeg");
+        assert_eq!(server.metrics().summary(Stage::Decode).count, 0);
+    }
+
+    #[test]
+    fn thumbnails_the_frames_of_animated_gifs() {
+        let dir = test_dir("thumbnails_the_frames_of_animated_gifs");
+        let server = server(&dir, &setup_options());
+        let mut gif_bytes = Vec::new();
+        let frames = [[255, 0, 0, 255], [0, 0, 255, 255]].map(|color| image::Frame::new(image::RgbaImage::from_pixel(16, 16, image::Rgba(color))));
+        image::gif::GifEncoder::new(&mut gif_bytes).encode_frames(frames).unwrap();
+        let path = dir.join("animated.gif");
+        std::fs::write(&path, gif_bytes).unwrap();
+        let path = path.to_str().unwrap();
+        for (args, color) in [(&[][..], [255, 0, 0]), (&["frame=1"][..], [0, 0, 255])] {
+            let img = decode(&server.fetch(path, 8, 8, &image_options(args)).unwrap().bytes);
+            assert_eq!(img.dimensions(), (8, 8));
+            assert!(img.to_rgb8().pixels().all(|pixel| pixel.0 == color), "{:?} of {:?}", img.get_pixel(0, 0), args);
+        }
+        assert!(server.fetch(path, 8, 8, &image_options(&["frame=2"])).is_err());
+    }
+
+    #[test]
+    fn packs_every_size_into_an_ico() {
+        let dir = test_dir("packs_every_size_into_an_ico");
+        let server = server(&dir, &setup_options());
+        let path = write_image(&dir, "image.png", &solid_image(64, 64, [255, 0, 0]), ImageFormat::Png);
+        let ico = server.ico(&path, &[16, 32, 48], &image_options(&[])).unwrap();
+        assert_eq!((ico.width, ico.height), (48, 48));
+        let ico_bytes = &ico.bytes;
+        assert_eq!(ico_bytes[..6], [0, 0, 1, 0, 3, 0]);
+        for (i, size) in [16, 32, 48].into_iter().enumerate() {
+            let entry = &ico_bytes[6 + i * 16..6 + (i + 1) * 16];
+            assert_eq!(entry[..2], [size as u8, size as u8]);
+            let length = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as usize;
+            let offset = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as usize;
+            let png_img = image::load_from_memory_with_format(&ico_bytes[offset..offset + length], ImageFormat::Png).unwrap();
+            assert_eq!(png_img.dimensions(), (size, size));
+        }
+        assert_eq!(image::load_from_memory_with_format(ico_bytes, ImageFormat::Ico).unwrap().dimensions(), (48, 48));
+        for sizes in [&[][..], &[0], &[16, 257]] {
+            assert!(server.ico(&path, sizes, &image_options(&[])).is_err(), "{:?}", sizes);
+        }
+    }
+
+    #[test]
+    fn revalidates_images_over_http_without_decoding_them_again() {
+        let dir = test_dir("revalidates_images_over_http_without_decoding_them_again");
+        let server = server(&dir, &http_options());
+        let etag = Arc::new(Mutex::new("v1"));
+        let revalidation_count = Arc::new(AtomicUsize::new(0));
+        let (response_etag, response_revalidation_count) = (etag.clone(), revalidation_count.clone());
+        let http_server = HttpServer::new(move |head| {
+            let etag = *response_etag.lock().unwrap();
+            if head.to_lowercase().contains(&format!("if-none-match: \"{}\"", etag)) {
+                response_revalidation_count.fetch_add(1, Ordering::SeqCst);
+                return b"HTTP/1.1 304 Not Modified\r\nCache-Control: no-cache\r\n\r\n".to_vec();
+            }
+            let color = if etag == "v1" {[255, 0, 0]} else {[0, 0, 255]};
+            let body = encode(&solid_image(8, 8, color), ImageFormat::Png);
+            let mut response = format!("HTTP/1.1 200 OK\r\nETag: \"{}\"\r\nCache-Control: no-cache\r\nContent-Length: {}\r\n\r\n", etag, body.len()).into_bytes();
+            response.extend(body);
+            response
+        });
+        let url = http_server.url("/image.png");
+        let options = image_options(&[]);
+        let fetch_color = || decode(&server.fetch(&url, 4, 4, &options).unwrap().bytes).to_rgb8().get_pixel(2, 2).0;
+        assert_eq!(fetch_color(), [255, 0, 0]);
+        assert_eq!(fetch_color(), [255, 0, 0]);
+        assert_eq!(fetch_color(), [255, 0, 0]);
+        assert_eq!(revalidation_count.load(Ordering::SeqCst), 2);
+        assert_eq!(server.metrics().summary(Stage::Decode).count, 1);
+        assert_eq!(server.cache_stats().hits, 2);
+
+        *etag.lock().unwrap() = "v2";
+        assert_eq!(fetch_color(), [0, 0, 255]);
+        assert_eq!(server.metrics().summary(Stage::Decode).count, 2);
+    }
+
+    /// The PNG image with an `eXIf` chunk telling the orientation, right after the header chunk
+    fn with_png_orientation(png_bytes: &[u8], orientation: u16) -> Vec<u8> {
+        let chunk = [&b"eXIf"[..], &exif_tiff(orientation)].concat();
+        let mut crc = !0u32;
+        for byte in &chunk {
+            crc ^= u32::from(*byte);
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {(crc >> 1) ^ 0xEDB8_8320} else {crc >> 1};
+            }
+        }
+        // The signature and the header chunk
+        let mut img_bytes = png_bytes[..33].to_vec();
+        img_bytes.extend_from_slice(&(chunk.len() as u32 - 4).to_be_bytes());
+        img_bytes.extend_from_slice(&chunk);
+        img_bytes.extend_from_slice(&(!crc).to_be_bytes());
+        img_bytes.extend_from_slice(&png_bytes[33..]);
+        img_bytes
+    }
+
+    #[test]
+    fn returns_sources_of_the_requested_size_and_format_without_encoding_them() {
+        let dir = test_dir("returns_sources_of_the_requested_size_and_format_without_encoding_them");
+        let server = server(&dir, &setup_options());
+        let img = noise_image(8, 8);
+        let bmp_path = write_image(&dir, "image.bmp", &img, ImageFormat::Bmp);
+        let png_path = write_image(&dir, "image.png", &img, ImageFormat::Png);
+        let exif_path = dir.join("exif.png");
+        std::fs::write(&exif_path, with_png_orientation(&encode(&img, ImageFormat::Png), 1)).unwrap();
+        let exif_path = exif_path.to_str().unwrap();
+        assert_eq!(exif::orientation(&std::fs::read(exif_path).unwrap()), Some(1));
+        let encode_count = || server.metrics().summary(Stage::Encode).count;
+
+        for (path, format) in [(&bmp_path, "bmp"), (&png_path, "png")] {
+            let fetched = server.fetch(path, 8, 8, &image_options(&[format])).unwrap();
+            assert_eq!(*fetched.bytes, std::fs::read(path).unwrap(), "{}", format);
+        }
+        assert_eq!((encode_count(), server.metrics().summary(Stage::Decode).count), (0, 0));
+        // Only the unchanged image is returned as it is, not the ones of other sizes or options
+        let grayscale_img = decode(&server.fetch(&bmp_path, 8, 8, &image_options(&["grayscale"])).unwrap().bytes).to_rgb8();
+        assert!(grayscale_img.pixels().all(|pixel| pixel.0[0] == pixel.0[1] && pixel.0[1] == pixel.0[2]));
+        assert_eq!(server.fetch(&bmp_path, 4, 4, &image_options(&[])).unwrap().width, 4);
+        assert_eq!(encode_count(), 2);
+        // The EXIF data would be returned too
+        let exif_bytes = server.fetch(exif_path, 8, 8, &image_options(&["png"])).unwrap().bytes;
+        assert!(exif::png_exif(&exif_bytes).is_none());
+        assert_eq!(encode_count(), 3);
+    }
+
+    #[test]
+    fn stamps_the_watermark_into_the_corner() {
+        let dir = test_dir("stamps_the_watermark_into_the_corner");
+        let watermark_path = write_image(&dir, "watermark.png", &solid_image(4, 4, [255, 0, 0]), ImageFormat::Png);
+        let server = server(&dir, &SetupOptions {watermark: Some(PathBuf::from(&watermark_path)), ..setup_options()});
+        let path = write_image(&dir, "image.png", &solid_image(32, 32, [0, 0, 255]), ImageFormat::Png);
+        let watermark_arg = format!("watermark={}", watermark_path);
+        let watermarked_pixels = |args: &[&str]| {
+            let img = decode(&server.fetch(&path, 32, 32, &image_options(args)).unwrap().bytes).to_rgb8();
+            img.enumerate_pixels().filter(|(_, _, pixel)| pixel.0 != [0, 0, 255]).map(|(x, y, pixel)| {
+                assert_eq!(pixel.0, [255, 0, 0], "{} {} of {:?}", x, y, args);
+                (x, y)
+            }).collect::<Vec<_>>()
+        };
+        let square = |from_x: u32, from_y: u32| -> Vec<(u32, u32)> {
+            (from_y..from_y + 4).flat_map(|y| (from_x..from_x + 4).map(move |x| (x, y))).collect()
+        };
+        assert_eq!(watermarked_pixels(&[&watermark_arg, "watermark_margin=2"]), square(26, 26));
+        // The watermark was decoded the first time already
+        std::fs::remove_file(&watermark_path).unwrap();
+        assert_eq!(watermarked_pixels(&[&watermark_arg, "watermark_corner=top_left", "watermark_margin=2"]), square(2, 2));
+        assert_eq!(watermarked_pixels(&["watermark"]), square(28, 28));
+        assert!(watermarked_pixels(&[]).is_empty());
+        let translucent_img = decode(&server.fetch(&path, 32, 32, &image_options(&["watermark", "watermark_opacity=0.5"])).unwrap().bytes).to_rgb8();
+        let [red, _, blue] = translucent_img.get_pixel(30, 30).0;
+        assert!(red.abs_diff(128) <= 1 && blue.abs_diff(127) <= 1, "{:?}", translucent_img.get_pixel(30, 30));
+    }
+
+    #[test]
+    fn caches_only_in_memory_without_a_writable_cache_dir() {
+        let dir = test_dir("caches_only_in_memory_without_a_writable_cache_dir");
+        // Nothing can be created inside of a file, even by root
+        std::fs::write(dir.join("file"), b"").unwrap();
+        let cache_dir = dir.join("file").join("cache");
+        let cache_dir = cache_dir.to_str().unwrap();
+        assert!(PictoServer::new(cache_dir, true, &setup_options()).is_err());
+        let path = write_image(&dir, "image.png", &solid_image(8, 8, [255, 0, 0]), ImageFormat::Png);
+        let options = image_options(&[]);
+
+        let server = PictoServer::new(cache_dir, true, &SetupOptions {memory_only: true, ..setup_options()}).unwrap();
+        server.fetch(&path, 4, 4, &options).unwrap();
+        server.fetch(&path, 4, 4, &options).unwrap();
+        let stats = server.cache_stats();
+        assert_eq!((stats.entries, stats.disk_entries, stats.hits), (1, 0, 1));
+        server.write_index().unwrap();
+        assert_eq!(server.drop_disk_cache().unwrap(), 0);
+        server.clear_cache().unwrap();
+
+        // Images aren't spilled to disk, if memory is low
+        let low_memory_options = SetupOptions {memory_only: true, min_available_memory: u64::MAX, ..setup_options()};
+        let server = PictoServer::new(cache_dir, true, &low_memory_options).unwrap();
+        assert_eq!(server.fetch(&path, 4, 4, &options).unwrap().width, 4);
+        assert_eq!(server.cache_stats().entries, 0);
+        server.clear_cache().unwrap();
+        assert!(dir.join("file").is_file());
+    }
+
+    #[test]
+    fn loads_an_image_requested_at_the_same_time_once() {
+        let dir = test_dir("loads_an_image_requested_at_the_same_time_once");
+        let server = server(&dir, &http_options());
+        let img_bytes = encode(&noise_image(64, 64), ImageFormat::Png);
+        let http_server = HttpServer::new(move |_| {
+            // So all of the requests arrive while the first one is loading
+            std::thread::sleep(Duration::from_millis(300));
+            http_response(&img_bytes)
+        });
+        let url = http_server.url("/image.png");
+        let options = image_options(&[]);
+        let request_count = 8;
+        let barrier = std::sync::Barrier::new(request_count);
+        let results : Vec<_> = std::thread::scope(|scope| {
+            let handles : Vec<_> = (0..request_count).map(|_| scope.spawn(|| {
+                barrier.wait();
+                server.fetch(&url, 16, 16, &options)
+            })).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap().unwrap().bytes.to_vec()).collect()
+        });
+        assert!(results.iter().all(|img_bytes| *img_bytes == results[0]));
+        assert_eq!(decode(&results[0]).dimensions(), (16, 16));
+        assert_eq!(server.metrics().summary(Stage::Decode).count, 1);
+        assert_eq!(http_server.connection_count(), 1);
+    }
+
+    #[test]
+    fn decodes_a_source_encoded_in_two_formats_once() {
+        let dir = test_dir("decodes_a_source_encoded_in_two_formats_once");
+        let server = server(&dir, &SetupOptions {cache_decoded: true, ..setup_options()});
+        let path = write_image(&dir, "image.png", &noise_image(16, 16), ImageFormat::Png);
+        let png_bytes = server.fetch(&path, 8, 8, &image_options(&["png"])).unwrap().bytes;
+        let jpeg_bytes = server.fetch(&path, 8, 8, &image_options(&["jpeg"])).unwrap().bytes;
+        assert!(png_bytes.starts_with(b"\x89PNG") && jpeg_bytes.starts_with(&[0xFF, 0xD8, 0xFF]));
+        assert_eq!(server.metrics().summary(Stage::Decode).count, 1);
+        let cache_stats = server.cache_stats();
+        assert_eq!((cache_stats.entries, cache_stats.decoded_entries), (2, 1));
+    }
+}
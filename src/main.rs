@@ -1,281 +1,2218 @@
-use std::io::{Read, Write};
-use std::collections::{HashMap, HashSet};
-use std::ffi::OsStr;
-use std::sync::{Arc, RwLock, mpsc};
+use std::io::{Read, Write, BufWriter};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak, mpsc};
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant};
 use anyhow::anyhow;
-use interprocess::os::windows::named_pipe::{PipeListener, DuplexBytePipeStream, PipeListenerOptions, PipeMode};
-use image::{ImageFormat, GenericImageView, EncodableLayout};
-use sysinfo::{System, SystemExt, RefreshKind};
 use once_cell::sync::OnceCell;
 use mimalloc::MiMalloc;
+use tracing::{trace, info, warn, error};
+use tracing_subscriber::EnvFilter;
+use picto_crab::{EncodedImage, PictoServer, ReplyFormat, SetupOptions, ImageOptions, ResizeMode, CropRect, Stage, TimingSummary};
+use picto_crab::options::parse_value;
+
+mod config;
+mod transport;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-const GETS_THREAD_COUNT: usize = 12;
+/// Messages are read from the stream in chunks of this size, unless `PICTOCRAB_READ_BUFFER` is set
 const BUFFER_SIZE: usize = 4096;
-const PIPE_NAME: &str = "img_process_server";
-const MIN_AVAILABLE_MEMORY : u64 = 2;
+const READ_BUFFER_ENV: &str = "PICTOCRAB_READ_BUFFER";
+/// Messages are claimed to have up to 4 GiB, so at most this much is reserved for them before they were read
+const MAX_PREALLOCATED_MESSAGE: usize = 16 * 1024 * 1024;
+/// Replies are collected in a buffer of this size, before they are written to the stream
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+/// Clients speaking version 2 get the format of every reply body, they ask for it with `protocol|2`.
+/// Version 3 also sends the width and height of images
+const PROTOCOL_VERSION: u32 = 3;
+/// The command was not run, because the connection sent more commands than the rate limit allows
+const STATUS_RATE_LIMITED: u8 = 2;
+/// One image of a `gets` failed to load, the replies of the other images still follow
+const STATUS_IMAGE_FAILED: u8 = 3;
+/// The setup options a later `setup` can change, like the ones set by the config at startup
+const RUNTIME_SETUP_OPTIONS: [&str; 2] = ["threads", "min_available_memory"];
+
+/// The size of the chunks messages are read in, read from the environment once
+static READ_BUFFER_SIZE: OnceCell<usize> = OnceCell::new();
+/// Tells main to shut down, and whether to clear the cache first
+static SHUTDOWN: OnceCell<mpsc::Sender<bool>> = OnceCell::new();
 
-static CACHE_DIR : OnceCell<String> = OnceCell::new();
-static THREADED_READS: OnceCell<bool> = OnceCell::new();
+/// Runs on a worker thread, it sends its result back on a channel it owns, so it can't end up with another request
+type Job = Box<dyn FnOnce(&PictoServer) + Send>;
+type ThreadChannels = Vec<(mpsc::Sender<Job>, std::thread::JoinHandle<()>)>;
 
-enum CacheType {
-    OnDisk(u32),
-    InMemory(Arc<Vec<u8>>)
+/// Shared by all connections
+#[derive(Default)]
+struct ServerState {
+    /// Created by the first `setup`
+    server: OnceCell<Arc<PictoServer>>,
+    /// The setup options of the first `setup`, after the ones of the config. Later ones can't change them
+    setup_args: OnceCell<Vec<String>>,
+    /// The threads are spawned by setup, once the thread count is known
+    thread_channels: RwLock<ThreadChannels>,
+    /// Set by the first `setup`, like the server
+    max_in_flight_bytes: OnceCell<usize>,
+    /// Set by the first `setup`, connections are not limited before it
+    rate_limit: OnceCell<Option<RateLimit>>,
+    /// The limits of the running `gets`, which `shutdown` cancels, so no thread waits for an image to be sent to a client, which stopped reading
+    in_flights: Mutex<Vec<Weak<InFlight>>>,
+    /// Read at startup, its setup options are applied before the ones of every `setup`
+    config: config::StartupConfig
 }
-type CachedImages = HashMap<String, CacheType>;
-type CachedPaths = HashSet<String>;
-type CachedImageShared = Arc<RwLock<(CachedImages, CachedPaths)>>;
-type ThreadChannels = Vec<(mpsc::Sender<(u32, u32, Vec<String>)>, mpsc::Receiver<Vec<u8>>)>;
 
+impl ServerState {
+    fn server(&self) -> anyhow::Result<&Arc<PictoServer>> {
+        self.server.get().ok_or(anyhow!("Not setup"))
+    }
+}
 
-fn send_image<S: Write>(img_bytes : Arc<Vec<u8>>, stream : &mut S) -> anyhow::Result<()> {
-    #[cfg(feature = "log")]
-    let instant = std::time::Instant::now();
-    stream.write(&(img_bytes.len() as u32).to_be_bytes())?; // Length
-    stream.write(&img_bytes)?;
-    #[cfg(feature = "log")]
-    println!("s: {}ns", instant.elapsed().as_nanos());
+/// How many commands per second a connection may send, it may send `burst` commands at once
+#[derive(Clone, Copy, Debug)]
+struct RateLimit {
+    per_second: f64,
+    burst: f64
+}
+
+/// Kept by every connection between its commands
+struct Session {
+    /// Reused to read every message
+    read_buffer: Vec<u8>,
+    /// The tokens of the rate limit, which are left, full if `None`. Every command takes one
+    tokens: Option<f64>,
+    /// When the tokens were last counted
+    tokens_counted: Instant,
+    /// Agreed on with `protocol`, clients which never sent it speak version 1
+    protocol_version: u32,
+    /// Whether the client may be on another host, like TCP clients
+    remote: bool,
+    /// Whether the client sent the TCP token with `auth`
+    authenticated: bool
+}
+
+impl Session {
+    fn new(remote : bool) -> Self {
+        Self {read_buffer: vec![0u8; read_buffer_size()], tokens: None, tokens_counted: Instant::now(), protocol_version: 1, remote, authenticated: false}
+    }
+
+    /// Authenticates the client, if `auth` was sent with the TCP token of the config
+    fn authenticate<S: ReplyStream>(&mut self, args : &[&str], stream : &mut S, state : &ServerState) -> anyhow::Result<()> {
+        let token = get_arg(args, 1, "token")?;
+        let expected = state.config.tcp_token.as_deref().ok_or(anyhow!("No TCP token is configured"))?;
+        // Compared without stopping at the first different byte, so the token can't be guessed from how long the reply takes
+        let matches = token.len() == expected.len() && token.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+        if !matches {
+            return Err(anyhow!("Invalid token"));
+        }
+        self.authenticated = true;
+        send_done(stream)
+    }
+
+    /// Remote clients may only change the server, like setting it up, reading from other directories or stopping it, once they authenticated.
+    /// Before that they may only load images, if the server was set up with a `root`, so they can't read every file the server can
+    fn check_allowed(&self, command : &str, state : &ServerState) -> anyhow::Result<()> {
+        if !self.remote || self.authenticated {return Ok(());}
+        let changes_server = matches!(command, "setup" | "shutdown" | "remove" | "clear_cache" | "drop_disk_cache");
+        if changes_server {
+            return Err(anyhow!("{} is only allowed over TCP after `auth` with the TCP token", command));
+        }
+        let reads_images = !matches!(command, "ping" | "metrics" | "cache_stats");
+        // Before setup the command fails anyway
+        if reads_images && state.server.get().is_some_and(|server| server.read_root().is_none()) {
+            return Err(anyhow!("{} is only allowed over TCP after `auth` with the TCP token, or once the server is set up with a `root`", command));
+        }
+        Ok(())
+    }
+
+    /// Replies with the highest version both the client and the server speak, which is used for the following replies
+    fn negotiate_protocol<S: ReplyStream>(&mut self, args : &[&str], stream : &mut S) -> anyhow::Result<()> {
+        let version : u32 = parse_value("protocol version", get_arg(args, 1, "protocol version")?)?;
+        if version == 0 {
+            return Err(anyhow!("Invalid protocol version : 0"));
+        }
+        let version = version.min(PROTOCOL_VERSION);
+        send_reply(STATUS_OK, version.to_string().as_bytes(), stream)?;
+        self.protocol_version = version;
+        Ok(())
+    }
+
+    /// Takes a token for the command, or returns how long to wait until there is one
+    fn take_token(&mut self, command : &str, state : &ServerState) -> Result<(), Duration> {
+        let Some(Some(limit)) = state.rate_limit.get() else {return Ok(())};
+        // Health checks have to work, no matter how many commands were sent
+        if command == "ping" {return Ok(());}
+        let now = Instant::now();
+        let refilled = now.duration_since(self.tokens_counted).as_secs_f64() * limit.per_second;
+        let tokens = self.tokens.map_or(limit.burst, |tokens| (tokens + refilled).min(limit.burst));
+        self.tokens_counted = now;
+        if tokens < 1.0 {
+            self.tokens = Some(tokens);
+            return Err(Duration::from_secs_f64((1.0 - tokens) / limit.per_second));
+        }
+        self.tokens = Some(tokens - 1.0);
+        Ok(())
+    }
+}
+
+/// Limits how many bytes of images of a `gets` are loaded, but not sent yet. The threads wait until enough of them were sent
+struct InFlight {
+    max_bytes: usize,
+    /// Whether the images are sent in the order of the paths, instead of as soon as they are loaded
+    ordered: bool,
+    state: Mutex<InFlightState>,
+    changed: Condvar
+}
+
+#[derive(Default)]
+struct InFlightState {
+    bytes: usize,
+    /// If the images are sent in order, the image sent next is let through above the limit, otherwise the images waiting to be sent after it never would be
+    next_index: usize,
+    /// Set once the request failed, so the threads don't wait for images, which are never sent
+    cancelled: bool
+}
+
+impl InFlight {
+    fn new(max_bytes: usize, ordered: bool) -> Self {
+        Self {max_bytes, ordered, state: Mutex::default(), changed: Condvar::new()}
+    }
+
+    /// Waits until the image at `index` with this many bytes fits within the limit
+    fn acquire(&self, index: usize, bytes: usize) -> anyhow::Result<()> {
+        let state = self.state.lock().expect("Cannot lock in flight images");
+        let mut state = self.changed.wait_while(state, |state| {
+            // Images sent as soon as they are loaded only have to wait for any image to be sent
+            let is_let_through = if self.ordered {index == state.next_index} else {state.bytes == 0};
+            !state.cancelled && !is_let_through && state.bytes.saturating_add(bytes) > self.max_bytes
+        }).expect("Cannot lock in flight images");
+        if state.cancelled {return Err(anyhow!("Request failed"));}
+        state.bytes += bytes;
+        Ok(())
+    }
+
+    /// Called once the next image was sent
+    fn sent(&self, bytes: usize) {
+        let mut state = self.state.lock().expect("Cannot lock in flight images");
+        state.bytes -= bytes;
+        state.next_index += 1;
+        self.changed.notify_all();
+    }
+
+    fn cancel(&self) {
+        self.state.lock().expect("Cannot lock in flight images").cancelled = true;
+        self.changed.notify_all();
+    }
+}
+
+type SharedState = Arc<ServerState>;
+
+
+/// Replies are written to it, it knows whether the client wants the format of every reply
+trait ReplyStream: Write {
+    fn protocol_version(&self) -> u32;
+}
+
+impl<W: Write> ReplyStream for BufWriter<TimedWriter<W>> {
+    fn protocol_version(&self) -> u32 {
+        self.get_ref().protocol_version
+    }
+}
+
+/// `img` is the image the body contains, its format and size are sent to clients speaking newer protocol versions
+fn send_header<S: ReplyStream>(status : u8, img : Option<&EncodedImage>, length : usize, stream : &mut S) -> anyhow::Result<()> {
+    stream.write_all(&[status])?;
+    if stream.protocol_version() >= 2 {
+        let format = img.map_or(ReplyFormat::None, |img| ReplyFormat::of_image(&img.bytes));
+        stream.write_all(&[format as u8])?;
+    }
+    if stream.protocol_version() >= 3 {
+        // Replies without an image have a size of 0x0
+        let (width, height) = img.map_or((0, 0), |img| (img.width, img.height));
+        stream.write_all(&width.to_be_bytes())?;
+        stream.write_all(&height.to_be_bytes())?;
+    }
+    stream.write_all(&(length as u32).to_be_bytes())?; // Length
     Ok(())
 }
 
-fn get_disk_cache_path(cache_id: &u32) -> anyhow::Result<String> {
-    Ok(format!("{}/{}.bmp", CACHE_DIR.get().ok_or(anyhow!("Not setup"))?, cache_id))
+fn send_reply<S: ReplyStream>(status : u8, data : &[u8], stream : &mut S) -> anyhow::Result<()> {
+    send_header(status, None, data.len(), stream)?;
+    stream.write_all(data)?;
+    Ok(())
 }
 
-fn cache_img(path : String, img_bytes : Arc<Vec<u8>>, cached_images : &CachedImageShared) -> anyhow::Result<()> {
-    #[cfg(feature = "log")]
+fn send_image<S: ReplyStream>(img : EncodedImage, stream : &mut S) -> anyhow::Result<()> {
     let instant = std::time::Instant::now();
-    let sys = System::new_with_specifics(RefreshKind::with_memory(Default::default()));
-    let available_memory = sys.available_memory();
-    let mut unlocked_cache = cached_images.write().expect("Cannot write to cache");
-
-    if (available_memory / 1000000000) < MIN_AVAILABLE_MEMORY {
-        let cache_id = unlocked_cache.0.len() as u32;
-        std::fs::write(get_disk_cache_path(&cache_id)?, img_bytes.as_bytes())?;
-        unlocked_cache.0.insert(path, CacheType::OnDisk(cache_id));
-    } else {
-        unlocked_cache.0.insert(path, CacheType::InMemory(img_bytes));
+    send_header(STATUS_OK, Some(&img), img.bytes.len(), stream)?;
+    stream.write_all(&img.bytes)?;
+    trace!(bytes = img.bytes.len() as u64, nanos = instant.elapsed().as_nanos() as u64, "Sent image");
+    Ok(())
+}
+
+/// Like `send_image`, but the body starts with the 4 byte big-endian index of the path, so the client knows which image it is
+fn send_indexed_image<S: ReplyStream>(index : usize, img : EncodedImage, stream : &mut S) -> anyhow::Result<()> {
+    let instant = std::time::Instant::now();
+    send_header(STATUS_OK, Some(&img), img.bytes.len() + 4, stream)?;
+    stream.write_all(&(index as u32).to_be_bytes())?;
+    stream.write_all(&img.bytes)?;
+    trace!(bytes = img.bytes.len() as u64, nanos = instant.elapsed().as_nanos() as u64, "Sent image");
+    Ok(())
+}
+
+/// Replies in place of an image of a `gets`, which failed to load. If `index` is set, the body starts with it, like with `send_indexed_image`
+fn send_image_failed<S: ReplyStream>(index : Option<usize>, error : &anyhow::Error, stream : &mut S) -> anyhow::Result<()> {
+    let message = format!("{:#}", error);
+    let index_length = if index.is_some() {4} else {0};
+    send_header(STATUS_IMAGE_FAILED, None, message.len() + index_length, stream)?;
+    if let Some(index) = index {
+        stream.write_all(&(index as u32).to_be_bytes())?;
     }
-    #[cfg(feature = "log")]
-    println!("ci: {}ns", instant.elapsed().as_nanos());
+    stream.write_all(message.as_bytes())?;
     Ok(())
 }
 
+/// Replies with an empty body to commands, which only have to tell that they are done
+fn send_done<S: ReplyStream>(stream : &mut S) -> anyhow::Result<()> {
+    send_reply(STATUS_OK, &[], stream)
+}
 
-fn get_from_cache<'a>(path : &str, cached_images : &CachedImageShared) -> anyhow::Result<Option<Arc<Vec<u8>>>> {
-    let unlocked_cache = cached_images.read().expect("Cannot read from cache");
-    let i = Ok(match unlocked_cache.0.get(path) {
-        Some(cache_type) => {
-            Some(match cache_type {
-                CacheType::OnDisk(cache_id) => {
-                    Arc::new(std::fs::read(get_disk_cache_path(cache_id)?)?)
-                },
-                CacheType::InMemory(img_bytes) => img_bytes.clone()
-            })
-        },
-        None => None
-    });
-    i
+fn send_error<S: ReplyStream>(error : &anyhow::Error, stream : &mut S) -> anyhow::Result<()> {
+    send_reply(STATUS_ERROR, format!("{:#}", error).as_bytes(), stream)
 }
 
+fn send_rate_limited<S: ReplyStream>(retry_in : Duration, stream : &mut S) -> anyhow::Result<()> {
+    send_reply(STATUS_RATE_LIMITED, format!("Rate limited, retry in {} ms", retry_in.as_millis() + 1).as_bytes(), stream)
+}
 
-fn get_image<S: Write>(stream : &mut S, cached_images : &CachedImageShared, path : &str, width : u32, height : u32) -> anyhow::Result<()> {
-    match get_from_cache(path, cached_images)? {
-        Some(img_bytes) => {
-            send_image(img_bytes, stream)?;
-            return Ok(());
-        }
-        None => {}
-    };
-    #[cfg(feature = "log")]
-    let instant = std::time::Instant::now();
-    let raw_img_bytes = if path.starts_with("https://") {
-        match reqwest::blocking::get(path) {
-            Ok(res) => {
-                match res.error_for_status() {
-                    Ok(response) => {
-                       response.bytes().unwrap().to_vec()
-                    }
-                    Err(err) => {
-                        return Err(anyhow!("Error getting : {}", err));
-                    }
-                }
-            }
-            Err(err) => {
-                return Err(anyhow!("Error with path {} getting : {}", path, err));
-            }
+fn get_image<S: ReplyStream>(stream : &mut S, server : &PictoServer, path : &str, width : u32, height : u32, options : &ImageOptions) -> anyhow::Result<()> {
+    send_image(server.fetch(path, width, height, options)?, stream)
+}
+
+fn gets_thread(server: Arc<PictoServer>, receiver: mpsc::Receiver<Job>) -> anyhow::Result<()> {
+    loop {
+        // The sender is dropped when shutting down
+        let Ok(job) = receiver.recv() else {return Ok(())};
+        // A panicking job drops its result sender, so the request fails instead of the thread dying
+        if std::panic::catch_unwind(AssertUnwindSafe(|| job(&server))).is_err() {
+            error!("A job panicked, the thread keeps running");
         }
-    } else {
-        if !*THREADED_READS.get().ok_or(anyhow!("Not setup"))? {
-            // Just get the write guard first, which will prevent any other threads from reading images at the same time
-            // This can improve performance, if reading off hard drives, because the seek head then doesn't have to move as much
-            let _guard = cached_images.write().expect("Could not get write lock");
-            std::fs::read(path)?
-            // guard gets dropped here
-        } else {
-            std::fs::read(path)?
+    }
+}
+
+fn spawn_gets_thread(i: usize, server: &Arc<PictoServer>) -> (mpsc::Sender<Job>, std::thread::JoinHandle<()>) {
+    let thread_server = server.clone();
+    let (to_thread_send, in_thread_recv) = mpsc::channel();
+    let handle = std::thread::spawn(move || {
+        if let Err(e) = gets_thread(thread_server, in_thread_recv) {
+            error!("Thread {} exited with error: {}", i, e);
         }
-    };
-    #[cfg(feature = "log")]
-    println!("r: {}ns", instant.elapsed().as_nanos());
-    #[cfg(feature = "log")]
-    let instant = std::time::Instant::now();
+    });
+    (to_thread_send, handle)
+}
+
+fn spawn_gets_threads(thread_count: usize, server: &Arc<PictoServer>) -> ThreadChannels {
+    (0..thread_count).map(|i| spawn_gets_thread(i, server)).collect()
+}
 
-    let mut img = image::load_from_memory(&raw_img_bytes)?;
-    if img.width() != width || img.height() != height {
-        img = img.thumbnail_exact(width, height);
-    }
-    let mut bmp_img_bytes = Vec::new();
-    img.write_to(&mut bmp_img_bytes, ImageFormat::Bmp)?;
-    #[cfg(feature = "log")]
-    println!("d: {}ns", instant.elapsed().as_nanos());
-    let bmp_img_bytes_rc = Arc::new(bmp_img_bytes);
-    cache_img(path.to_string(), bmp_img_bytes_rc.clone(), cached_images)?;
-    send_image(bmp_img_bytes_rc, stream)?;
+/// Replaces the threads, which stopped and gave back their jobs, and sends the jobs to the new ones
+fn respawn_threads(state: &ServerState, dead_jobs: Vec<(usize, Job)>) -> anyhow::Result<()> {
+    let server = state.server()?;
+    let mut thread_channels = state.thread_channels.write().expect("Cannot write thread channels");
+    for (i, job) in dead_jobs {
+        // Shutting down empties the channels, while they weren't locked
+        if i >= thread_channels.len() {return Err(anyhow!("Thread {} stopped", i));}
+        warn!("Thread {} stopped, starting it again", i);
+        thread_channels[i] = spawn_gets_thread(i, server);
+        thread_channels[i].0.send(job).map_err(|_| anyhow!("Thread {} stopped", i))?;
+    }
     Ok(())
 }
 
-fn gets_thread(cached_images: CachedImageShared, receiver: mpsc::Receiver<(u32, u32, Vec<String>)>, sender: mpsc::Sender<Vec<u8>>) -> anyhow::Result<()> {
-    loop {
-        let (width, height, paths) = receiver.recv()?;
-        let mut cursor = Vec::<u8>::new();
-        for path in &paths {
-            get_image(&mut cursor, &cached_images, path, width, height)?;
+/// The thread a path is loaded on, if that thread doesn't have too many paths of the batch already.
+/// It only depends on the path, so requests of the same image are loaded by the same thread, which reads the same files and keeps its connections to the same hosts
+fn thread_index(path: &str, thread_count: usize) -> usize {
+    let mut hasher = fnv::FnvHasher::default();
+    path.hash(&mut hasher);
+    (hasher.finish() % thread_count as u64) as usize
+}
+
+/// Splits the paths among the threads by `thread_index`, so repeated requests of a path are loaded by the same thread.
+/// Returns the indices of the paths and the paths of every thread, which got any
+fn split_among_threads<'a>(paths: &[&'a str], thread_count: usize) -> Vec<(usize, Vec<usize>, Vec<&'a str>)> {
+    // A thread doesn't get more than twice its share, so a batch, whose paths hash to the same threads, is still loaded at the same time
+    let max_paths = paths.len().div_ceil(thread_count) * 2;
+    let mut thread_parts = vec![(Vec::new(), Vec::new()); thread_count];
+    for (index, path) in paths.iter().enumerate() {
+        let mut thread = thread_index(path, thread_count);
+        while thread_parts[thread].0.len() >= max_paths {
+            thread = (thread + 1) % thread_count;
         }
-        sender.send(cursor)?;
+        thread_parts[thread].0.push(index);
+        thread_parts[thread].1.push(*path);
     }
+    thread_parts.into_iter()
+        .enumerate()
+        .filter(|(_, (indices, _))| !indices.is_empty())
+        .map(|(thread, (indices, paths))| (thread, indices, paths))
+        .collect()
 }
 
-fn gets_images(stream: &mut DuplexBytePipeStream, cached_images: &CachedImageShared, thread_channels: &ThreadChannels, width: u32, height: u32, paths: &[&str]) -> anyhow::Result<()> {
-    let unlocked_cache = cached_images.read().expect("Cannot read from cache");
-    let paths_key = paths.join("");
-    #[cfg(feature = "log")]
-    let instant = std::time::Instant::now();
-    let all_cached = unlocked_cache.1.contains(&paths_key);
-    #[cfg(feature = "log")]
-    println!("uc: {}ns, e: {}", instant.elapsed().as_nanos(), unlocked_cache.1.len());
-    std::mem::drop(unlocked_cache);
-    if all_cached {
-        for path in paths {
-            get_image(stream, cached_images, path, width, height)?;
+/// Splits the paths among the threads and runs `job` on every part, together with the indices of its paths in `paths`. Returns the number of parts
+fn spawn_on_threads(state: &ServerState, paths: &[&str], job: impl Fn(&PictoServer, Vec<usize>, Vec<&str>) + Clone + Send + 'static) -> anyhow::Result<usize> {
+    let thread_channels = state.thread_channels.read().expect("Cannot read thread channels");
+    // Setup might still be spawning the threads
+    if thread_channels.is_empty() {return Err(anyhow!("Not setup"));}
+    let thread_parts = split_among_threads(paths, thread_channels.len());
+    // The threads only stop, if something went very wrong, their jobs are sent again once they were replaced
+    let mut dead_jobs = Vec::new();
+    for (i, indices, thread_paths) in &thread_parts {
+        let indices = indices.clone();
+        let thread_paths : Vec<_> = thread_paths.iter().map(|s| s.to_string()).collect();
+        let job = job.clone();
+        let sent = thread_channels[*i].0.send(Box::new(move |server: &PictoServer| {
+            job(server, indices, thread_paths.iter().map(String::as_str).collect());
+        }));
+        if let Err(mpsc::SendError(job)) = sent {
+            dead_jobs.push((*i, job));
         }
-        return Ok(());
     }
+    drop(thread_channels);
+    if !dead_jobs.is_empty() {
+        respawn_threads(state, dead_jobs)?;
+    }
+    Ok(thread_parts.len())
+}
 
-    let mut thread_chunks : Vec<_> = paths.chunks(paths.len() / GETS_THREAD_COUNT).map(|v| Vec::from(v)).collect();
-    while thread_chunks.len() > GETS_THREAD_COUNT {
-        let mut remainder = thread_chunks.pop().unwrap();
-        thread_chunks.last_mut().unwrap().append(&mut remainder);
+/// Like `spawn_on_threads`, but waits for the results of `job` and returns them in the order of the first path of every part
+fn run_on_threads<T: Send + 'static>(state: &ServerState, paths: &[&str], job: impl Fn(&PictoServer, Vec<&str>) -> T + Clone + Send + 'static) -> anyhow::Result<Vec<T>> {
+    let (sender, receiver) = mpsc::channel();
+    let parts = spawn_on_threads(state, paths, move |server, indices, paths| {
+        // The request might have failed in another thread already and stopped waiting
+        let _ = sender.send((indices[0], job(server, paths)));
+    })?;
+    // Ends once every part is done, because the jobs own the senders
+    let mut results : Vec<_> = receiver.iter().collect();
+    if results.len() < parts {return Err(anyhow!("Thread stopped before finishing"));}
+    results.sort_unstable_by_key(|(start, _)| *start);
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
+
+/// Sends the images of a `gets` in the order of the paths, while the threads are still loading the later ones. Returns how many failed to load
+fn send_in_order<S: ReplyStream>(stream: &mut S, receiver: mpsc::Receiver<(usize, anyhow::Result<EncodedImage>)>, in_flight: &InFlight, count: usize) -> anyhow::Result<usize> {
+    let mut loaded = HashMap::new();
+    let mut failed = 0;
+    for next_index in 0..count {
+        let result = loop {
+            if let Some(result) = loaded.remove(&next_index) {break result;}
+            let (index, result) = receiver.recv().map_err(|_| anyhow!("Thread stopped before loading every image"))?;
+            loaded.insert(index, result);
+        };
+        match result {
+            Ok(img) => {
+                let bytes = img.bytes.len();
+                send_image(img, stream)?;
+                in_flight.sent(bytes);
+            },
+            Err(e) => {
+                send_image_failed(None, &e, stream)?;
+                in_flight.sent(0);
+                failed += 1;
+            }
+        }
     }
-    for (i, thread_paths) in thread_chunks.iter().enumerate() {
-        let thread_paths : Vec<_> = thread_paths.iter().map(|s| s.to_string()).collect();
-        thread_channels[i].0.send((width, height, thread_paths))?;
+    Ok(failed)
+}
+
+/// Sends the images of a `gets_unordered` with their index, as soon as any of the threads loaded them. Returns how many failed to load
+fn send_as_loaded<S: ReplyStream>(stream: &mut S, receiver: mpsc::Receiver<(usize, anyhow::Result<EncodedImage>)>, in_flight: &InFlight, count: usize) -> anyhow::Result<usize> {
+    let mut failed = 0;
+    for _ in 0..count {
+        let (index, result) = receiver.recv().map_err(|_| anyhow!("Thread stopped before loading every image"))?;
+        let bytes = match result {
+            Ok(img) => {
+                let bytes = img.bytes.len();
+                send_indexed_image(index, img, stream)?;
+                bytes
+            },
+            Err(e) => {
+                send_image_failed(Some(index), &e, stream)?;
+                failed += 1;
+                0
+            }
+        };
+        // Otherwise small images would wait in the buffer for the slower ones
+        stream.flush()?;
+        in_flight.sent(bytes);
+    }
+    Ok(failed)
+}
+
+/// Sends the images at `paths`, in their order or if not `ordered` with their index as soon as they are loaded.
+/// Images which fail to load are replied to with their error, without stopping the others
+fn gets_images<S: ReplyStream>(stream: &mut S, state: &ServerState, width: u32, height: u32, options: &ImageOptions, paths: &[&str], ordered: bool) -> anyhow::Result<()> {
+    let server = state.server()?;
+    if server.is_batch_cached(width, height, options, paths) {
+        for (index, path) in paths.iter().enumerate() {
+            let index = if ordered {None} else {Some(index)};
+            match server.fetch(path, width, height, options) {
+                Ok(img) => match index {
+                    Some(index) => send_indexed_image(index, img, stream)?,
+                    None => send_image(img, stream)?
+                },
+                // The image might have changed since it was cached
+                Err(e) => send_image_failed(index, &e, stream)?
+            }
+        }
+        return Ok(());
     }
 
-    for i in 0..thread_chunks.len() {
-        let (_, receiver) = &thread_channels[i];
-        let thread_written_data : Vec<u8> = receiver.recv()?;
-        stream.write(&thread_written_data)?;
+    let in_flight = Arc::new(InFlight::new(state.max_in_flight_bytes.get().copied().unwrap_or(usize::MAX), ordered));
+    {
+        let mut in_flights = state.in_flights.lock().expect("Cannot lock in flight images");
+        in_flights.retain(|in_flight| in_flight.strong_count() > 0);
+        in_flights.push(Arc::downgrade(&in_flight));
+    }
+    let job_in_flight = in_flight.clone();
+    let job_options = options.clone();
+    let (sender, receiver) = mpsc::channel();
+    spawn_on_threads(state, paths, move |server, indices, paths| {
+        // How many of the paths of this thread were loaded
+        let mut loaded = 0;
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| server.fetch_batch_results(&paths, width, height, &job_options, |result| {
+            let index = indices[loaded];
+            // Failed images have no bytes, so they never wait
+            if let Ok(img) = &result {
+                job_in_flight.acquire(index, img.bytes.len())?;
+            }
+            // The request might have failed in another thread already and stopped receiving
+            let _ = sender.send((index, result));
+            loaded += 1;
+            Ok(())
+        })));
+        if let (Err(_), Some(panicked_path)) = (result, paths.get(loaded)) {
+            // Otherwise the request would wait forever for the rest of the images of this thread
+            let _ = sender.send((indices[loaded], Err(anyhow!("Loading {} panicked", panicked_path))));
+            for index in &indices[loaded + 1..] {
+                let _ = sender.send((*index, Err(anyhow!("Not loaded, because loading {} panicked", panicked_path))));
+            }
+        }
+    })?;
+    let sent = if ordered {
+        send_in_order(stream, receiver, &in_flight, paths.len())
+    } else {
+        send_as_loaded(stream, receiver, &in_flight, paths.len())
+    };
+    if sent.is_err() {
+        // Otherwise the threads would wait for their images to be sent forever
+        in_flight.cancel();
+    }
+    if sent? == 0 {
+        server.set_batch_cached(width, height, options, paths);
     }
-    let mut unlocked_cache = cached_images.write().expect("Cannot read from cache");
-    unlocked_cache.1.insert(paths_key);
     Ok(())
 }
 
+/// Caches the images on the threads, without sending them, and replies with how many of them could or couldn't be loaded
+fn preload_images<S: ReplyStream>(stream: &mut S, state: &ServerState, width: u32, height: u32, options: &ImageOptions, paths: &[&str]) -> anyhow::Result<()> {
+    let server = state.server()?;
+    let job_options = options.clone();
+    let succeeded : usize = run_on_threads(state, paths, move |server, paths| server.preload_batch(&paths, width, height, &job_options))?
+        .into_iter()
+        .sum();
+    send_preload_summary(stream, server, width, height, options, paths, succeeded)
+}
 
-fn setup(disk_cache_dir: &str, working_dir: &str, threaded_reads: bool) -> anyhow::Result<()> {
+/// Like `preload_images`, but also replies with the progress, every time one of the images was loaded or failed to
+fn preload_images_with_progress<S: ReplyStream>(stream: &mut S, state: &ServerState, width: u32, height: u32, options: &ImageOptions, paths: &[&str]) -> anyhow::Result<()> {
+    let server = state.server()?;
+    let job_options = options.clone();
+    let (sender, receiver) = mpsc::channel();
+    spawn_on_threads(state, paths, move |server, _, paths| {
+        server.preload_batch_each(&paths, width, height, &job_options, |path, loaded| {
+            // The request might have failed already and stopped receiving
+            let _ = sender.send((path.to_string(), loaded));
+        });
+    })?;
+    let mut succeeded = 0;
+    for processed in 1..=paths.len() {
+        let (path, loaded) = receiver.recv().map_err(|_| anyhow!("Thread stopped before loading every image"))?;
+        if loaded {succeeded += 1;}
+        // Debug formatting quotes and escapes the path like a JSON string
+        let progress = format!("{{\"processed\":{},\"total\":{},\"path\":{:?}}}", processed, paths.len(), path);
+        send_reply(STATUS_OK, progress.as_bytes(), stream)?;
+        // Otherwise the progress would only arrive together with the summary
+        stream.flush()?;
+    }
+    send_preload_summary(stream, server, width, height, options, paths, succeeded)
+}
+
+fn send_preload_summary<S: ReplyStream>(stream: &mut S, server: &PictoServer, width: u32, height: u32, options: &ImageOptions, paths: &[&str], succeeded: usize) -> anyhow::Result<()> {
+    let failed = paths.len() - succeeded;
+    if failed == 0 {
+        // A gets of the same paths now only has to send them
+        server.set_batch_cached(width, height, options, paths);
+    }
+    let summary = format!("{{\"succeeded\":{},\"failed\":{}}}", succeeded, failed);
+    send_reply(STATUS_OK, summary.as_bytes(), stream)
+}
+
+
+fn setup(disk_cache_dir: &str, working_dir: &str, threaded_reads: bool, args: &[&str], state: &ServerState) -> anyhow::Result<()> {
+    let option_args = state.config.setup_option_args(args);
+    let options = SetupOptions::parse(&option_args)?;
+    if state.server.get().is_some() {
+        return setup_again(disk_cache_dir, working_dir, threaded_reads, args, state);
+    }
     std::env::set_current_dir(working_dir)?;
-    if CACHE_DIR.get().is_some() {return Ok(());}
-    CACHE_DIR.set(disk_cache_dir.to_string()).expect("Can only setup once!");
-    THREADED_READS.set(threaded_reads).unwrap();
+    let server = Arc::new(PictoServer::new(disk_cache_dir, threaded_reads, &options)?);
+    // Another client might have set up at the same time
+    if state.server.set(server.clone()).is_err() {
+        return setup_again(disk_cache_dir, working_dir, threaded_reads, args, state);
+    }
+    let _ = state.setup_args.set(option_args.into_iter().map(str::to_string).collect());
+    let _ = state.max_in_flight_bytes.set(options.max_in_flight_bytes);
+    let _ = state.rate_limit.set(options.rate_limit.map(|per_second| RateLimit {
+        per_second,
+        burst: options.rate_burst.map_or(per_second.ceil(), f64::from)
+    }));
+    *state.thread_channels.write().expect("Cannot write thread channels") = spawn_gets_threads(options.thread_count, &server);
     Ok(())
 }
 
-fn clear_cache(cached_images : &CachedImageShared) -> anyhow::Result<()> {
-    let mut unlocked_cache = cached_images.write().expect("Cannot write to cache");
-    let cached_paths = &mut unlocked_cache.1;
-    cached_paths.clear();
-    let cached_images = &mut unlocked_cache.0;
-    for (_, cache_type) in cached_images.drain() {
-        match cache_type {
-            CacheType::OnDisk(cache_id) => {
-                std::fs::remove_file(get_disk_cache_path(&cache_id)?)?
-            },
-            CacheType::InMemory(_) => {}
+/// Only changes what can be changed after the first `setup`, fails without changing anything if `args` would change other setup options
+fn setup_again(disk_cache_dir: &str, working_dir: &str, threaded_reads: bool, args: &[&str], state: &ServerState) -> anyhow::Result<()> {
+    let option_key = |arg : &str| arg.split_once('=').map_or(arg, |(key, _)| key).to_string();
+    // Options given more than once only take their last value
+    let mut last_args : Vec<&str> = Vec::new();
+    for arg in args {
+        last_args.retain(|last_arg| option_key(last_arg) != option_key(arg));
+        last_args.push(arg);
+    }
+    let (runtime_args, last_args) : (Vec<&str>, Vec<&str>) = last_args.into_iter().partition(|arg| RUNTIME_SETUP_OPTIONS.contains(&option_key(arg).as_str()));
+    // Not set yet, if the first setup is still running
+    if let Some(first_args) = state.setup_args.get() {
+        let first_args : Vec<&str> = first_args.iter().map(String::as_str).collect();
+        let first_options = SetupOptions::parse(&first_args)?;
+        let mut changed : Vec<String> = Vec::new();
+        for arg in last_args {
+            let options = SetupOptions::parse(&[first_args.as_slice(), &[arg]].concat())?;
+            if options != first_options {
+                changed.push(option_key(arg));
+            }
+        }
+        if !changed.is_empty() {
+            return Err(anyhow!("Cannot change the setup options {} after the first setup, the server has to be restarted to change them", changed.join(", ")));
+        }
+    }
+    // Only the options the client gave are changed, the others keep what an earlier setup changed them to
+    let runtime_options = SetupOptions::parse(&state.config.setup_option_args(&runtime_args))?;
+    std::env::set_current_dir(working_dir)?;
+    let server = state.server()?;
+    server.set_threaded_reads(threaded_reads);
+    server.set_cache_dir(disk_cache_dir)?;
+    if runtime_args.iter().any(|arg| option_key(arg) == "min_available_memory") {
+        server.set_min_available_memory(runtime_options.min_available_memory);
+    }
+    if runtime_args.iter().any(|arg| option_key(arg) == "threads") {
+        let mut thread_channels = state.thread_channels.write().expect("Cannot write thread channels");
+        // The old threads stop once they finished the jobs they were sent already
+        if thread_channels.len() != runtime_options.thread_count {
+            *thread_channels = spawn_gets_threads(runtime_options.thread_count, server);
         }
     }
     Ok(())
 }
 
-fn process_command(args : Vec<&str>, stream : &mut DuplexBytePipeStream, cached_images : &CachedImageShared, thread_channels: &ThreadChannels) -> anyhow::Result<()> {
-    match args[0] {
-        "clear_cache" => clear_cache(cached_images)?,
-        "setup" => setup(args[1], args[2], args[3] == "true")?,
-        "gets" => gets_images(stream, cached_images, thread_channels, args[1].parse::<u32>().unwrap(), args[2].parse::<u32>().unwrap(), &args[3..])?,
-        "get" => {get_image(stream, cached_images, args[1],  args[2].parse::<u32>().unwrap(), args[3].parse::<u32>().unwrap())?},
-        _ => {println!("No such command : {}", args[0])}
+/// Stops the threads once they finished their current job and either clears the cache or keeps the images cached on disk for the next run
+fn shutdown(state : &ServerState, clear: bool) -> anyhow::Result<()> {
+    let threads = std::mem::take(&mut *state.thread_channels.write().expect("Cannot write thread channels"));
+    let (senders, handles) : (Vec<_>, Vec<_>) = threads.into_iter().unzip();
+    std::mem::drop(senders);
+    // Otherwise threads waiting for their images to be sent would never finish their job
+    for in_flight in state.in_flights.lock().expect("Cannot lock in flight images").drain(..) {
+        if let Some(in_flight) = in_flight.upgrade() {
+            in_flight.cancel();
+        }
+    }
+    for handle in handles {
+        let _ = handle.join();
+    }
+    let Some(server) = state.server.get() else {return Ok(())};
+    if clear {
+        server.clear_cache()
+    } else {
+        server.write_index()
+    }
+}
+
+fn clear_cache<S: ReplyStream>(stream : &mut S, state : &ServerState) -> anyhow::Result<()> {
+    // Nothing is cached before setup
+    if let Some(server) = state.server.get() {
+        server.clear_cache()?;
+    }
+    send_done(stream)
+}
+
+fn cache_stats<S: ReplyStream>(stream : &mut S, state : &ServerState) -> anyhow::Result<()> {
+    let cache_stats = state.server.get().map(|server| server.cache_stats()).unwrap_or_default();
+    let stats = format!(
+        "{{\"entries\":{},\"memory_bytes\":{},\"disk_entries\":{},\"decoded_entries\":{},\"hits\":{},\"misses\":{}}}",
+        cache_stats.entries,
+        cache_stats.memory_bytes,
+        cache_stats.disk_entries,
+        cache_stats.decoded_entries,
+        cache_stats.hits,
+        cache_stats.misses
+    );
+    send_reply(STATUS_OK, stats.as_bytes(), stream)
+}
+
+fn remove_cached<S: ReplyStream>(stream : &mut S, state : &ServerState, path : &str, size : Option<(u32, u32)>) -> anyhow::Result<()> {
+    let removed = match state.server.get() {
+        Some(server) => server.remove(path, size)?,
+        None => 0
+    };
+    send_reply(STATUS_OK, removed.to_string().as_bytes(), stream)
+}
+
+fn drop_disk_cache<S: ReplyStream>(stream : &mut S, state : &ServerState) -> anyhow::Result<()> {
+    let removed = match state.server.get() {
+        Some(server) => server.drop_disk_cache()?,
+        None => 0
+    };
+    send_reply(STATUS_OK, removed.to_string().as_bytes(), stream)
+}
+
+/// Replies with the count and percentiles in nanoseconds of every stage, all zero before setup
+fn metrics<S: ReplyStream>(stream : &mut S, state : &ServerState) -> anyhow::Result<()> {
+    let stages : Vec<_> = Stage::ALL.iter()
+        .map(|stage| {
+            let summary = state.server.get().map(|server| server.metrics().summary(*stage)).unwrap_or_default();
+            let TimingSummary {count, p50, p90, p99, max} = summary;
+            format!("\"{}\":{{\"count\":{},\"p50\":{},\"p90\":{},\"p99\":{},\"max\":{}}}", stage.name(), count, p50, p90, p99, max)
+        })
+        .collect();
+    send_reply(STATUS_OK, format!("{{{}}}", stages.join(",")).as_bytes(), stream)
+}
+
+/// Replies with the server version and whether setup was run, without touching the cache
+fn ping<S: ReplyStream>(stream : &mut S, state : &ServerState) -> anyhow::Result<()> {
+    let status = format!("{{\"version\":\"{}\",\"protocol\":{},\"setup\":{}}}", env!("CARGO_PKG_VERSION"), PROTOCOL_VERSION, state.server.get().is_some());
+    send_reply(STATUS_OK, status.as_bytes(), stream)
+}
+
+fn get_arg<'a>(args : &[&'a str], index : usize, name : &str) -> anyhow::Result<&'a str> {
+    args.get(index).copied().ok_or(anyhow!("Missing argument : {}", name))
+}
+
+fn parse_dimension(args : &[&str], index : usize, name : &str) -> anyhow::Result<u32> {
+    let value = get_arg(args, index, name)?;
+    match parse_value(name, value)? {
+        0 => Err(anyhow!("Invalid {} : {}", name, value)),
+        dimension => Ok(dimension)
+    }
+}
+
+/// Blurhashes have 1 to 9 components per axis
+fn parse_components(args : &[&str], index : usize, name : &str) -> anyhow::Result<u32> {
+    let value = get_arg(args, index, name)?;
+    match parse_value(name, value)? {
+        components @ 1..=9 => Ok(components),
+        _ => Err(anyhow!("Invalid {} : {}, has to be between 1 and 9", name, value))
+    }
+}
+
+/// The end of the options of commands with paths after them, so paths which look like options, like `png`, are still paths
+const PATHS_SEPARATOR: &str = "--";
+
+/// Parses the options before the `--`, which have to be followed by the paths
+fn parse_options_and_paths<'a>(args : &'a [&'a str]) -> anyhow::Result<(ImageOptions, &'a [&'a str])> {
+    let separator = args.iter().position(|arg| *arg == PATHS_SEPARATOR)
+        .ok_or(anyhow!("Missing argument : {}, which has to be between the options and the paths", PATHS_SEPARATOR))?;
+    let (options, options_count) = ImageOptions::parse(&args[..separator])?;
+    if let Some(arg) = args[..separator].get(options_count) {
+        return Err(anyhow!("Unknown option : {}", arg));
+    }
+    Ok((options, &args[separator + 1..]))
+}
+
+fn process_command<S: ReplyStream>(args : Vec<&str>, payload : &[u8], stream : &mut S, state : &ServerState) -> anyhow::Result<()> {
+    let command = args.first().copied().filter(|command| !command.is_empty()).ok_or(anyhow!("Empty command"))?;
+    match command {
+        "clear_cache" => clear_cache(stream, state)?,
+        "drop_disk_cache" => drop_disk_cache(stream, state)?,
+        "cache_stats" => cache_stats(stream, state)?,
+        "ping" => ping(stream, state)?,
+        "metrics" => metrics(stream, state)?,
+        "remove" => {
+            let path = get_arg(&args, 1, "path")?;
+            let size = match args.len() {
+                2 => None,
+                4 => Some((parse_dimension(&args, 2, "width")?, parse_dimension(&args, 3, "height")?)),
+                _ => return Err(anyhow!("Expected a path and optionally a width and height"))
+            };
+            remove_cached(stream, state, path, size)?
+        },
+        "shutdown" => {
+            let clear = match args.get(1) {
+                None => false,
+                Some(&"clear_cache") => true,
+                Some(arg) => return Err(anyhow!("Unknown option : {}", arg))
+            };
+            let shutdown = SHUTDOWN.get().ok_or(anyhow!("Cannot shut down"))?;
+            // The server might stop before the reply would be flushed otherwise
+            send_done(stream)?;
+            stream.flush()?;
+            shutdown.send(clear)?;
+        },
+        "setup" => {
+            let disk_cache_dir = get_arg(&args, 1, "cache dir")?;
+            let working_dir = get_arg(&args, 2, "working dir")?;
+            let threaded_reads = parse_value("threaded reads", get_arg(&args, 3, "threaded reads")?)?;
+            setup(disk_cache_dir, working_dir, threaded_reads, &args[4..], state)?;
+            send_done(stream)?
+        },
+        "gets" => {
+            let width = parse_dimension(&args, 1, "width")?;
+            let height = parse_dimension(&args, 2, "height")?;
+            let (options, paths) = parse_options_and_paths(&args[3..])?;
+            gets_images(stream, state, width, height, &options, paths, true)?
+        },
+        "gets_unordered" => {
+            let width = parse_dimension(&args, 1, "width")?;
+            let height = parse_dimension(&args, 2, "height")?;
+            let (options, paths) = parse_options_and_paths(&args[3..])?;
+            gets_images(stream, state, width, height, &options, paths, false)?
+        },
+        "preload" => {
+            let width = parse_dimension(&args, 1, "width")?;
+            let height = parse_dimension(&args, 2, "height")?;
+            let (options, paths) = parse_options_and_paths(&args[3..])?;
+            preload_images(stream, state, width, height, &options, paths)?
+        },
+        "preload_progress" => {
+            let width = parse_dimension(&args, 1, "width")?;
+            let height = parse_dimension(&args, 2, "height")?;
+            let (options, paths) = parse_options_and_paths(&args[3..])?;
+            preload_images_with_progress(stream, state, width, height, &options, paths)?
+        },
+        "get" => {
+            let path = get_arg(&args, 1, "path")?;
+            let width = parse_dimension(&args, 2, "width")?;
+            let height = parse_dimension(&args, 3, "height")?;
+            let (options, options_count) = ImageOptions::parse(&args[4..])?;
+            if let Some(arg) = args[4..].get(options_count) {
+                return Err(anyhow!("Unknown option : {}", arg));
+            }
+            get_image(stream, state.server()?, path, width, height, &options)?
+        },
+        "process" => {
+            let width = parse_dimension(&args, 1, "width")?;
+            let height = parse_dimension(&args, 2, "height")?;
+            let (options, options_count) = ImageOptions::parse(&args[3..])?;
+            if let Some(arg) = args[3..].get(options_count) {
+                return Err(anyhow!("Unknown option : {}", arg));
+            }
+            // The image follows the arguments, as a big-endian length and the bytes
+            let mut remaining = payload;
+            let img_length = take_u32(&mut remaining).map_err(|_| anyhow!("Missing argument : image"))? as usize;
+            let raw_img_bytes = take_bytes(&mut remaining, img_length)?;
+            send_image(state.server()?.process_bytes(raw_img_bytes, width, height, &options)?, stream)?
+        },
+        "is_cached" => {
+            let path = get_arg(&args, 1, "path")?;
+            let width = parse_dimension(&args, 2, "width")?;
+            let height = parse_dimension(&args, 3, "height")?;
+            let (options, options_count) = ImageOptions::parse(&args[4..])?;
+            if let Some(arg) = args[4..].get(options_count) {
+                return Err(anyhow!("Unknown option : {}", arg));
+            }
+            let is_cached = state.server()?.is_cached(path, width, height, &options);
+            send_reply(STATUS_OK, &[is_cached as u8], stream)?
+        },
+        "crop" => {
+            let path = get_arg(&args, 1, "path")?;
+            let crop = CropRect {
+                x: parse_value("x", get_arg(&args, 2, "x")?)?,
+                y: parse_value("y", get_arg(&args, 3, "y")?)?,
+                width: parse_dimension(&args, 4, "width")?,
+                height: parse_dimension(&args, 5, "height")?
+            };
+            let (mut options, options_count) = ImageOptions::parse(&args[6..])?;
+            if let Some(arg) = args[6..].get(options_count) {
+                return Err(anyhow!("Unknown option : {}", arg));
+            }
+            options.crop = Some(crop);
+            get_image(stream, state.server()?, path, crop.width, crop.height, &options)?
+        },
+        "blurhash" => {
+            let path = get_arg(&args, 1, "path")?;
+            let components_x = parse_components(&args, 2, "components x")?;
+            let components_y = parse_components(&args, 3, "components y")?;
+            let blurhash = state.server()?.blurhash(path, components_x, components_y)?;
+            send_reply(STATUS_OK, blurhash.as_bytes(), stream)?
+        },
+        "dominant_color" => {
+            let path = get_arg(&args, 1, "path")?;
+            let count = match args.get(2) {
+                Some(count) => parse_value("color count", count)?,
+                None => 1
+            };
+            let colors = state.server()?.dominant_colors(path, count)?;
+            send_reply(STATUS_OK, colors.as_bytes(), stream)?
+        },
+        "dimensions" => {
+            let path = get_arg(&args, 1, "path")?;
+            let dimensions = state.server()?.dimensions(path)?;
+            send_reply(STATUS_OK, dimensions.as_bytes(), stream)?
+        },
+        "phash" => {
+            let path = get_arg(&args, 1, "path")?;
+            let phash = state.server()?.phash(path)?;
+            send_reply(STATUS_OK, phash.as_bytes(), stream)?
+        },
+        "montage" => {
+            let columns = parse_dimension(&args, 1, "columns")?;
+            let cell_width = parse_dimension(&args, 2, "cell width")?;
+            let cell_height = parse_dimension(&args, 3, "cell height")?;
+            let (options, paths) = parse_options_and_paths(&args[4..])?;
+            if paths.is_empty() {
+                return Err(anyhow!("Missing argument : paths"));
+            }
+            let montage = state.server()?.montage(paths, columns, cell_width, cell_height, &options)?;
+            send_image(montage, stream)?
+        },
+        "ico" => {
+            let path = get_arg(&args, 1, "path")?;
+            let sizes = get_arg(&args, 2, "sizes")?.split(',')
+                .map(|size| parse_value("icon size", size.trim()))
+                .collect::<anyhow::Result<Vec<u32>>>()?;
+            let (options, options_count) = ImageOptions::parse(&args[3..])?;
+            if let Some(arg) = args[3..].get(options_count) {
+                return Err(anyhow!("Unknown option : {}", arg));
+            }
+            send_image(state.server()?.ico(path, &sizes, &options)?, stream)?
+        },
+        "get_fit" => {
+            let path = get_arg(&args, 1, "path")?;
+            let max_side = parse_dimension(&args, 2, "max side")?;
+            let (mut options, options_count) = ImageOptions::parse(&args[3..])?;
+            if let Some(arg) = args[3..].get(options_count) {
+                return Err(anyhow!("Unknown option : {}", arg));
+            }
+            // Fitting into a square keeps the aspect ratio and makes the longer side max_side
+            options.resize_mode = ResizeMode::Fit;
+            get_image(stream, state.server()?, path, max_side, max_side, &options)?
+        },
+        _ => return Err(anyhow!("No such command : {}", command))
     }
     Ok(())
 }
 
 
-fn read_command(stream : &mut DuplexBytePipeStream, cached_images : &CachedImageShared, thread_channels: &ThreadChannels) -> anyhow::Result<()> {
+fn take_bytes<'a>(data : &mut &'a [u8], count : usize) -> anyhow::Result<&'a [u8]> {
+    if data.len() < count {return Err(anyhow!("Command is truncated"));}
+    let (taken, rest) = data.split_at(count);
+    *data = rest;
+    Ok(taken)
+}
+
+fn take_u32(data : &mut &[u8]) -> anyhow::Result<u32> {
+    Ok(u32::from_be_bytes(take_bytes(data, 4)?.try_into()?))
+}
+
+/// Splits a command into its arguments and the bytes after them, which only some commands use.
+/// Commands starting with a zero byte are a big-endian argument count, followed by every argument as a big-endian length and UTF-8 bytes,
+/// so arguments can contain any character. Other commands are UTF-8 with the arguments separated by `|`, and have no bytes after them.
+fn parse_command(data : &[u8]) -> anyhow::Result<(Vec<String>, &[u8])> {
+    if data.first() != Some(&0) {
+        return Ok((String::from_utf8_lossy(data).split('|').map(str::to_string).collect(), &[]));
+    }
+    let mut remaining = data;
+    let arg_count = take_u32(&mut remaining)?;
+    let args = (0..arg_count).map(|_| {
+        let arg_length = take_u32(&mut remaining)? as usize;
+        Ok(String::from_utf8(take_bytes(&mut remaining, arg_length)?.to_vec())?)
+    }).collect::<anyhow::Result<_>>()?;
+    Ok((args, remaining))
+}
+
+
+/// Measures how long writing to the stream takes, which is how long sending took
+struct TimedWriter<W: Write> {
+    /// The protocol version of the connection, which decides how replies are framed
+    protocol_version: u32,
+    inner: W,
+    written_in: Duration
+}
+
+impl<W: Write> Write for TimedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let instant = Instant::now();
+        let result = self.inner.write(buf);
+        self.written_in += instant.elapsed();
+        result
+    }
+
+    /// Not measured, so nothing counts as sent if nothing was written
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads and runs a single command, returns false once the client disconnected
+fn read_command<S: Read + Write>(stream : &mut S, session : &mut Session, state : &ServerState) -> anyhow::Result<bool> {
     let mut read_size_buffer = [0u8; 4];
-    if stream.read(&mut read_size_buffer)? == 0 {return Ok(())};
+    match stream.read_exact(&mut read_size_buffer) {
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+        result => result?
+    }
     let msg_size = u32::from_be_bytes(read_size_buffer);
-    let mut data = Vec::with_capacity(BUFFER_SIZE);
-    let mut buff = [0u8;BUFFER_SIZE];
+    // Long commands, like a `gets` of many paths, are read without growing the buffer again and again
+    let mut data = Vec::with_capacity((msg_size as usize).min(MAX_PREALLOCATED_MESSAGE));
     while data.len() < msg_size as usize {
-        let length = stream.read(&mut buff)?;
+        // Sockets are byte streams, so never read past the end of this message
+        let remaining = msg_size as usize - data.len();
+        let buff = &mut session.read_buffer;
+        let chunk_size = remaining.min(buff.len());
+        let length = stream.read(&mut buff[..chunk_size])?;
+        // Reads return 0 forever once the client is gone, instead of the rest of the message
+        if length == 0 {
+            return Err(anyhow!("Disconnected after {} of {} bytes of the message", data.len(), msg_size));
+        }
         data.extend(&buff[..length]);
     }
 
-    let command = String::from_utf8_lossy(&data).into_owned();
-    let args : Vec<&str> = command.split("|").collect();
-    process_command(args, stream, cached_images, thread_channels)?;
+    // Every reply is written in multiple pieces, which are sent together instead of one write per piece
+    let mut writer = BufWriter::with_capacity(WRITE_BUFFER_SIZE, TimedWriter {protocol_version: session.protocol_version, inner: &mut *stream, written_in: Duration::ZERO});
+    let result = parse_command(&data).and_then(|(args, payload)| {
+        let args : Vec<&str> = args.iter().map(String::as_str).collect();
+        let command = args.first().copied().unwrap_or_default();
+        // The reply is framed like before, the client only knows the version once it read it
+        if command == "protocol" {
+            return session.negotiate_protocol(&args, &mut writer);
+        }
+        match session.take_token(command, state) {
+            Ok(()) if command == "auth" => session.authenticate(&args, &mut writer, state),
+            Ok(()) => {
+                session.check_allowed(command, state)?;
+                process_command(args, payload, &mut writer, state)
+            },
+            Err(retry_in) => send_rate_limited(retry_in, &mut writer)
+        }
+    });
+    if let Err(e) = result {
+        // Let the client know instead of leaving it waiting for a reply
+        warn!("Command failed: {:#}", e);
+        send_error(&e, &mut writer)?;
+    }
+    writer.flush()?;
+    let written_in = writer.get_ref().written_in;
+    // Nothing was sent, if the client disconnected before the command was read
+    if let (Some(server), false) = (state.server.get(), written_in.is_zero()) {
+        server.metrics().record(Stage::Send, written_in);
+    }
+    Ok(true)
+}
+
+
+/// The `PICTOCRAB_READ_BUFFER` environment variable in bytes, or `BUFFER_SIZE`
+fn read_buffer_size() -> usize {
+    *READ_BUFFER_SIZE.get_or_init(|| {
+        let Ok(value) = std::env::var(READ_BUFFER_ENV) else {return BUFFER_SIZE};
+        match value.parse::<usize>() {
+            Ok(size) if size > 0 => size,
+            _ => {
+                warn!("Invalid {} : {}, using {} bytes", READ_BUFFER_ENV, value, BUFFER_SIZE);
+                BUFFER_SIZE
+            }
+        }
+    })
+}
+
+fn read_loop<S: Read + Write>(mut stream: S, remote: bool, state: &ServerState) -> anyhow::Result<()> {
+    let mut session = Session::new(remote);
+    while read_command(&mut stream, &mut session, state)? {}
     Ok(())
 }
 
 
-fn read_loop(mut stream: DuplexBytePipeStream, cached_images: CachedImageShared, thread_channels: &ThreadChannels) -> anyhow::Result<()> {
+/// Listens over TCP if an address was configured, otherwise on the local pipe or socket
+fn bind_listener(config: &config::StartupConfig) -> anyhow::Result<Box<dyn transport::Listener>> {
+    match &config.tcp_address {
+        Some(address) => {
+            let address = transport::tcp_bind_address(address);
+            let listener = transport::bind_tcp(&address).map_err(|e| anyhow!("Could not listen on {} : {}", address, e))?;
+            info!("Listening on {}", address);
+            if config.tcp_token.is_none() {
+                info!("No TCP token is configured, so TCP clients cannot set up, clear or stop the server, and only load images once it is set up with a root");
+            }
+            Ok(listener)
+        },
+        None => {
+            let listener = transport::bind_local(&config.pipe_name).map_err(|e| anyhow!("Could not create pipe listener : {}", e))?;
+            info!("Listening on the pipe {}", config.pipe_name);
+            Ok(listener)
+        }
+    }
+}
+
+/// Sets the server up with the cache dir of the config, so clients don't have to
+fn setup_from_config(state: &ServerState) -> anyhow::Result<()> {
+    let config = &state.config;
+    let Some(cache_dir) = &config.cache_dir else {return Ok(())};
+    setup(cache_dir, &config.working_dir, config.threaded_reads, &[], state)?;
+    info!("Set up with the cache dir {}", cache_dir);
+    Ok(())
+}
+
+fn accept_loop(listener: Box<dyn transport::Listener>, state: SharedState) {
+    info!("Waiting for connections");
     loop {
-        read_command(&mut stream, &cached_images, thread_channels)?
+        let Ok(stream) = listener.accept() else {continue};
+        let client_name = stream.client_name();
+        let remote = stream.is_remote();
+        info!("Connected to {}", client_name);
+        let state = state.clone();
+        // Every client gets its own thread, so clients don't have to wait for each other to disconnect
+        std::thread::spawn(move || {
+            if let Err(e) = read_loop(stream, remote, &state) {
+                error!("Error with client {}: {:?}", client_name, e)
+            }
+        });
     }
 }
 
+/// Logs info and above by default, `RUST_LOG=trace` also logs how long every step of loading an image takes
+fn init_logging() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+}
 
 fn main() {
-    let listener : PipeListener<DuplexBytePipeStream> = PipeListenerOptions::new()
-        .name(OsStr::new(PIPE_NAME))
-        .mode(PipeMode::Messages)
-        .create()
-        .expect("Could not create pipe listener");
-
-    let data = HashMap::with_capacity(300000);
-    let cached_images = CachedImageShared::new(RwLock::new((data, Default::default())));
-
-    let mut thread_channels = Vec::with_capacity(GETS_THREAD_COUNT);
-    for i in 0..GETS_THREAD_COUNT {
-        let thread_cached_images = cached_images.clone();
-        let (to_thread_send, in_thread_recv) = mpsc::channel();
-        let (in_thread_send, from_thread_recv) = mpsc::channel();
+    init_logging();
+    info!("Reading messages in chunks of {} bytes", read_buffer_size());
+    let config = match config::StartupConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Invalid config : {:#}", e);
+            std::process::exit(1)
+        }
+    };
+    let listener = match bind_listener(&config) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
+
+    let state = SharedState::new(ServerState {config, ..Default::default()});
+    if let Err(e) = setup_from_config(&state) {
+        error!("Cannot set up from the config : {:#}", e);
+        std::process::exit(1)
+    }
+
+    let (shutdown_sender, shutdown_receiver) = mpsc::channel();
+    let ctrlc_sender = shutdown_sender.clone();
+    if let Err(e) = ctrlc::set_handler(move || {let _ = ctrlc_sender.send(false);}) {
+        warn!("Cannot handle Ctrl-C: {}", e);
+    }
+    SHUTDOWN.set(shutdown_sender).unwrap();
+
+    let accept_state = state.clone();
+    std::thread::spawn(move || accept_loop(listener, accept_state));
+
+    let clear = shutdown_receiver.recv().unwrap_or(false);
+    info!("Shutting down");
+    if let Err(e) = shutdown(&state, clear) {
+        error!("Error while shutting down: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use image::GenericImageView;
+    use super::*;
+
+    /// Accepts a single byte per write, like a stream, whose buffer is full
+    #[derive(Default)]
+    struct OneByteWriter {
+        written: Vec<u8>
+    }
+
+    impl Write for OneByteWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let Some(byte) = buf.first() else {return Ok(0)};
+            self.written.push(*byte);
+            Ok(1)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A reply stream speaking `protocol_version`, like the one of a connection
+    fn reply_writer<W: Write>(inner: W, protocol_version: u32) -> BufWriter<TimedWriter<W>> {
+        BufWriter::with_capacity(WRITE_BUFFER_SIZE, TimedWriter {protocol_version, inner, written_in: Duration::ZERO})
+    }
+
+    /// A connection, which sends the commands written to `input` and collects the replies
+    struct TestConnection {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+        write_count: usize
+    }
+
+    impl TestConnection {
+        fn new(commands: &[&[&str]]) -> Self {
+            let input = commands.iter().flat_map(|args| encode_command(args)).collect();
+            Self {input: std::io::Cursor::new(input), output: Vec::new(), write_count: 0}
+        }
+    }
+
+    impl Read for TestConnection {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for TestConnection {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.write_count += 1;
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A message with length prefixed arguments, like clients send them
+    fn encode_command(args: &[&str]) -> Vec<u8> {
+        let mut command = (args.len() as u32).to_be_bytes().to_vec();
+        for arg in args {
+            command.extend_from_slice(&(arg.len() as u32).to_be_bytes());
+            command.extend_from_slice(arg.as_bytes());
+        }
+        let mut message = (command.len() as u32).to_be_bytes().to_vec();
+        message.extend(command);
+        message
+    }
+
+    /// Splits replies of protocol version 1 into their status and body
+    fn parse_replies(mut output: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut replies = Vec::new();
+        while !output.is_empty() {
+            let status = output[0];
+            let length = u32::from_be_bytes(output[1..5].try_into().unwrap()) as usize;
+            replies.push((status, output[5..5 + length].to_vec()));
+            output = &output[5 + length..];
+        }
+        replies
+    }
+
+    /// Sends the commands on a new connection and returns every reply
+    fn run_commands(state: &ServerState, commands: &[&[&str]]) -> Vec<(u8, Vec<u8>)> {
+        let mut connection = TestConnection::new(commands);
+        read_loop(&mut connection, false, state).unwrap();
+        parse_replies(&connection.output)
+    }
+
+    /// Collects the log messages written to it
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Reads a single reply of protocol version 1 from a connected stream
+    fn read_reply(stream: &mut impl Read) -> (u8, Vec<u8>) {
+        let mut header = [0; 5];
+        stream.read_exact(&mut header).unwrap();
+        let mut body = vec![0; u32::from_be_bytes(header[1..].try_into().unwrap()) as usize];
+        stream.read_exact(&mut body).unwrap();
+        (header[0], body)
+    }
+
+    /// Accepts clients of the listener in the background, like `main` does
+    fn serve(listener: Box<dyn transport::Listener>, state: ServerState) -> SharedState {
+        let state = SharedState::new(state);
+        let accept_state = state.clone();
+        std::thread::spawn(move || accept_loop(listener, accept_state));
+        state
+    }
+
+    /// A name of a local socket or pipe, which no other test or server uses
+    fn local_name(name: &str) -> String {
+        format!("picto-crab-test-{}-{}", std::process::id(), name)
+    }
+
+    #[cfg(windows)]
+    fn connect_local(name: &str) -> interprocess::os::windows::named_pipe::DuplexBytePipeStream {
+        interprocess::os::windows::named_pipe::DuplexBytePipeStream::connect(std::ffi::OsStr::new(name)).unwrap()
+    }
+
+    /// Connects like `bind_local` binds, in the abstract namespace if supported, otherwise at `/tmp/{name}.sock`
+    #[cfg(not(windows))]
+    fn connect_local(name: &str) -> interprocess::local_socket::LocalSocketStream {
+        use interprocess::local_socket::{LocalSocketStream, NameTypeSupport};
+        let socket_name = match NameTypeSupport::query() {
+            NameTypeSupport::OnlyPaths => format!("/tmp/{}.sock", name),
+            NameTypeSupport::OnlyNamespaced | NameTypeSupport::Both => format!("@{}", name)
+        };
+        LocalSocketStream::connect(socket_name).unwrap()
+    }
+
+    /// An empty directory named after the test, in the temporary directory
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join("picto-crab-server-tests").join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes a solid color BMP to `dir` and returns its absolute path, so tests don't depend on the working directory
+    fn write_bmp(dir: &std::path::Path, name: &str, width: u32, height: u32, color: [u8; 3]) -> String {
+        let path = dir.join(name);
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(width, height, image::Rgb(color))).save_with_format(&path, image::ImageFormat::Bmp).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    /// A server set up with the cache dir in `dir` and these setup options, which always caches in memory
+    fn set_up_state(dir: &std::path::Path, options: &[&str]) -> ServerState {
+        set_up(ServerState::default(), dir, options)
+    }
+
+    /// Sets up the state like `set_up_state` over a local connection
+    fn set_up(state: ServerState, dir: &std::path::Path, options: &[&str]) -> ServerState {
+        let cache_dir = dir.join("cache");
+        let mut args = vec!["setup", cache_dir.to_str().unwrap(), dir.to_str().unwrap(), "true", "threads=2", "min_available_memory=0"];
+        args.extend_from_slice(options);
+        assert_eq!(run_commands(&state, &[&args]), [(STATUS_OK, Vec::new())]);
+        state
+    }
+
+    #[test]
+    fn splits_batches_of_any_size_among_the_threads() {
+        for count in [0, 1, 5, 100] {
+            let paths : Vec<String> = (0..count).map(|i| format!("image{}.bmp", i)).collect();
+            let paths : Vec<&str> = paths.iter().map(String::as_str).collect();
+            let parts = split_among_threads(&paths, 8);
+            let mut indices : Vec<usize> = parts.iter().flat_map(|(_, indices, _)| indices.iter().copied()).collect();
+            indices.sort_unstable();
+            assert_eq!(indices, (0..count).collect::<Vec<_>>());
+            for (thread, indices, thread_paths) in &parts {
+                assert!(*thread < 8);
+                assert!(!indices.is_empty());
+                assert!(indices.iter().zip(thread_paths).all(|(index, path)| paths[*index] == *path));
+            }
+        }
+    }
+
+    #[test]
+    fn sends_the_whole_image_to_streams_accepting_single_bytes() {
+        let img_bytes : Vec<u8> = (0..3 * WRITE_BUFFER_SIZE).map(|i| i as u8).collect();
+        let mut writer = reply_writer(OneByteWriter::default(), 1);
+        send_image(EncodedImage::new(img_bytes.clone(), 1, 1), &mut writer).unwrap();
+        writer.flush().unwrap();
+        let written = writer.into_inner().map_err(|_| ()).unwrap().inner.written;
+        assert_eq!(written[0], STATUS_OK);
+        assert_eq!(u32::from_be_bytes(written[1..5].try_into().unwrap()) as usize, img_bytes.len());
+        assert_eq!(&written[5..], &img_bytes[..]);
+    }
+
+    #[test]
+    fn replies_with_the_error_of_a_failed_command() {
+        let dir = test_dir("replies_with_the_error_of_a_failed_command");
+        let state = set_up_state(&dir, &[]);
+        let missing_path = dir.join("missing.bmp");
+        let missing_path = missing_path.to_str().unwrap();
+        let replies = run_commands(&state, &[&["get", missing_path, "10", "10"], &["clear_cache"]]);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].0, STATUS_ERROR);
+        assert!(String::from_utf8_lossy(&replies[0].1).contains("No such file"), "{}", String::from_utf8_lossy(&replies[0].1));
+        assert_eq!(replies[1], (STATUS_OK, Vec::new()));
+    }
+
+    #[test]
+    fn takes_every_argument_after_the_separator_as_path() {
+        let dir = test_dir("takes_every_argument_after_the_separator_as_path");
+        let state = set_up_state(&dir, &[]);
+        write_bmp(&dir, "png", 4, 4, [255, 0, 0]);
+        write_bmp(&dir, "--", 4, 4, [0, 255, 0]);
+        let dir = dir.to_str().unwrap();
+        let replies = run_commands(&state, &[&["gets", "2", "2", "png", "--", &format!("{}/png", dir), &format!("{}/--", dir)], &["gets", "2", "2", "png"]]);
+        assert_eq!(replies.len(), 3);
+        assert!(replies[..2].iter().all(|(status, img_bytes)| *status == STATUS_OK && img_bytes.starts_with(b"\x89PNG")));
+        assert_eq!(replies[2].0, STATUS_ERROR);
+    }
+
+    #[test]
+    fn spawns_as_many_threads_as_set_up() {
+        let dir = test_dir("spawns_as_many_threads_as_set_up");
+        let state = set_up_state(&dir, &["threads=4"]);
+        let thread_channels = state.thread_channels.read().unwrap();
+        assert_eq!(thread_channels.len(), 4);
+        assert!(thread_channels.iter().all(|(_, handle)| !handle.is_finished()));
+        drop(thread_channels);
+        let cache_dir = dir.join("cache");
+        let setup_args = ["setup", cache_dir.to_str().unwrap(), dir.to_str().unwrap(), "true"];
+        let replies = run_commands(&state, &[&[&setup_args[..], &["threads=8"]].concat(), &[&setup_args[..], &["max_pixels=4"]].concat()]);
+        assert_eq!(replies[0], (STATUS_OK, Vec::new()));
+        assert_eq!(state.thread_channels.read().unwrap().len(), 8);
+        assert_eq!(replies[1].0, STATUS_ERROR);
+        assert!(String::from_utf8_lossy(&replies[1].1).contains("max_pixels"));
+        // Setups, which don't give the thread count, keep it
+        assert_eq!(run_commands(&state, &[&setup_args]), [(STATUS_OK, Vec::new())]);
+        assert_eq!(state.thread_channels.read().unwrap().len(), 8);
+    }
+
+    #[test]
+    fn overrides_the_setup_options_of_the_config() {
+        let dir = test_dir("overrides_the_setup_options_of_the_config");
+        let cache_dir = dir.join("cache");
+        let config = config::StartupConfig {
+            cache_dir: Some(cache_dir.to_str().unwrap().to_string()),
+            working_dir: dir.to_str().unwrap().to_string(),
+            setup_options: vec!["threads=2".to_string(), "min_available_memory=18446744073709551615".to_string()],
+            ..Default::default()
+        };
+        let state = ServerState {config, ..Default::default()};
+        setup_from_config(&state).unwrap();
+        assert_eq!(state.thread_channels.read().unwrap().len(), 2);
+        let setup = ["setup", cache_dir.to_str().unwrap(), dir.to_str().unwrap(), "true", "threads=3", "min_available_memory=0"];
+        assert_eq!(run_commands(&state, &[&setup]), [(STATUS_OK, Vec::new())]);
+        let thread_channels = state.thread_channels.read().unwrap();
+        assert_eq!(thread_channels.len(), 3);
+        assert!(thread_channels.iter().all(|(_, handle)| !handle.is_finished()));
+        drop(thread_channels);
+        // Cached in memory now, instead of on disk like the config told
+        let path = write_bmp(&dir, "image.bmp", 8, 8, [255, 0, 0]);
+        assert_eq!(run_commands(&state, &[&["get", &path, "4", "4"]])[0].0, STATUS_OK);
+        let cache_stats = state.server().unwrap().cache_stats();
+        assert_eq!((cache_stats.entries, cache_stats.disk_entries), (1, 0));
+    }
+
+    #[test]
+    fn counts_the_hits_and_misses_of_the_cache() {
+        let dir = test_dir("counts_the_hits_and_misses_of_the_cache");
+        let state = set_up_state(&dir, &[]);
+        let first_path = write_bmp(&dir, "first.bmp", 16, 16, [255, 0, 0]);
+        let second_path = write_bmp(&dir, "second.bmp", 16, 16, [0, 0, 255]);
+        let replies = run_commands(&state, &[
+            &["get", &first_path, "8", "8"],
+            &["get", &first_path, "8", "8"],
+            &["get", &second_path, "8", "8"],
+            &["get", &first_path, "8", "8"],
+            &["cache_stats"]
+        ]);
+        assert!(replies.iter().all(|(status, _)| *status == STATUS_OK));
+        let stats = String::from_utf8(replies[4].1.clone()).unwrap();
+        for count in ["\"entries\":2", "\"disk_entries\":0", "\"hits\":2", "\"misses\":2"] {
+            assert!(stats.contains(count), "{} in {}", count, stats);
+        }
+    }
+
+    #[test]
+    fn gets_and_removes_paths_containing_the_old_delimiter() {
+        let dir = test_dir("gets_and_removes_paths_containing_the_old_delimiter");
+        let state = set_up_state(&dir, &[]);
+        let path = write_bmp(&dir, "a|b.bmp", 8, 8, [255, 0, 0]);
+        let watermark_arg = format!("watermark={}", write_bmp(&dir, "mark|1.bmp", 2, 2, [0, 0, 0]));
+        let replies = run_commands(&state, &[
+            &["get", &path, "4", "4"],
+            &["gets", "4", "4", &watermark_arg, "--", &path],
+            &["remove", &path]
+        ]);
+        assert!(replies[..2].iter().all(|(status, img_bytes)| *status == STATUS_OK && img_bytes.starts_with(b"BM")));
+        assert_eq!(replies[2], (STATUS_OK, b"2".to_vec()));
+    }
+
+    #[test]
+    fn keeps_the_connection_after_malformed_commands() {
+        let state = ServerState::default();
+        let replies = run_commands(&state, &[&["setup", "cache"], &["get", "image.bmp", "wide", "8"], &[], &["ping"]]);
+        let errors : Vec<_> = replies[..3].iter().map(|(status, message)| (*status, String::from_utf8_lossy(message).into_owned())).collect();
+        assert_eq!(errors, [
+            (STATUS_ERROR, "Missing argument : working dir".to_string()),
+            (STATUS_ERROR, "Invalid width : wide".to_string()),
+            (STATUS_ERROR, "Empty command".to_string())
+        ]);
+        assert_eq!(replies[3].0, STATUS_OK);
+    }
+
+    #[test]
+    fn gets_an_image_over_the_local_socket() {
+        let dir = test_dir("gets_an_image_over_the_local_socket");
+        let path = write_bmp(&dir, "image.bmp", 8, 8, [255, 0, 0]);
+        let name = local_name("local");
+        serve(transport::bind_local(&name).unwrap(), set_up_state(&dir, &[]));
+        let mut stream = connect_local(&name);
+        stream.write_all(&encode_command(&["get", &path, "4", "4"])).unwrap();
+        let (status, img_bytes) = read_reply(&mut stream);
+        assert_eq!(status, STATUS_OK);
+        assert_eq!(image::load_from_memory(&img_bytes).unwrap().dimensions(), (4, 4));
+    }
+
+    #[test]
+    fn gets_an_image_over_tcp_after_authenticating() {
+        let dir = test_dir("gets_an_image_over_tcp_after_authenticating");
+        let path = write_bmp(&dir, "image.bmp", 8, 8, [255, 0, 0]);
+        let config = config::StartupConfig {tcp_token: Some("token".to_string()), ..Default::default()};
+        let state = set_up(ServerState {config, ..Default::default()}, &dir, &[]);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        serve(Box::new(listener), state);
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        let mut send = |args: &[&str]| {
+            stream.write_all(&encode_command(args)).unwrap();
+            read_reply(&mut stream)
+        };
+        // Without a root, any file could be read
+        assert_eq!(send(&["get", &path, "4", "4"]), (STATUS_ERROR, b"get is only allowed over TCP after `auth` with the TCP token, or once the server is set up with a `root`".to_vec()));
+        assert_eq!(send(&["clear_cache"]), (STATUS_ERROR, b"clear_cache is only allowed over TCP after `auth` with the TCP token".to_vec()));
+        assert_eq!(send(&["ping"]).0, STATUS_OK);
+        assert_eq!(send(&["auth", "guess"]), (STATUS_ERROR, b"Invalid token".to_vec()));
+        assert_eq!(send(&["auth", "token"]), (STATUS_OK, Vec::new()));
+        let (status, img_bytes) = send(&["get", &path, "4", "4"]);
+        assert_eq!(status, STATUS_OK);
+        assert_eq!(image::load_from_memory(&img_bytes).unwrap().dimensions(), (4, 4));
+        assert_eq!(send(&["clear_cache"]), (STATUS_OK, Vec::new()));
+    }
+
+    #[test]
+    fn only_reads_images_within_the_root_over_tcp_without_authenticating() {
+        let dir = test_dir("only_reads_images_within_the_root_over_tcp_without_authenticating");
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        let inside_path = write_bmp(&root, "inside.bmp", 8, 8, [255, 0, 0]);
+        let outside_path = write_bmp(&dir, "outside.bmp", 8, 8, [0, 0, 255]);
+        let missing_path = dir.join("missing.bmp").to_str().unwrap().to_string();
+        let escaping_path = format!("{}/../outside.bmp", root.to_str().unwrap());
+        let state = set_up_state(&dir, &[&format!("root={}", root.to_str().unwrap())]);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        serve(Box::new(listener), state);
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        let mut send = |args: &[&str]| {
+            stream.write_all(&encode_command(args)).unwrap();
+            read_reply(&mut stream)
+        };
+        assert_eq!(send(&["get", &inside_path, "4", "4"]).0, STATUS_OK);
+        // Files outside of the root fail the same, whether they exist or not
+        for path in [&outside_path, &escaping_path, &missing_path] {
+            for command in ["get", "dimensions"] {
+                let args : &[&str] = if command == "get" {&[command, path, "4", "4"]} else {&[command, path]};
+                let (status, message) = send(args);
+                assert_eq!(status, STATUS_ERROR);
+                let message = String::from_utf8_lossy(&message);
+                assert!(message.ends_with(&format!("Path {} is outside of the root directory", path)), "{}", message);
+            }
+        }
+    }
+
+    #[test]
+    fn fails_to_listen_on_a_used_address() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let config = config::StartupConfig {tcp_address: Some(address.clone()), ..Default::default()};
+        let Err(error) = bind_listener(&config) else {panic!("Listened on the used address {}", address)};
+        assert!(error.to_string().starts_with(&format!("Could not listen on {} : ", address)), "{}", error);
+    }
+
+    #[test]
+    fn serves_clients_at_the_same_time() {
+        let dir = test_dir("serves_clients_at_the_same_time");
+        let paths = [write_bmp(&dir, "first.bmp", 8, 8, [255, 0, 0]), write_bmp(&dir, "second.bmp", 8, 8, [0, 0, 255])];
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        serve(Box::new(listener), set_up_state(&dir, &[&format!("root={}", dir.to_str().unwrap())]));
+        // Both stay connected, so the second one is only served if the first one doesn't block it
+        let mut streams = [std::net::TcpStream::connect(address).unwrap(), std::net::TcpStream::connect(address).unwrap()];
+        for (stream, path) in streams.iter_mut().rev().zip(paths.iter().rev()) {
+            stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            stream.write_all(&encode_command(&["get", path, "4", "4"])).unwrap();
+            assert_eq!(read_reply(stream).0, STATUS_OK);
+        }
+    }
+
+    #[test]
+    fn replies_only_with_the_images_of_the_own_batch_to_overlapping_gets() {
+        let dir = test_dir("replies_only_with_the_images_of_the_own_batch_to_overlapping_gets");
+        let state = set_up_state(&dir, &[]);
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 0]];
+        std::thread::scope(|scope| {
+            for (client, color) in colors.into_iter().enumerate() {
+                let paths : Vec<String> = (0..8).map(|i| write_bmp(&dir, &format!("{}-{}.bmp", client, i), 8, 8, color)).collect();
+                let state = &state;
+                scope.spawn(move || {
+                    let mut args = vec!["gets", "4", "4", "--"];
+                    args.extend(paths.iter().map(String::as_str));
+                    for _ in 0..20 {
+                        let replies = run_commands(state, &[&args]);
+                        assert_eq!(replies.len(), paths.len());
+                        for (status, img_bytes) in replies {
+                            assert_eq!(status, STATUS_OK);
+                            assert_eq!(image::load_from_memory(&img_bytes).unwrap().to_rgb8().get_pixel(2, 2).0, color);
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    /// A client, which stopped reading. Writing blocks until the sender is dropped
+    struct BlockedWriter(mpsc::Receiver<()>);
+
+    impl Write for BlockedWriter {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            let _ = self.0.recv();
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn shuts_down_while_a_client_stopped_reading_its_images() {
+        let dir = test_dir("shuts_down_while_a_client_stopped_reading_its_images");
+        let state = SharedState::new(set_up_state(&dir, &["max_in_flight_bytes=1"]));
+        // Larger than the write buffer, so sending the first one blocks, while the others wait for it to be sent
+        let paths : Vec<String> = (0..6).map(|i| write_bmp(&dir, &format!("{}.bmp", i), 160, 160, [i as u8, 0, 0])).collect();
+        let (unblock_sender, unblock_receiver) = mpsc::channel();
+        let gets_state = state.clone();
         std::thread::spawn(move || {
-            if let Err(e) = gets_thread(thread_cached_images, in_thread_recv, in_thread_send) {
-                eprintln!("Thread {} exited with error: {}", i, e);
+            let paths : Vec<&str> = paths.iter().map(String::as_str).collect();
+            let mut stream = reply_writer(BlockedWriter(unblock_receiver), 1);
+            let _ = gets_images(&mut stream, &gets_state, 160, 160, &ImageOptions::default(), &paths, true);
+        });
+        // So the threads are waiting
+        std::thread::sleep(Duration::from_millis(500));
+        let (done_sender, done_receiver) = mpsc::channel();
+        let shutdown_state = state.clone();
+        std::thread::spawn(move || done_sender.send(shutdown(&shutdown_state, false).is_ok()));
+        assert_eq!(done_receiver.recv_timeout(Duration::from_secs(10)), Ok(true));
+        std::mem::drop(unblock_sender);
+    }
+
+    /// `main` stops accepting clients and exits once it receives the signal, this checks everything before that
+    #[test]
+    fn signals_main_and_stops_the_threads_to_shut_down() {
+        let dir = test_dir("signals_main_and_stops_the_threads_to_shut_down");
+        let state = set_up_state(&dir, &[]);
+        let (shutdown_sender, shutdown_receiver) = mpsc::channel();
+        SHUTDOWN.set(shutdown_sender).unwrap();
+        assert_eq!(run_commands(&state, &[&["shutdown", "clear_cache"]]), [(STATUS_OK, Vec::new())]);
+        assert_eq!(shutdown_receiver.try_recv(), Ok(true));
+        shutdown(&state, false).unwrap();
+        assert!(state.thread_channels.read().unwrap().is_empty());
+        assert!(dir.join("cache").join("index.txt").exists());
+        let replies = run_commands(&state, &[&["gets", "4", "4", "--", "image.bmp"]]);
+        assert_eq!(replies, [(STATUS_ERROR, b"Not setup".to_vec())]);
+    }
+
+    #[test]
+    fn reports_whether_it_was_set_up_to_pings() {
+        let dir = test_dir("reports_whether_it_was_set_up_to_pings");
+        let state = ServerState::default();
+        let ping = |state: &ServerState| {
+            let replies = run_commands(state, &[&["ping"]]);
+            assert_eq!(replies[0].0, STATUS_OK);
+            String::from_utf8(replies[0].1.clone()).unwrap()
+        };
+        assert!(ping(&state).ends_with("\"setup\":false}"));
+        assert!(!dir.join("cache").exists());
+        let state = set_up(state, &dir, &[]);
+        assert!(ping(&state).ends_with("\"setup\":true}"));
+    }
+
+    #[test]
+    fn fits_the_longest_side_into_the_max_side() {
+        let dir = test_dir("fits_the_longest_side_into_the_max_side");
+        let state = set_up_state(&dir, &[]);
+        let landscape_path = write_bmp(&dir, "landscape.bmp", 1000, 500, [255, 0, 0]);
+        let portrait_path = write_bmp(&dir, "portrait.bmp", 500, 1000, [255, 0, 0]);
+        let replies = run_commands(&state, &[&["get_fit", &landscape_path, "200"], &["get_fit", &portrait_path, "200"]]);
+        let dimensions : Vec<_> = replies.iter().map(|(_, img_bytes)| image::load_from_memory(img_bytes).unwrap().dimensions()).collect();
+        assert_eq!(dimensions, [(200, 100), (100, 200)]);
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn speaks_the_protocol_of_the_client() {
+        use picto_crab::client::{ClientError, PictoClient};
+        let dir = test_dir("speaks_the_protocol_of_the_client");
+        let path = write_bmp(&dir, "image.bmp", 8, 8, [255, 0, 0]);
+        let name = local_name("client");
+        serve(transport::bind_local(&name).unwrap(), ServerState::default());
+        let mut client = PictoClient::connect_local(&name).unwrap();
+        assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+        client.setup(dir.join("cache").to_str().unwrap(), dir.to_str().unwrap(), true, &["threads=2", "min_available_memory=0"]).unwrap();
+        let options = ImageOptions::default();
+        assert_eq!(image::load_from_memory(&client.get(&path, 4, 4, &options).unwrap()).unwrap().dimensions(), (4, 4));
+        let missing_path = dir.join("missing.bmp");
+        let images = client.gets(&[&path, missing_path.to_str().unwrap()], 4, 4, &options).unwrap();
+        assert!(images[0].is_ok());
+        assert!(matches!(images[1], Err(ClientError::ImageFailed(_))));
+        assert!(matches!(client.get(&path, 0, 4, &options), Err(ClientError::Server(_))));
+        client.clear_cache().unwrap();
+    }
+
+    #[test]
+    fn logs_the_time_of_every_stage_at_trace_level() {
+        let dir = test_dir("logs_the_time_of_every_stage_at_trace_level");
+        let state = set_up_state(&dir, &[]);
+        let path = write_bmp(&dir, "image.bmp", 8, 8, [255, 0, 0]);
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || run_commands(&state, &[&["get", &path, "4", "4"]]));
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        for stage in ["Read image", "Decoded image", "Processed image", "Sent image"] {
+            assert!(logs.lines().any(|line| line.contains("TRACE") && line.contains(stage) && line.contains("nanos=")), "{} in {}", stage, logs);
+        }
+    }
+
+    #[test]
+    fn writes_batches_in_a_few_large_writes() {
+        let dir = test_dir("writes_batches_in_a_few_large_writes");
+        let state = set_up_state(&dir, &[]);
+        let paths : Vec<String> = (0..100).map(|i| write_bmp(&dir, &format!("{}.bmp", i), 8, 8, [i as u8, 0, 0])).collect();
+        let mut args = vec!["gets", "8", "8", "--"];
+        args.extend(paths.iter().map(String::as_str));
+        let mut connection = TestConnection::new(&[&args]);
+        read_loop(&mut connection, false, &state).unwrap();
+        assert_eq!(parse_replies(&connection.output).len(), 100);
+        // Writing the header and the body of every image on its own would take 200 writes
+        assert!(connection.write_count <= 5, "{} writes", connection.write_count);
+    }
+
+    #[test]
+    fn preloads_images_into_the_cache() {
+        let dir = test_dir("preloads_images_into_the_cache");
+        let state = set_up_state(&dir, &[]);
+        let paths : Vec<String> = (0..3).map(|i| write_bmp(&dir, &format!("{}.bmp", i), 8, 8, [0, i as u8, 0])).collect();
+        let missing_path = dir.join("missing.bmp");
+        let mut preload_args = vec!["preload", "4", "4", "--", missing_path.to_str().unwrap()];
+        preload_args.extend(paths.iter().map(String::as_str));
+        let mut commands : Vec<Vec<&str>> = vec![preload_args];
+        commands.extend(paths.iter().map(|path| vec!["get", path, "4", "4"]));
+        commands.push(vec!["cache_stats"]);
+        let commands : Vec<&[&str]> = commands.iter().map(Vec::as_slice).collect();
+        let replies = run_commands(&state, &commands);
+        assert_eq!(replies[0], (STATUS_OK, b"{\"succeeded\":3,\"failed\":1}".to_vec()));
+        let stats = String::from_utf8(replies[4].1.clone()).unwrap();
+        assert!(stats.contains("\"hits\":3"), "{}", stats);
+    }
+
+    #[test]
+    fn reports_the_timings_of_the_stages() {
+        let dir = test_dir("reports_the_timings_of_the_stages");
+        let state = set_up_state(&dir, &[]);
+        let paths : Vec<String> = (0..3).map(|i| write_bmp(&dir, &format!("{}.bmp", i), 8, 8, [0, 0, i as u8])).collect();
+        let mut commands : Vec<Vec<&str>> = paths.iter().map(|path| vec!["get", path, "4", "4"]).collect();
+        commands.push(vec!["metrics"]);
+        let commands : Vec<&[&str]> = commands.iter().map(Vec::as_slice).collect();
+        let replies = run_commands(&state, &commands);
+        let metrics = String::from_utf8(replies[3].1.clone()).unwrap();
+        for stage in ["read", "decode", "process", "encode"] {
+            assert!(metrics.contains(&format!("\"{}\":{{\"count\":3,\"p50\":", stage)), "{} in {}", stage, metrics);
+        }
+        // Every reply is sent, the one of setting up too
+        assert!(metrics.contains("\"send\":{\"count\":4,\"p50\":"), "{}", metrics);
+        let server = state.server().unwrap();
+        for stage in Stage::ALL {
+            let summary = server.metrics().summary(stage);
+            assert!(summary.p50 <= summary.p99 && summary.p99 <= summary.max && summary.max > 0, "{:?} of {}", summary, stage.name());
+        }
+    }
+
+    #[test]
+    fn spills_to_the_cache_dir_of_the_last_setup() {
+        let dir = test_dir("spills_to_the_cache_dir_of_the_last_setup");
+        // Images are always cached on disk then
+        let options = ["min_available_memory=18446744073709551615"];
+        let state = set_up_state(&dir, &options);
+        let count_cached_files = |cache_dir: &std::path::Path| -> usize {
+            let Ok(format_dirs) = std::fs::read_dir(cache_dir) else {return 0};
+            format_dirs.map(|entry| entry.unwrap().path()).filter(|path| path.is_dir())
+                .map(|format_dir| std::fs::read_dir(format_dir).unwrap().count())
+                .sum()
+        };
+        let first_path = write_bmp(&dir, "first.bmp", 8, 8, [255, 0, 0]);
+        assert_eq!(run_commands(&state, &[&["get", &first_path, "4", "4"]])[0].0, STATUS_OK);
+        assert_eq!(count_cached_files(&dir.join("cache")), 1);
+
+        let new_cache_dir = dir.join("nested").join("cache");
+        let setup = ["setup", new_cache_dir.to_str().unwrap(), dir.to_str().unwrap(), "true", "threads=2", "min_available_memory=0", options[0]];
+        assert_eq!(run_commands(&state, &[&setup]), [(STATUS_OK, Vec::new())]);
+        let second_path = write_bmp(&dir, "second.bmp", 8, 8, [0, 255, 0]);
+        assert_eq!(run_commands(&state, &[&["get", &second_path, "4", "4"]])[0].0, STATUS_OK);
+        // The image cached before moved along
+        assert_eq!(count_cached_files(&dir.join("cache")), 0);
+        assert_eq!(count_cached_files(&new_cache_dir), 2);
+        let replies = run_commands(&state, &[&["get", &first_path, "4", "4"], &["get", &second_path, "4", "4"]]);
+        assert!(replies.iter().all(|(status, img_bytes)| *status == STATUS_OK && img_bytes.starts_with(b"BM")));
+        assert_eq!(state.server().unwrap().cache_stats().hits, 2);
+    }
+
+    #[test]
+    fn tells_whether_an_image_is_cached_without_loading_it() {
+        let dir = test_dir("tells_whether_an_image_is_cached_without_loading_it");
+        let state = set_up_state(&dir, &[]);
+        let path = write_bmp(&dir, "image.bmp", 8, 8, [255, 0, 0]);
+        let replies = run_commands(&state, &[
+            &["is_cached", &path, "4", "4"],
+            &["is_cached", &path, "4", "4"],
+            &["get", &path, "4", "4"],
+            &["is_cached", &path, "4", "4"],
+            &["is_cached", &path, "2", "2"],
+            &["is_cached", &path, "4", "4", "grayscale"]
+        ]);
+        assert_eq!(replies[..2], [(STATUS_OK, vec![0]), (STATUS_OK, vec![0])]);
+        assert_eq!(replies[3..], [(STATUS_OK, vec![1]), (STATUS_OK, vec![0]), (STATUS_OK, vec![0])]);
+        // Only the get loaded the image
+        assert_eq!(state.server().unwrap().metrics().summary(Stage::Read).count, 1);
+    }
+
+    #[test]
+    fn creates_a_missing_cache_dir_on_setup() {
+        let dir = test_dir("creates_a_missing_cache_dir_on_setup");
+        let cache_dir = dir.join("missing").join("nested").join("cache");
+        let state = ServerState::default();
+        let setup = ["setup", cache_dir.to_str().unwrap(), dir.to_str().unwrap(), "true", "threads=2", "min_available_memory=18446744073709551615"];
+        assert_eq!(run_commands(&state, &[&setup]), [(STATUS_OK, Vec::new())]);
+        assert!(cache_dir.is_dir());
+        let path = write_bmp(&dir, "image.bmp", 8, 8, [255, 0, 0]);
+        assert_eq!(run_commands(&state, &[&["get", &path, "4", "4"]])[0].0, STATUS_OK);
+        assert_eq!(state.server().unwrap().cache_stats().disk_entries, 1);
+
+        // A directory can't be created inside of a file
+        let file_path = dir.join("file");
+        std::fs::write(&file_path, b"").unwrap();
+        let cache_dir = file_path.join("cache");
+        let setup = ["setup", cache_dir.to_str().unwrap(), dir.to_str().unwrap(), "true"];
+        let replies = run_commands(&ServerState::default(), &[&setup]);
+        assert_eq!(replies[0].0, STATUS_ERROR);
+        let message = String::from_utf8_lossy(&replies[0].1);
+        assert!(message.contains("Cannot create cache dir"), "{}", message);
+    }
+
+    #[test]
+    fn replies_with_the_path_of_images_which_cannot_be_decoded() {
+        let dir = test_dir("replies_with_the_path_of_images_which_cannot_be_decoded");
+        let state = set_up_state(&dir, &[]);
+        let garbage_path = dir.join("garbage.bmp");
+        std::fs::write(&garbage_path, b"not an image").unwrap();
+        let garbage_path = garbage_path.to_str().unwrap();
+        let cut_off_path = dir.join("cut_off.png");
+        let bmp_bytes = std::fs::read(write_bmp(&dir, "image.bmp", 8, 8, [255, 0, 0])).unwrap();
+        let mut png_bytes = Vec::new();
+        image::load_from_memory(&bmp_bytes).unwrap().write_to(&mut png_bytes, image::ImageFormat::Png).unwrap();
+        std::fs::write(&cut_off_path, &png_bytes[..40]).unwrap();
+        let cut_off_path = cut_off_path.to_str().unwrap();
+
+        let replies = run_commands(&state, &[&["get", garbage_path, "4", "4"], &["get", cut_off_path, "4", "4"]]);
+        let messages : Vec<_> = replies.iter().map(|(status, message)| {
+            assert_eq!(*status, STATUS_ERROR);
+            String::from_utf8_lossy(message).into_owned()
+        }).collect();
+        assert!(messages[0].contains(&format!("Cannot decode {} (12 bytes, unknown format)", garbage_path)), "{}", messages[0]);
+        assert!(messages[1].contains(&format!("Cannot decode {} (40 bytes, png)", cut_off_path)), "{}", messages[1]);
+    }
+
+    #[test]
+    fn keeps_the_images_in_flight_within_the_limit() {
+        for ordered in [true, false] {
+            let in_flight = InFlight::new(100, ordered);
+            let peak_bytes = AtomicUsize::new(0);
+            let (sender, receiver) = mpsc::channel();
+            std::thread::scope(|scope| {
+                for thread in 0..4 {
+                    let (sender, in_flight, peak_bytes) = (sender.clone(), &in_flight, &peak_bytes);
+                    scope.spawn(move || for i in 0..10 {
+                        let index = i * 4 + thread;
+                        in_flight.acquire(index, 40).unwrap();
+                        peak_bytes.fetch_max(in_flight.state.lock().unwrap().bytes, Ordering::SeqCst);
+                        sender.send(index).unwrap();
+                    });
+                }
+                // Sends the images like `send_in_order` and `send_as_loaded` would
+                let mut loaded = Vec::new();
+                for next_index in 0..40 {
+                    if ordered {
+                        while !loaded.contains(&next_index) {
+                            loaded.push(receiver.recv().unwrap());
+                        }
+                    } else {
+                        receiver.recv().unwrap();
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                    in_flight.sent(40);
+                }
+            });
+            // The image sent next is let through above the limit, if the images are sent in order
+            let max_bytes = if ordered {140} else {100};
+            let peak_bytes = peak_bytes.into_inner();
+            assert!((40..=max_bytes).contains(&peak_bytes), "{} bytes in flight, ordered: {}", peak_bytes, ordered);
+        }
+    }
+
+    #[test]
+    fn gets_large_batches_with_little_memory_in_flight() {
+        let dir = test_dir("gets_large_batches_with_little_memory_in_flight");
+        // Only about one reply of 102 bytes fits
+        let state = set_up_state(&dir, &["max_in_flight_bytes=150"]);
+        let paths : Vec<String> = (0..50).map(|i| write_bmp(&dir, &format!("{}.bmp", i), 8, 8, [i as u8, 0, 0])).collect();
+        // Of different sizes, so the second batch isn't cached already
+        for (command, size) in [("gets", "4"), ("gets_unordered", "3")] {
+            let mut args = vec![command, size, size, "--"];
+            args.extend(paths.iter().map(String::as_str));
+            let replies = run_commands(&state, &[&args]);
+            assert_eq!(replies.len(), 50);
+            assert!(replies.iter().all(|(status, _)| *status == STATUS_OK), "{}", command);
+        }
+    }
+
+    #[test]
+    fn sends_the_unordered_images_as_soon_as_they_are_loaded() {
+        let dir = test_dir("sends_the_unordered_images_as_soon_as_they_are_loaded");
+        let state = set_up_state(&dir, &["allowed_hosts=127.0.0.1"]);
+        let slow_path = write_bmp(&dir, "slow.bmp", 8, 8, [255, 0, 0]);
+        let slow_bytes = std::fs::read(&slow_path).unwrap();
+        // Answers after a while
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/slow.bmp", listener.local_addr().unwrap());
+        std::thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                if stream.read(&mut byte).unwrap() == 0 {break;}
+                request.push(byte[0]);
             }
+            std::thread::sleep(Duration::from_millis(500));
+            let _ = stream.write_all(format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", slow_bytes.len()).as_bytes());
+            let _ = stream.write_all(&slow_bytes);
         });
-        thread_channels.push((to_thread_send, from_thread_recv));
+        // Loaded by the other thread, so they don't wait for the slow one
+        let fast_paths : Vec<String> = (0..).map(|i| dir.join(format!("fast{}.bmp", i)).to_str().unwrap().to_string())
+            .filter(|path| thread_index(path, 2) != thread_index(&url, 2))
+            .take(3)
+            .collect();
+        for (i, path) in fast_paths.iter().enumerate() {
+            write_bmp(&dir, std::path::Path::new(path).file_name().unwrap().to_str().unwrap(), 8, 8, [0, i as u8, 0]);
+        }
+
+        let mut args = vec!["gets_unordered", "4", "4", "--", &url];
+        args.extend(fast_paths.iter().map(String::as_str));
+        let replies = run_commands(&state, &[&args]);
+        let indices : Vec<u32> = replies.iter().map(|(status, body)| {
+            assert_eq!(*status, STATUS_OK);
+            u32::from_be_bytes(body[..4].try_into().unwrap())
+        }).collect();
+        assert_eq!(indices.len(), 4);
+        assert_eq!(indices[3], 0, "{:?}", indices);
+    }
+
+    #[test]
+    fn reads_gets_of_thousands_of_paths() {
+        let dir = test_dir("reads_gets_of_thousands_of_paths");
+        let state = set_up_state(&dir, &[]);
+        let colors : Vec<[u8; 3]> = (0..5).map(|i| [i * 50, 0, 255 - i * 50]).collect();
+        let paths : Vec<String> = colors.iter().enumerate().map(|(i, color)| write_bmp(&dir, &format!("{}.bmp", i), 8, 8, *color)).collect();
+        let mut args = vec!["gets", "2", "2", "--"];
+        args.extend((0..2000).map(|i| paths[i % paths.len()].as_str()));
+        assert!(encode_command(&args).len() > 20 * BUFFER_SIZE);
+        let replies = run_commands(&state, &[&args]);
+        assert_eq!(replies.len(), 2000);
+        for (i, (status, img_bytes)) in replies.iter().enumerate() {
+            assert_eq!(*status, STATUS_OK);
+            assert_eq!(image::load_from_memory(img_bytes).unwrap().to_rgb8().get_pixel(1, 1).0, colors[i % colors.len()], "{}", i);
+        }
+    }
+
+    #[test]
+    fn stops_reading_once_the_client_disconnects_within_a_message() {
+        let state = ServerState::default();
+        let message = encode_command(&["ping"]);
+        let body_length = message.len() - 4;
+        for read_length in [4, 10] {
+            let mut connection = TestConnection::new(&[]);
+            connection.input = std::io::Cursor::new(message[..read_length].to_vec());
+            // Reading past the end returns 0 forever, like a disconnected stream
+            let error = read_loop(&mut connection, false, &state).unwrap_err();
+            assert_eq!(error.to_string(), format!("Disconnected after {} of {} bytes of the message", read_length - 4, body_length));
+            assert!(connection.output.is_empty());
+        }
+        // Disconnecting between messages isn't an error
+        let mut connection = TestConnection::new(&[&["ping"]]);
+        read_loop(&mut connection, false, &state).unwrap();
+        assert_eq!(parse_replies(&connection.output).len(), 1);
+    }
+
+    #[test]
+    fn rate_limits_the_commands_of_every_connection() {
+        let dir = test_dir("rate_limits_the_commands_of_every_connection");
+        let state = set_up_state(&dir, &["rate_limit=1", "rate_burst=3"]);
+        let mut commands : Vec<&[&str]> = vec![&["cache_stats"]; 20];
+        commands.push(&["ping"]);
+        let replies = run_commands(&state, &commands);
+        let ok_count = replies[..20].iter().filter(|(status, _)| *status == STATUS_OK).count();
+        // A token might have been refilled in between
+        assert!((3..=4).contains(&ok_count), "{} commands weren't limited", ok_count);
+        assert!(replies[..3].iter().all(|(status, _)| *status == STATUS_OK));
+        for (status, message) in &replies[ok_count..20] {
+            assert_eq!(*status, STATUS_RATE_LIMITED);
+            assert!(String::from_utf8_lossy(message).starts_with("Rate limited, retry in "));
+        }
+        assert_eq!(replies[20].0, STATUS_OK);
+        // Other connections have their own tokens
+        assert_eq!(run_commands(&state, &[&["cache_stats"]])[0].0, STATUS_OK);
+    }
+
+    /// Parses the replies of protocol version 2, which tell the format of the image after the status
+    fn parse_tagged_replies(mut output: &[u8]) -> Vec<(u8, u8, Vec<u8>)> {
+        let mut replies = Vec::new();
+        while !output.is_empty() {
+            let length = u32::from_be_bytes(output[2..6].try_into().unwrap()) as usize;
+            replies.push((output[0], output[1], output[6..6 + length].to_vec()));
+            output = &output[6 + length..];
+        }
+        replies
     }
 
-    println!("[PictoCrab] Waiting for connection");
-    for stream in listener.incoming() {
-        let Ok(stream) = stream else {continue};
-        let process_id = stream.client_process_id().unwrap();
-        println!("[PictoCrab] Connected to process {:?}", process_id);
-        if let Err(e) = read_loop(stream, cached_images.clone(), &thread_channels) {
-            println!("[PictoCrab] Error with client {:?}: {:?}", process_id, e)
+    #[test]
+    fn tags_the_replies_with_the_format_of_their_image() {
+        let dir = test_dir("tags_the_replies_with_the_format_of_their_image");
+        let state = set_up_state(&dir, &[]);
+        let path = write_bmp(&dir, "image.bmp", 8, 8, [255, 0, 0]);
+        let mut connection = TestConnection::new(&[
+            &["protocol", "2"],
+            &["get", &path, "4", "4", "png"],
+            &["get", &path, "4", "4", "bmp"],
+            &["get", &path, "4", "4", "jpeg"],
+            &["get", &path, "0", "4"],
+            &["cache_stats"]
+        ]);
+        read_loop(&mut connection, false, &state).unwrap();
+        // The reply to the handshake is still framed like before
+        let handshake_length = 5 + u32::from_be_bytes(connection.output[1..5].try_into().unwrap()) as usize;
+        assert_eq!(parse_replies(&connection.output[..handshake_length]), [(STATUS_OK, b"2".to_vec())]);
+        let replies = parse_tagged_replies(&connection.output[handshake_length..]);
+        let tags : Vec<_> = replies.iter().map(|(status, format, _)| (*status, ReplyFormat::from_tag(*format).unwrap())).collect();
+        assert_eq!(tags, [
+            (STATUS_OK, ReplyFormat::Png),
+            (STATUS_OK, ReplyFormat::Bmp),
+            (STATUS_OK, ReplyFormat::Jpeg),
+            (STATUS_ERROR, ReplyFormat::None),
+            (STATUS_OK, ReplyFormat::None)
+        ]);
+        assert!(replies[0].2.starts_with(b"\x89PNG") && replies[1].2.starts_with(b"BM"));
+    }
+
+    #[test]
+    fn negotiates_the_highest_version_both_sides_speak() {
+        let dir = test_dir("negotiates_the_highest_version_both_sides_speak");
+        let state = set_up_state(&dir, &[]);
+        let path = write_bmp(&dir, "image.bmp", 8, 8, [255, 0, 0]);
+        // A client of version 1 keeps getting replies without a format tag
+        let replies = run_commands(&state, &[&["protocol", "1"], &["get", &path, "4", "4", "png"], &["protocol", "0"]]);
+        assert_eq!(replies[0], (STATUS_OK, b"1".to_vec()));
+        assert_eq!(replies[1].0, STATUS_OK);
+        assert!(replies[1].1.starts_with(b"\x89PNG"));
+        assert_eq!(replies[2].0, STATUS_ERROR);
+        // Clients, which never negotiate, speak version 1 too
+        assert!(run_commands(&state, &[&["get", &path, "4", "4", "png"]])[0].1.starts_with(b"\x89PNG"));
+        let replies = run_commands(&state, &[&["protocol", "99"]]);
+        assert_eq!(replies, [(STATUS_OK, PROTOCOL_VERSION.to_string().into_bytes())]);
+    }
+
+    /// Parses the replies of protocol version 3, which tell the size of the image after its format
+    fn parse_sized_replies(mut output: &[u8]) -> Vec<(u8, (u32, u32), Vec<u8>)> {
+        let mut replies = Vec::new();
+        while !output.is_empty() {
+            let width = u32::from_be_bytes(output[2..6].try_into().unwrap());
+            let height = u32::from_be_bytes(output[6..10].try_into().unwrap());
+            let length = u32::from_be_bytes(output[10..14].try_into().unwrap()) as usize;
+            replies.push((output[0], (width, height), output[14..14 + length].to_vec()));
+            output = &output[14 + length..];
+        }
+        replies
+    }
+
+    #[test]
+    fn reports_the_size_of_the_images_it_replies_with() {
+        let dir = test_dir("reports_the_size_of_the_images_it_replies_with");
+        let state = set_up_state(&dir, &[]);
+        let path = write_bmp(&dir, "image.bmp", 16, 8, [255, 0, 0]);
+        let mut connection = TestConnection::new(&[
+            &["protocol", "3"],
+            &["get", &path, "10", "10", "resize=fit", "png"],
+            &["get", &path, "32", "32", "resize=fit", "no_upscale"],
+            &["get", &path, "0", "4"]
+        ]);
+        read_loop(&mut connection, false, &state).unwrap();
+        let handshake_length = 5 + u32::from_be_bytes(connection.output[1..5].try_into().unwrap()) as usize;
+        assert_eq!(parse_replies(&connection.output[..handshake_length]), [(STATUS_OK, b"3".to_vec())]);
+        let replies = parse_sized_replies(&connection.output[handshake_length..]);
+        assert_eq!(replies.len(), 3);
+        for (status, dimensions, img_bytes) in &replies[..2] {
+            assert_eq!(*status, STATUS_OK);
+            assert_eq!(image::load_from_memory(img_bytes).unwrap().dimensions(), *dimensions);
+        }
+        assert_eq!(replies[0].1, (10, 5));
+        assert_eq!(replies[1].1, (16, 8));
+        // Replies without an image have no size
+        assert_eq!((replies[2].0, replies[2].1), (STATUS_ERROR, (0, 0)));
+    }
+
+    #[test]
+    fn processes_images_sent_inline() {
+        let dir = test_dir("processes_images_sent_inline");
+        let state = set_up_state(&dir, &[]);
+        let mut png_bytes = Vec::new();
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(16, 8, image::Rgb([0, 255, 0]))).write_to(&mut png_bytes, image::ImageFormat::Png).unwrap();
+        // The image follows the arguments in the same message
+        let mut message = encode_command(&["process", "4", "4", "bmp"]).split_off(4);
+        message.extend_from_slice(&(png_bytes.len() as u32).to_be_bytes());
+        message.extend_from_slice(&png_bytes);
+        let mut input = (message.len() as u32).to_be_bytes().to_vec();
+        input.extend(message);
+        input.extend(encode_command(&["process", "4", "4"]));
+        let mut connection = TestConnection::new(&[]);
+        connection.input = std::io::Cursor::new(input);
+        read_loop(&mut connection, false, &state).unwrap();
+        let replies = parse_replies(&connection.output);
+
+        assert_eq!(replies[0].0, STATUS_OK);
+        assert!(replies[0].1.starts_with(b"BM"));
+        let img = image::load_from_memory(&replies[0].1).unwrap();
+        assert_eq!(img.dimensions(), (4, 4));
+        assert_eq!(img.to_rgb8().get_pixel(2, 1).0, [0, 255, 0]);
+        assert_eq!(replies[1], (STATUS_ERROR, b"Missing argument : image".to_vec()));
+        assert_eq!(state.server().unwrap().cache_stats().entries, 0);
+    }
+
+    #[test]
+    fn replaces_stopped_threads_and_survives_panicking_jobs() {
+        let dir = test_dir("replaces_stopped_threads_and_survives_panicking_jobs");
+        let state = set_up_state(&dir, &[]);
+        let paths : Vec<String> = (0..20).map(|i| write_bmp(&dir, &format!("{}.bmp", i), 8, 8, [i as u8, 0, 0])).collect();
+        {
+            let mut thread_channels = state.thread_channels.write().unwrap();
+            // Like a thread, which exited
+            let (sender, receiver) = mpsc::channel::<Job>();
+            std::mem::drop(receiver);
+            thread_channels[0] = (sender, std::thread::spawn(|| {}));
+            thread_channels[1].0.send(Box::new(|_: &PictoServer| panic!("Job panicked"))).unwrap();
+        }
+        let mut args = vec!["gets", "4", "4", "--"];
+        args.extend(paths.iter().map(String::as_str));
+        for size in ["4", "3"] {
+            args[1] = size;
+            args[2] = size;
+            let replies = run_commands(&state, &[&args]);
+            assert_eq!(replies.len(), 20);
+            assert!(replies.iter().all(|(status, _)| *status == STATUS_OK));
+        }
+        let thread_channels = state.thread_channels.read().unwrap();
+        assert!(thread_channels.iter().all(|(sender, handle)| sender.send(Box::new(|_: &PictoServer| {})).is_ok() && !handle.is_finished()));
+    }
+
+    #[test]
+    fn replies_with_the_progress_of_preloading_before_the_summary() {
+        let dir = test_dir("replies_with_the_progress_of_preloading_before_the_summary");
+        let state = set_up_state(&dir, &[]);
+        let mut paths : Vec<String> = (0..5).map(|i| write_bmp(&dir, &format!("{}.bmp", i), 8, 8, [0, i as u8, 0])).collect();
+        paths.push(dir.join("missing.bmp").to_str().unwrap().to_string());
+        let mut args = vec!["preload_progress", "4", "4", "--"];
+        args.extend(paths.iter().map(String::as_str));
+        let replies = run_commands(&state, &[&args]);
+        assert_eq!(replies.len(), 7);
+        assert!(replies.iter().all(|(status, _)| *status == STATUS_OK));
+        let mut progress_paths = Vec::new();
+        for (i, (_, progress)) in replies[..6].iter().enumerate() {
+            let progress = String::from_utf8(progress.clone()).unwrap();
+            let prefix = format!("{{\"processed\":{},\"total\":6,\"path\":\"", i + 1);
+            let path = progress.strip_prefix(&prefix).and_then(|path| path.strip_suffix("\"}")).unwrap_or_else(|| panic!("{}", progress));
+            progress_paths.push(path.to_string());
+        }
+        progress_paths.sort();
+        let mut sorted_paths = paths.clone();
+        sorted_paths.sort();
+        assert_eq!(progress_paths, sorted_paths);
+        assert_eq!(replies[6].1, b"{\"succeeded\":5,\"failed\":1}");
+        // Without progress only the summary is sent
+        args[0] = "preload";
+        assert_eq!(run_commands(&state, &[&args]), [(STATUS_OK, b"{\"succeeded\":5,\"failed\":1}".to_vec())]);
+    }
+
+    #[test]
+    fn gets_the_other_images_of_a_batch_with_a_missing_file() {
+        let dir = test_dir("gets_the_other_images_of_a_batch_with_a_missing_file");
+        let state = set_up_state(&dir, &[]);
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255]];
+        let mut paths : Vec<String> = colors.iter().enumerate().map(|(i, color)| write_bmp(&dir, &format!("{}.bmp", i), 8, 8, *color)).collect();
+        let missing_path = dir.join("missing.bmp").to_str().unwrap().to_string();
+        paths.insert(1, missing_path.clone());
+        let mut args = vec!["gets", "4", "4", "--"];
+        args.extend(paths.iter().map(String::as_str));
+        let replies = run_commands(&state, &[&args, &["ping"]]);
+        assert_eq!(replies.len(), 5);
+        assert_eq!(replies[1].0, STATUS_IMAGE_FAILED);
+        let message = String::from_utf8_lossy(&replies[1].1);
+        assert!(message.contains(&missing_path), "{}", message);
+        for (reply, color) in [&replies[0], &replies[2], &replies[3]].into_iter().zip(colors) {
+            assert_eq!(reply.0, STATUS_OK);
+            assert_eq!(image::load_from_memory(&reply.1).unwrap().to_rgb8().get_pixel(2, 2).0, color);
+        }
+        // The connection goes on
+        assert_eq!(replies[4].0, STATUS_OK);
+    }
+
+    #[test]
+    fn loads_a_path_on_the_same_thread_in_every_batch() {
+        let paths : Vec<String> = (0..1000).map(|i| format!("images/{}.png", i)).collect();
+        let paths : Vec<&str> = paths.iter().map(String::as_str).collect();
+        let threads : Vec<usize> = paths.iter().map(|path| thread_index(path, 4)).collect();
+        assert!(paths.iter().zip(&threads).all(|(path, thread)| thread_index(path, 4) == *thread));
+        for thread in 0..4 {
+            let count = threads.iter().filter(|path_thread| **path_thread == thread).count();
+            assert!((150..=350).contains(&count), "{} paths on thread {}", count, thread);
+        }
+        // The same path is loaded by the same thread, no matter which other paths are in the batch
+        for batch in [&paths[..], &paths[..10], &paths[500..520], &paths[7..8]] {
+            for (thread, _, thread_paths) in split_among_threads(batch, 4) {
+                for path in thread_paths {
+                    assert_eq!(thread, thread_index(path, 4), "{}", path);
+                }
+            }
         }
+        // Unless a thread got too many paths of a batch
+        let same_thread_paths : Vec<&str> = paths.iter().zip(&threads).filter(|(_, thread)| **thread == 0).map(|(path, _)| *path).take(8).collect();
+        let parts = split_among_threads(&same_thread_paths, 4);
+        assert!(parts.iter().all(|(_, indices, _)| indices.len() <= 4));
     }
 }
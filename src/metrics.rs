@@ -0,0 +1,151 @@
+//! Histograms of how long the steps of loading and sending images take, which can be read while the server runs
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Every power of two is split into this many buckets, so percentiles are at most 12.5% above the exact value
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+const BUCKET_COUNT: usize = ((64 - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS) as usize;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    /// Reading a local file or downloading an image
+    Read,
+    Decode,
+    /// Orienting, cropping, resizing and applying the other options
+    Process,
+    Encode,
+    /// Writing the replies of a command to the client
+    Send
+}
+
+impl Stage {
+    pub const ALL: [Stage; 5] = [Self::Read, Self::Decode, Self::Process, Self::Encode, Self::Send];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Decode => "decode",
+            Self::Process => "process",
+            Self::Encode => "encode",
+            Self::Send => "send"
+        }
+    }
+}
+
+/// Percentiles of the recorded durations in nanoseconds
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimingSummary {
+    pub count: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64
+}
+
+fn bucket_index(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS {return nanos as usize;}
+    let exponent = 63 - nanos.leading_zeros();
+    let sub_bucket = (nanos >> (exponent - SUB_BUCKET_BITS)) & (SUB_BUCKETS - 1);
+    ((exponent - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS + sub_bucket) as usize
+}
+
+/// The largest value, which is counted in the bucket
+fn bucket_upper_bound(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {return index;}
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let lower_bound = (SUB_BUCKETS + index % SUB_BUCKETS) << shift;
+    lower_bound + ((1 << shift) - 1)
+}
+
+/// Counts durations in buckets, which get wider the longer the durations are
+struct Histogram {
+    buckets: [AtomicU64; BUCKET_COUNT],
+    max: AtomicU64
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            max: AtomicU64::new(0)
+        }
+    }
+
+    fn record(&self, nanos: u64) {
+        self.buckets[bucket_index(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn summary(&self) -> TimingSummary {
+        let counts : Vec<_> = self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect();
+        // Recording might happen at the same time, so the count is summed from the same loads as the percentiles
+        let count : u64 = counts.iter().sum();
+        let max = self.max.load(Ordering::Relaxed);
+        let percentile = |percent: u64| {
+            if count == 0 {return 0;}
+            let rank = (count * percent).div_ceil(100);
+            let mut counted = 0;
+            for (index, bucket_count) in counts.iter().enumerate() {
+                counted += bucket_count;
+                if counted >= rank {
+                    return bucket_upper_bound(index).min(max);
+                }
+            }
+            max
+        };
+        TimingSummary {count, p50: percentile(50), p90: percentile(90), p99: percentile(99), max}
+    }
+}
+
+/// A histogram for every stage, shared by all threads
+pub struct Metrics {
+    histograms: [Histogram; Stage::ALL.len()]
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {histograms: std::array::from_fn(|_| Histogram::new())}
+    }
+}
+
+impl Metrics {
+    pub fn record(&self, stage: Stage, duration: Duration) {
+        self.histograms[stage as usize].record(duration.as_nanos().min(u64::MAX as u128) as u64);
+    }
+
+    pub fn summary(&self, stage: Stage) -> TimingSummary {
+        self.histograms[stage as usize].summary()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_every_duration_in_a_bucket_up_to_its_upper_bound() {
+        for nanos in (0..100_000).chain([u64::MAX / 3, u64::MAX]) {
+            let index = bucket_index(nanos);
+            assert!(index < BUCKET_COUNT);
+            assert!(nanos <= bucket_upper_bound(index), "{} in bucket {}", nanos, index);
+            assert!(index == 0 || nanos > bucket_upper_bound(index - 1), "{} in bucket {}", nanos, index);
+        }
+    }
+
+    #[test]
+    fn summarizes_durations_in_percentiles() {
+        let metrics = Metrics::default();
+        for micros in 1..=100 {
+            metrics.record(Stage::Decode, Duration::from_micros(micros));
+        }
+        let summary = metrics.summary(Stage::Decode);
+        assert_eq!((summary.count, summary.max), (100, 100_000));
+        for (percentile, nanos) in [(summary.p50, 50_000), (summary.p90, 90_000), (summary.p99, 99_000)] {
+            assert!(percentile >= nanos && percentile <= nanos + nanos / 8, "{} instead of {}", percentile, nanos);
+        }
+        assert_eq!(metrics.summary(Stage::Encode).count, 0);
+    }
+}
@@ -0,0 +1,827 @@
+//! Options of `setup` and of the image requests, parsed from `key=value` and flag arguments
+
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use anyhow::anyhow;
+use image::{ImageFormat, Rgb, Rgba};
+use image::imageops::FilterType;
+
+/// Images are cached on disk instead of in memory, once less than this many bytes of memory are available
+const DEFAULT_MIN_AVAILABLE_MEMORY : u64 = 2_000_000_000;
+const DEFAULT_JPEG_QUALITY: u8 = 75;
+const DEFAULT_MAX_PIXELS: u64 = 100_000_000;
+const DEFAULT_MAX_REDIRECTS: usize = 5;
+const MAX_BLUR_SIGMA: f32 = 100.0;
+const MAX_SHARPEN_SIGMA: f32 = 10.0;
+/// The `auto` filter resizes to at most this many pixels with the fast thumbnail filter, and to more with a better, but slower one
+const AUTO_THUMBNAIL_MAX_PIXELS: u64 = 512 * 512;
+const DEFAULT_BACKGROUND: Rgb<u8> = Rgb([u8::MAX, u8::MAX, u8::MAX]);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Bmp,
+    Png,
+    Jpeg,
+    #[cfg(feature = "webp")]
+    Webp
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bmp" => Some(Self::Bmp),
+            "png" => Some(Self::Png),
+            "jpg" | "jpeg" => Some(Self::Jpeg),
+            #[cfg(feature = "webp")]
+            "webp" => Some(Self::Webp),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Bmp => "bmp",
+            Self::Png => "png",
+            Self::Jpeg => "jpeg",
+            #[cfg(feature = "webp")]
+            Self::Webp => "webp"
+        }
+    }
+
+    pub fn image_format(&self) -> ImageFormat {
+        match self {
+            Self::Bmp => ImageFormat::Bmp,
+            Self::Png => ImageFormat::Png,
+            Self::Jpeg => ImageFormat::Jpeg,
+            #[cfg(feature = "webp")]
+            Self::Webp => ImageFormat::WebP
+        }
+    }
+
+    /// Whether the quality option changes how images of this format are encoded
+    pub fn is_lossy(&self) -> bool {
+        match self {
+            Self::Jpeg => true,
+            #[cfg(feature = "webp")]
+            Self::Webp => true,
+            Self::Bmp | Self::Png => false
+        }
+    }
+
+    /// Whether images of this format keep their transparency
+    pub fn has_alpha(&self) -> bool {
+        match self {
+            Self::Png => true,
+            #[cfg(feature = "webp")]
+            Self::Webp => true,
+            Self::Bmp | Self::Jpeg => false
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ResizeMode {
+    /// Resize to exactly the requested size, ignoring the aspect ratio
+    #[default]
+    Exact,
+    /// Resize to fit within the requested size, keeping the aspect ratio
+    Fit,
+    /// Resize to fill the requested size, keeping the aspect ratio by cropping the center
+    Cover
+}
+
+impl ResizeMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "exact" => Some(Self::Exact),
+            "fit" => Some(Self::Fit),
+            "cover" => Some(Self::Cover),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Exact => "exact",
+            Self::Fit => "fit",
+            Self::Cover => "cover"
+        }
+    }
+}
+
+/// The corner of the image a watermark is put into
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight
+}
+
+impl Corner {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "top_left" => Some(Self::TopLeft),
+            "top_right" => Some(Self::TopRight),
+            "bottom_left" => Some(Self::BottomLeft),
+            "bottom_right" => Some(Self::BottomRight),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::TopLeft => "top_left",
+            Self::TopRight => "top_right",
+            Self::BottomLeft => "bottom_left",
+            Self::BottomRight => "bottom_right"
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ResizeFilter {
+    /// `Thumbnail` for images up to `AUTO_THUMBNAIL_MAX_PIXELS`, `CatmullRom` for larger ones
+    #[default]
+    Auto,
+    /// The fast filter of `DynamicImage::thumbnail_exact`
+    Thumbnail,
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3
+}
+
+impl ResizeFilter {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(Self::Auto),
+            "thumbnail" => Some(Self::Thumbnail),
+            "nearest" => Some(Self::Nearest),
+            "triangle" => Some(Self::Triangle),
+            "catmull" => Some(Self::CatmullRom),
+            "gaussian" => Some(Self::Gaussian),
+            "lanczos3" => Some(Self::Lanczos3),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Thumbnail => "thumbnail",
+            Self::Nearest => "nearest",
+            Self::Triangle => "triangle",
+            Self::CatmullRom => "catmull",
+            Self::Gaussian => "gaussian",
+            Self::Lanczos3 => "lanczos3"
+        }
+    }
+
+    /// The filter `Auto` stands for at this size, the other filters are returned as they are
+    pub fn for_size(self, width: u32, height: u32) -> Self {
+        match self {
+            Self::Auto if width as u64 * height as u64 <= AUTO_THUMBNAIL_MAX_PIXELS => Self::Thumbnail,
+            Self::Auto => Self::CatmullRom,
+            filter => filter
+        }
+    }
+
+    /// `None` for the thumbnail filter, `Auto` has to be resolved with `for_size` first
+    pub fn filter_type(&self) -> Option<FilterType> {
+        match self {
+            Self::Auto | Self::Thumbnail => None,
+            Self::Nearest => Some(FilterType::Nearest),
+            Self::Triangle => Some(FilterType::Triangle),
+            Self::CatmullRom => Some(FilterType::CatmullRom),
+            Self::Gaussian => Some(FilterType::Gaussian),
+            Self::Lanczos3 => Some(FilterType::Lanczos3)
+        }
+    }
+}
+
+/// A region of the source image, cropped before resizing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CropRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32
+}
+
+impl CropRect {
+    /// Parses `x,y,width,height`
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let values = value.split(',').map(|v| parse_value("crop", v)).collect::<anyhow::Result<Vec<u32>>>()?;
+        let [x, y, width, height] = values[..] else {return Err(anyhow!("Crop has to be x,y,width,height, got {}", value))};
+        if width == 0 || height == 0 {
+            return Err(anyhow!("Crop has to be at least 1x1, got {}x{}", width, height));
+        }
+        Ok(Self {x, y, width, height})
+    }
+}
+
+impl std::fmt::Display for CropRect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+/// An unsharp mask applied after resizing, only differences above `threshold` are sharpened
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sharpen {
+    pub sigma: f32,
+    pub threshold: i32
+}
+
+impl Sharpen {
+    /// Parses `sigma,threshold`
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let Some((sigma, threshold)) = value.split_once(',') else {return Err(anyhow!("Sharpen has to be sigma,threshold, got {}", value))};
+        let sigma : f32 = parse_value("sharpen sigma", sigma)?;
+        let threshold : i32 = parse_value("sharpen threshold", threshold)?;
+        // Also rejects NaN
+        if !(sigma > 0.0 && sigma <= MAX_SHARPEN_SIGMA) {
+            return Err(anyhow!("Sharpen sigma has to be above 0 and at most {}, got {}", MAX_SHARPEN_SIGMA, sigma));
+        }
+        if !(0..=255).contains(&threshold) {
+            return Err(anyhow!("Sharpen threshold has to be between 0 and 255, got {}", threshold));
+        }
+        Ok(Self {sigma, threshold})
+    }
+}
+
+impl std::fmt::Display for Sharpen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{}", self.sigma, self.threshold)
+    }
+}
+
+/// Makes the resized image transparent outside of a shape, for avatars
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mask {
+    /// The largest circle, or ellipse for images that aren't square, which fits into the image
+    Circle,
+    /// Rounded corners with this radius in pixels
+    Rounded(u32)
+}
+
+impl Mask {
+    /// Parses `circle` or `rounded,radius`
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        if value == "circle" {
+            return Ok(Self::Circle);
+        }
+        let Some(("rounded", radius)) = value.split_once(',') else {return Err(anyhow!("Mask has to be circle or rounded,radius, got {}", value))};
+        match parse_value("mask radius", radius)? {
+            0 => Err(anyhow!("Mask radius has to be at least 1")),
+            radius => Ok(Self::Rounded(radius))
+        }
+    }
+}
+
+impl std::fmt::Display for Mask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Circle => write!(f, "circle"),
+            Self::Rounded(radius) => write!(f, "rounded,{}", radius)
+        }
+    }
+}
+
+/// Blends every pixel toward `color` after resizing, by `strength` from 0 (unchanged) to 1 (only the color)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tint {
+    pub color: Rgb<u8>,
+    pub strength: f32
+}
+
+impl Tint {
+    /// What `sepia` tints grayscale images with
+    pub const SEPIA: Tint = Tint {color: Rgb([112, 66, 20]), strength: 0.4};
+
+    /// Parses `RRGGBB,strength`
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let Some((color, strength)) = value.split_once(',') else {return Err(anyhow!("Tint has to be RRGGBB,strength, got {}", value))};
+        let Rgba([red, green, blue, alpha]) = parse_color(color)?;
+        if alpha != u8::MAX {
+            return Err(anyhow!("Tint color has to be opaque, got {}", color));
+        }
+        let strength : f32 = parse_value("tint strength", strength)?;
+        // Also rejects NaN
+        if !(0.0..=1.0).contains(&strength) {
+            return Err(anyhow!("Tint strength has to be between 0 and 1, got {}", strength));
+        }
+        Ok(Self {color: Rgb([red, green, blue]), strength})
+    }
+}
+
+impl std::fmt::Display for Tint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Rgb([red, green, blue]) = self.color;
+        write!(f, "{:02x}{:02x}{:02x},{}", red, green, blue, self.strength)
+    }
+}
+
+/// Parses a `RRGGBB` or `RRGGBBAA` hex color, optionally starting with `#`
+fn parse_color(value : &str) -> anyhow::Result<Rgba<u8>> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("Color has to be RRGGBB or RRGGBBAA in hex, got {}", value));
+    }
+    let channel = |i : usize| u8::from_str_radix(hex.get(i * 2..i * 2 + 2).unwrap_or("ff"), 16).unwrap();
+    Ok(Rgba([channel(0), channel(1), channel(2), channel(3)]))
+}
+
+pub fn parse_value<T: FromStr>(name : &str, value : &str) -> anyhow::Result<T> {
+    value.parse::<T>().map_err(|_| anyhow!("Invalid {} : {}", name, value))
+}
+
+fn parse_seconds(name : &str, value : &str) -> anyhow::Result<Duration> {
+    Duration::try_from_secs_f64(parse_value(name, value)?).map_err(|_| anyhow!("Invalid {} : {}", name, value))
+}
+
+/// Optional `key=value` arguments of `setup`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetupOptions {
+    /// How many threads are used to load the images of a `gets` request
+    pub thread_count: usize,
+    /// How many idle connections are kept open per host, defaults to the thread count
+    pub http_pool_size: Option<usize>,
+    /// How long idle connections are kept open
+    pub http_idle_timeout: Duration,
+    /// How long fetching a single image may take
+    pub http_timeout: Duration,
+    /// How long images are used without asking their server whether they changed, if it doesn't tell. Forever if `None`
+    pub http_max_age: Option<Duration>,
+    /// How many redirects are followed when fetching an image
+    pub max_redirects: usize,
+    /// How many bytes of images may be cached in memory
+    pub max_memory_bytes: usize,
+    pub read_root: Option<PathBuf>,
+    /// Images with more pixels are rejected without decoding them
+    pub max_pixels: u64,
+    /// Encoded images with more bytes are rejected instead of being cached and sent
+    pub max_output_bytes: usize,
+    /// How many bytes of images of a `gets` request may be loaded, but not sent yet
+    pub max_in_flight_bytes: usize,
+    /// Below this many bytes of available memory images are cached on disk
+    pub min_available_memory: u64,
+    /// Compress the images cached in memory, so more of them fit into memory
+    pub compress_memory: bool,
+    /// Also cache the decoded source images, so other sizes and formats of them don't have to decode them again
+    pub cache_decoded: bool,
+    /// Never cache images on disk, nothing is written to the cache dir
+    pub memory_only: bool,
+    /// How long requests of a path, which failed to load, fail without loading it again. Zero disables this
+    pub failure_ttl: Duration,
+    /// Only images from these hosts are fetched. If empty, only hosts with public addresses are
+    pub allowed_hosts: Vec<String>,
+    /// How many commands per second every connection may send, unlimited if `None`
+    pub rate_limit: Option<f64>,
+    /// How many commands a connection may send at once, defaults to the rate limit
+    pub rate_burst: Option<u32>,
+    /// The image put onto images requested with the `watermark` option, without a path
+    pub watermark: Option<PathBuf>
+}
+
+impl Default for SetupOptions {
+    fn default() -> Self {
+        Self {
+            thread_count: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            http_pool_size: None,
+            http_idle_timeout: Duration::from_secs(90),
+            http_timeout: Duration::from_secs(10),
+            http_max_age: None,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            max_memory_bytes: usize::MAX,
+            read_root: None,
+            max_pixels: DEFAULT_MAX_PIXELS,
+            max_output_bytes: usize::MAX,
+            max_in_flight_bytes: usize::MAX,
+            min_available_memory: DEFAULT_MIN_AVAILABLE_MEMORY,
+            compress_memory: false,
+            cache_decoded: false,
+            memory_only: false,
+            failure_ttl: Duration::from_secs(5),
+            allowed_hosts: Vec::new(),
+            rate_limit: None,
+            rate_burst: None,
+            watermark: None
+        }
+    }
+}
+
+impl SetupOptions {
+    pub fn parse(args: &[&str]) -> anyhow::Result<Self> {
+        let mut options = Self::default();
+        for arg in args {
+            let (key, value) = arg.split_once('=').ok_or(anyhow!("Invalid setup option : {}", arg))?;
+            match key {
+                "threads" => {
+                    options.thread_count = parse_value("thread count", value)?;
+                    if options.thread_count == 0 {
+                        return Err(anyhow!("Thread count has to be at least 1"));
+                    }
+                },
+                "http_pool_size" => options.http_pool_size = Some(parse_value("http pool size", value)?),
+                "http_idle_timeout" => options.http_idle_timeout = parse_seconds("http idle timeout", value)?,
+                "http_timeout" => options.http_timeout = parse_seconds("http timeout", value)?,
+                "http_max_age" => options.http_max_age = Some(parse_seconds("http max age", value)?),
+                "max_redirects" => options.max_redirects = parse_value("max redirects", value)?,
+                "max_memory" => options.max_memory_bytes = parse_value("max memory", value)?,
+                "root" => options.read_root = Some(PathBuf::from(value)),
+                "max_pixels" => options.max_pixels = parse_value("max pixels", value)?,
+                "max_output_bytes" => options.max_output_bytes = parse_value("max output bytes", value)?,
+                "max_in_flight_bytes" => options.max_in_flight_bytes = parse_value("max in flight bytes", value)?,
+                "min_available_memory" => options.min_available_memory = parse_value("min available memory", value)?,
+                "cache_decoded" => options.cache_decoded = parse_value("cache decoded", value)?,
+                "compress_memory" => options.compress_memory = parse_value("compress memory", value)?,
+                "memory_only" => options.memory_only = parse_value("memory only", value)?,
+                "failure_ttl" => options.failure_ttl = parse_seconds("failure ttl", value)?,
+                "allowed_hosts" => options.allowed_hosts = value.split(',')
+                    .map(str::trim)
+                    .filter(|host| !host.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                "rate_limit" => {
+                    let rate_limit : f64 = parse_value("rate limit", value)?;
+                    if !rate_limit.is_finite() || rate_limit <= 0.0 {
+                        return Err(anyhow!("Invalid rate limit : {}, has to be above 0", value));
+                    }
+                    options.rate_limit = Some(rate_limit);
+                },
+                "watermark" => options.watermark = Some(PathBuf::from(value)),
+                "rate_burst" => {
+                    options.rate_burst = Some(parse_value("rate burst", value)?);
+                    if options.rate_burst == Some(0) {
+                        return Err(anyhow!("Rate burst has to be at least 1"));
+                    }
+                },
+                _ => return Err(anyhow!("Unknown setup option : {}", key))
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Options that change how an image is processed, sent as optional arguments of `get` and `gets`
+#[derive(Clone, Debug)]
+pub struct ImageOptions {
+    pub format: OutputFormat,
+    /// JPEG and WebP quality from 1 to 100
+    pub quality: u8,
+    pub resize_mode: ResizeMode,
+    pub filter: ResizeFilter,
+    /// Never resize to a larger size than the source image
+    pub no_upscale: bool,
+    pub crop: Option<CropRect>,
+    /// Don't rotate images with an EXIF orientation
+    pub ignore_orientation: bool,
+    /// Mirror the source image horizontally, after orienting it
+    pub flip_h: bool,
+    /// Mirror the source image vertically, after orienting it
+    pub flip_v: bool,
+    /// Degrees from 0 to 360, by which the source image is rotated clockwise after flipping it
+    pub rotate: f32,
+    pub grayscale: bool,
+    /// Added to every color channel after resizing
+    pub brightness: i32,
+    /// Contrast change in percent after resizing, negative values reduce the contrast
+    pub contrast: f32,
+    /// Above 1 brightens the dark colors of the resized image, below 1 darkens them
+    pub gamma: f32,
+    /// Degrees from 0 to 360, by which the hue of every color is rotated after resizing
+    pub hue_rotate: f32,
+    /// Saturation in percent of the original after resizing, 0 is grayscale
+    pub saturation: f32,
+    pub tint: Option<Tint>,
+    /// Turn the resized image into shades of brown, like an old photo. Applied before `tint`
+    pub sepia: bool,
+    pub sharpen: Option<Sharpen>,
+    /// Sigma of the gaussian blur applied after resizing
+    pub blur: Option<f32>,
+    /// Background color, the resized image is centered on to fill the requested size
+    pub pad: Option<Rgba<u8>>,
+    /// The path of an image put onto the resized image, empty for the watermark set up with `setup`
+    pub watermark: Option<String>,
+    pub watermark_corner: Corner,
+    /// From 0 (invisible) to 1 (as opaque as the watermark image)
+    pub watermark_opacity: f32,
+    /// Pixels between the watermark and the edges of the image
+    pub watermark_margin: u32,
+    /// Applied last, only to formats with transparency
+    pub mask: Option<Mask>,
+    /// Transparent images are put on this color, if the format has no transparency
+    pub background: Rgb<u8>,
+    /// The index of the frame of animated GIFs
+    pub frame: u32,
+    /// Reduce BMP and PNG images to a palette of at most this many colors
+    pub colors: Option<u16>
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::default(),
+            quality: DEFAULT_JPEG_QUALITY,
+            resize_mode: ResizeMode::default(),
+            filter: ResizeFilter::default(),
+            no_upscale: false,
+            crop: None,
+            ignore_orientation: false,
+            flip_h: false,
+            flip_v: false,
+            rotate: 0.0,
+            grayscale: false,
+            brightness: 0,
+            contrast: 0.0,
+            gamma: 1.0,
+            hue_rotate: 0.0,
+            saturation: 100.0,
+            tint: None,
+            sepia: false,
+            sharpen: None,
+            blur: None,
+            pad: None,
+            watermark: None,
+            watermark_corner: Corner::default(),
+            watermark_opacity: 1.0,
+            watermark_margin: 0,
+            mask: None,
+            background: DEFAULT_BACKGROUND,
+            frame: 0,
+            colors: None
+        }
+    }
+}
+
+impl ImageOptions {
+    /// Applies a single argument to the options, returns false if the argument is not an option
+    pub fn parse_arg(&mut self, arg: &str) -> anyhow::Result<bool> {
+        if let Some(format) = OutputFormat::from_name(arg) {
+            self.format = format;
+            return Ok(true);
+        }
+        if arg == "no_upscale" {
+            self.no_upscale = true;
+            return Ok(true);
+        }
+        if arg == "ignore_orientation" {
+            self.ignore_orientation = true;
+            return Ok(true);
+        }
+        if arg == "flip_h" {
+            self.flip_h = true;
+            return Ok(true);
+        }
+        if arg == "flip_v" {
+            self.flip_v = true;
+            return Ok(true);
+        }
+        if arg == "grayscale" {
+            self.grayscale = true;
+            return Ok(true);
+        }
+        if arg == "sepia" {
+            self.sepia = true;
+            return Ok(true);
+        }
+        if arg == "watermark" {
+            self.watermark = Some(String::new());
+            return Ok(true);
+        }
+        let Some((key, value)) = arg.split_once('=') else {return Ok(false)};
+        match key {
+            "quality" => {
+                let quality : u8 = parse_value("quality", value)?;
+                if !(1..=100).contains(&quality) {
+                    return Err(anyhow!("Quality has to be between 1 and 100, got {}", quality));
+                }
+                self.quality = quality;
+            },
+            "resize" => self.resize_mode = ResizeMode::from_name(value).ok_or(anyhow!("Unknown resize mode : {}", value))?,
+            "filter" => self.filter = ResizeFilter::from_name(value).ok_or(anyhow!("Unknown filter : {}", value))?,
+            "crop" => self.crop = Some(CropRect::parse(value)?),
+            "pad" => self.pad = Some(parse_color(value)?),
+            "mask" => self.mask = Some(Mask::parse(value)?),
+            "watermark" => {
+                if value.is_empty() {
+                    return Err(anyhow!("Missing watermark path"));
+                }
+                self.watermark = Some(value.to_string());
+            },
+            "watermark_corner" => self.watermark_corner = Corner::from_name(value).ok_or(anyhow!("Unknown watermark corner : {}", value))?,
+            "watermark_opacity" => {
+                let opacity : f32 = parse_value("watermark opacity", value)?;
+                // Also rejects NaN
+                if !(0.0..=1.0).contains(&opacity) {
+                    return Err(anyhow!("Watermark opacity has to be between 0 and 1, got {}", value));
+                }
+                self.watermark_opacity = opacity;
+            },
+            "watermark_margin" => self.watermark_margin = parse_value("watermark margin", value)?,
+            "frame" => self.frame = parse_value("frame", value)?,
+            "colors" => {
+                let colors : u16 = parse_value("colors", value)?;
+                if !(2..=256).contains(&colors) {
+                    return Err(anyhow!("Colors have to be between 2 and 256, got {}", colors));
+                }
+                self.colors = Some(colors);
+            },
+            "background" => {
+                let Rgba([red, green, blue, alpha]) = parse_color(value)?;
+                if alpha != u8::MAX {
+                    return Err(anyhow!("Background has to be opaque, got {}", value));
+                }
+                self.background = Rgb([red, green, blue]);
+            },
+            "brightness" => {
+                let brightness : i32 = parse_value("brightness", value)?;
+                if !(-255..=255).contains(&brightness) {
+                    return Err(anyhow!("Brightness has to be between -255 and 255, got {}", brightness));
+                }
+                self.brightness = brightness;
+            },
+            "contrast" => {
+                let contrast : f32 = parse_value("contrast", value)?;
+                // Also rejects NaN
+                if !(-100.0..=100.0).contains(&contrast) {
+                    return Err(anyhow!("Contrast has to be between -100 and 100, got {}", value));
+                }
+                self.contrast = contrast;
+            },
+            "gamma" => {
+                let gamma : f32 = parse_value("gamma", value)?;
+                if !(0.1..=10.0).contains(&gamma) {
+                    return Err(anyhow!("Gamma has to be between 0.1 and 10, got {}", value));
+                }
+                self.gamma = gamma;
+            },
+            "hue_rotate" => {
+                let degrees : f32 = parse_value("hue rotate", value)?;
+                if !degrees.is_finite() {
+                    return Err(anyhow!("Hue rotate has to be a number of degrees, got {}", value));
+                }
+                self.hue_rotate = degrees.rem_euclid(360.0);
+            },
+            "saturation" => {
+                let saturation : f32 = parse_value("saturation", value)?;
+                if !(0.0..=500.0).contains(&saturation) {
+                    return Err(anyhow!("Saturation has to be between 0 and 500, got {}", value));
+                }
+                self.saturation = saturation;
+            },
+            "rotate" => {
+                let degrees : f32 = parse_value("rotate", value)?;
+                if !degrees.is_finite() {
+                    return Err(anyhow!("Rotate has to be a number of degrees, got {}", value));
+                }
+                // Every angle is sent the same way, so equal rotations share a cache key
+                self.rotate = degrees.rem_euclid(360.0);
+            },
+            "tint" => self.tint = Some(Tint::parse(value)?),
+            "sharpen" => self.sharpen = Some(Sharpen::parse(value)?),
+            "blur" => {
+                let sigma : f32 = parse_value("blur", value)?;
+                // Also rejects NaN
+                if !(sigma > 0.0 && sigma <= MAX_BLUR_SIGMA) {
+                    return Err(anyhow!("Blur has to be above 0 and at most {}, got {}", MAX_BLUR_SIGMA, value));
+                }
+                self.blur = Some(sigma);
+            },
+            _ => return Ok(false)
+        }
+        Ok(true)
+    }
+
+    /// Parses options from the start of the arguments, returns the options and how many arguments were options
+    pub fn parse(args: &[&str]) -> anyhow::Result<(Self, usize)> {
+        let mut options = Self::default();
+        let mut options_count = 0;
+        for arg in args {
+            if !options.parse_arg(arg)? {break;}
+            options_count += 1;
+        }
+        // The transparent corners of masks would be put on the background with formats without transparency
+        if options.mask.is_some() && !options.format.has_alpha() {
+            options.format = OutputFormat::Png;
+        }
+        Ok((options, options_count))
+    }
+
+    /// The options as arguments of `get` and `gets`, leaving out those with default values
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![self.format.name().to_string()];
+        if self.format.is_lossy() {
+            args.push(format!("quality={}", self.quality));
+        }
+        if self.resize_mode != ResizeMode::Exact {
+            args.push(format!("resize={}", self.resize_mode.name()));
+        }
+        if self.filter != ResizeFilter::Auto {
+            args.push(format!("filter={}", self.filter.name()));
+        }
+        if self.no_upscale {
+            args.push("no_upscale".to_string());
+        }
+        if let Some(crop) = &self.crop {
+            args.push(format!("crop={}", crop));
+        }
+        if self.ignore_orientation {
+            args.push("ignore_orientation".to_string());
+        }
+        if self.flip_h {
+            args.push("flip_h".to_string());
+        }
+        if self.flip_v {
+            args.push("flip_v".to_string());
+        }
+        if self.rotate != 0.0 {
+            args.push(format!("rotate={}", self.rotate));
+        }
+        if self.grayscale {
+            args.push("grayscale".to_string());
+        }
+        if self.brightness != 0 {
+            args.push(format!("brightness={}", self.brightness));
+        }
+        if self.contrast != 0.0 {
+            args.push(format!("contrast={}", self.contrast));
+        }
+        if self.gamma != 1.0 {
+            args.push(format!("gamma={}", self.gamma));
+        }
+        if self.hue_rotate != 0.0 {
+            args.push(format!("hue_rotate={}", self.hue_rotate));
+        }
+        if self.saturation != 100.0 {
+            args.push(format!("saturation={}", self.saturation));
+        }
+        if self.sepia {
+            args.push("sepia".to_string());
+        }
+        if let Some(tint) = &self.tint {
+            args.push(format!("tint={}", tint));
+        }
+        if let Some(sharpen) = &self.sharpen {
+            args.push(format!("sharpen={}", sharpen));
+        }
+        if let Some(sigma) = self.blur {
+            args.push(format!("blur={}", sigma));
+        }
+        if let Some(Rgba([red, green, blue, alpha])) = self.pad {
+            args.push(format!("pad={:02x}{:02x}{:02x}{:02x}", red, green, blue, alpha));
+        }
+        match self.watermark.as_deref() {
+            Some("") => args.push("watermark".to_string()),
+            Some(path) => args.push(format!("watermark={}", path)),
+            None => {}
+        }
+        // Without a watermark, its placement changes nothing
+        if self.watermark.is_some() {
+            if self.watermark_corner != Corner::default() {
+                args.push(format!("watermark_corner={}", self.watermark_corner.name()));
+            }
+            if self.watermark_opacity != 1.0 {
+                args.push(format!("watermark_opacity={}", self.watermark_opacity));
+            }
+            if self.watermark_margin != 0 {
+                args.push(format!("watermark_margin={}", self.watermark_margin));
+            }
+        }
+        if let Some(mask) = &self.mask {
+            args.push(format!("mask={}", mask));
+        }
+        if let Some(colors) = self.colors {
+            args.push(format!("colors={}", colors));
+        }
+        if self.frame != 0 {
+            args.push(format!("frame={}", self.frame));
+        }
+        if self.background != DEFAULT_BACKGROUND {
+            let Rgb([red, green, blue]) = self.background;
+            args.push(format!("background={:02x}{:02x}{:02x}", red, green, blue));
+        }
+        args
+    }
+
+    /// Whether an image, which already has the requested size, is changed by more than encoding it
+    pub fn changes_pixels(&self) -> bool {
+        self.crop.is_some() || self.flip_h || self.flip_v || self.rotate != 0.0 || self.grayscale
+            || self.brightness != 0 || self.contrast != 0.0 || self.gamma != 1.0 || self.hue_rotate != 0.0 || self.saturation != 100.0 || self.tint.is_some() || self.sepia || self.sharpen.is_some() || self.blur.is_some()
+            || self.pad.is_some() || self.watermark.is_some() || self.mask.is_some() || self.frame != 0 || self.colors.is_some()
+    }
+}
+
+/// The arguments joined by `,`. Values like the path of `watermark` can contain `,`, which is escaped, so other options never join to the same string
+impl std::fmt::Display for ImageOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let args : Vec<String> = self.to_args().iter().map(|arg| arg.replace('%', "%25").replace(',', "%2C")).collect();
+        write!(f, "{}", args.join(","))
+    }
+}
@@ -0,0 +1,153 @@
+//! Reduces images to a palette of few colors and encodes them as indexed BMP or PNG images, which are much smaller
+
+use std::collections::HashMap;
+use anyhow::anyhow;
+use color_quant::NeuQuant;
+use image::RgbaImage;
+
+/// Every this many pixels is sampled for the palette, lower is slower but more accurate
+const SAMPLE_FACTOR: i32 = 10;
+const BMP_HEADER_SIZE: u32 = 14 + 40;
+
+/// A palette of RGBA colors and the palette index of every pixel
+struct Indexed {
+    palette: Vec<[u8; 4]>,
+    indices: Vec<u8>
+}
+
+/// Images, which already have few enough colors, keep them exactly
+fn exact_palette(img: &RgbaImage, max_colors: usize) -> Option<Indexed> {
+    let mut color_indices = HashMap::new();
+    let mut indices = Vec::with_capacity(img.as_raw().len() / 4);
+    for pixel in img.pixels() {
+        let next_index = color_indices.len();
+        let index = *color_indices.entry(pixel.0).or_insert(next_index);
+        if index >= max_colors {return None;}
+        indices.push(index as u8);
+    }
+    let mut palette = vec![[0; 4]; color_indices.len()];
+    for (color, index) in color_indices {
+        palette[index] = color;
+    }
+    Some(Indexed {palette, indices})
+}
+
+fn quantize(img: &RgbaImage, max_colors: usize) -> Indexed {
+    if let Some(indexed) = exact_palette(img, max_colors) {
+        return indexed;
+    }
+    let quantizer = NeuQuant::new(SAMPLE_FACTOR, max_colors, img.as_raw());
+    // The alpha of the colors doesn't always converge to opaque, especially with few pixels
+    let is_opaque = img.pixels().all(|pixel| pixel.0[3] == u8::MAX);
+    let palette = quantizer.color_map_rgba().chunks_exact(4)
+        .map(|color| [color[0], color[1], color[2], if is_opaque {u8::MAX} else {color[3]}])
+        .collect();
+    let indices = img.as_raw().chunks_exact(4).map(|pixel| quantizer.index_of(pixel) as u8).collect();
+    Indexed {palette, indices}
+}
+
+/// An 8 bit BMP with a palette of at most 256 colors, BMP images have no transparency
+pub fn encode_bmp(img: &RgbaImage, max_colors: usize) -> Vec<u8> {
+    let Indexed {palette, indices} = quantize(img, max_colors);
+    let (width, height) = img.dimensions();
+    // Rows are padded to multiples of 4 bytes
+    let row_size = width.div_ceil(4) * 4;
+    let data_offset = BMP_HEADER_SIZE + palette.len() as u32 * 4;
+    let file_size = data_offset + row_size * height;
+    let mut bmp_bytes = Vec::with_capacity(file_size as usize);
+    bmp_bytes.extend_from_slice(b"BM");
+    bmp_bytes.extend_from_slice(&file_size.to_le_bytes());
+    bmp_bytes.extend_from_slice(&[0; 4]);
+    bmp_bytes.extend_from_slice(&data_offset.to_le_bytes());
+    bmp_bytes.extend_from_slice(&40u32.to_le_bytes());
+    bmp_bytes.extend_from_slice(&(width as i32).to_le_bytes());
+    bmp_bytes.extend_from_slice(&(height as i32).to_le_bytes());
+    bmp_bytes.extend_from_slice(&1u16.to_le_bytes()); // Planes
+    bmp_bytes.extend_from_slice(&8u16.to_le_bytes()); // Bits per pixel
+    bmp_bytes.extend_from_slice(&0u32.to_le_bytes()); // Uncompressed
+    bmp_bytes.extend_from_slice(&(row_size * height).to_le_bytes());
+    bmp_bytes.extend_from_slice(&[0; 8]); // Resolution
+    bmp_bytes.extend_from_slice(&(palette.len() as u32).to_le_bytes()); // Colors used
+    bmp_bytes.extend_from_slice(&0u32.to_le_bytes()); // Important colors
+    for [red, green, blue, _] in &palette {
+        bmp_bytes.extend_from_slice(&[*blue, *green, *red, 0]);
+    }
+    // The rows are stored from the bottom up
+    for row in indices.chunks_exact(width as usize).rev() {
+        bmp_bytes.extend_from_slice(row);
+        bmp_bytes.resize(bmp_bytes.len() + (row_size - width) as usize, 0);
+    }
+    bmp_bytes
+}
+
+/// An indexed PNG with a palette of at most 256 colors, which keeps transparency
+pub fn encode_png(img: &RgbaImage, max_colors: usize) -> anyhow::Result<Vec<u8>> {
+    let Indexed {palette, indices} = quantize(img, max_colors);
+    let mut png_bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png_bytes, img.width(), img.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(palette.iter().flat_map(|[red, green, blue, _]| [*red, *green, *blue]).collect());
+    if palette.iter().any(|color| color[3] != u8::MAX) {
+        encoder.set_trns(palette.iter().map(|color| color[3]).collect());
+    }
+    let mut writer = encoder.write_header().map_err(|e| anyhow!("Cannot encode PNG : {}", e))?;
+    writer.write_image_data(&indices).map_err(|e| anyhow!("Cannot encode PNG : {}", e))?;
+    std::mem::drop(writer);
+    Ok(png_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use image::{DynamicImage, ImageFormat, Rgba};
+    use crate::ImageOptions;
+    use crate::test_util::*;
+    use super::*;
+
+    fn gradient_image() -> RgbaImage {
+        RgbaImage::from_fn(64, 64, |x, y| Rgba([x as u8 * 4, y as u8 * 4, 128, u8::MAX]))
+    }
+
+    #[test]
+    fn reduces_a_gradient_to_an_indexed_bmp_of_the_palette_size() {
+        let img = gradient_image();
+        let bmp_bytes = encode_bmp(&img, 16);
+        assert_eq!(u16::from_le_bytes([bmp_bytes[28], bmp_bytes[29]]), 8);
+        let colors_used = u32::from_le_bytes(bmp_bytes[46..50].try_into().unwrap());
+        assert!((2..=16).contains(&colors_used), "{} colors", colors_used);
+        let full_bmp_bytes = encode(&DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(img).to_rgb8()), ImageFormat::Bmp);
+        assert!(bmp_bytes.len() * 2 < full_bmp_bytes.len(), "{} >= {}", bmp_bytes.len(), full_bmp_bytes.len());
+        let decoded = decode(&bmp_bytes).to_rgba8();
+        assert_eq!(decoded.dimensions(), (64, 64));
+        assert!(decoded.pixels().map(|pixel| pixel.0).collect::<HashSet<_>>().len() <= 16);
+    }
+
+    #[test]
+    fn reduces_a_gradient_to_an_indexed_png_of_the_palette_size() {
+        let png_bytes = encode_png(&gradient_image(), 16).unwrap();
+        let (_, reader) = png::Decoder::new(png_bytes.as_slice()).read_info().unwrap();
+        assert_eq!(reader.info().color_type, png::ColorType::Indexed);
+        let palette_size = reader.info().palette.as_ref().unwrap().len() / 3;
+        assert!((2..=16).contains(&palette_size), "{} colors", palette_size);
+        // The image is opaque
+        assert!(reader.info().trns.is_none(), "{:?}", reader.info().trns);
+        assert!(decode(&png_bytes).to_rgba8().pixels().all(|pixel| pixel.0[3] == u8::MAX));
+    }
+
+    #[test]
+    fn keeps_the_colors_of_images_with_few_colors() {
+        let img = RgbaImage::from_fn(5, 3, |x, _| [Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 128])][x as usize % 2]);
+        let decoded = decode(&encode_png(&img, 16).unwrap()).to_rgba8();
+        assert_eq!(decoded, img);
+    }
+
+    #[test]
+    fn accepts_only_palettes_of_2_to_256_colors() {
+        for colors in ["colors=1", "colors=257"] {
+            assert!(ImageOptions::parse(&[colors]).is_err(), "{}", colors);
+        }
+        // Each is cached separately
+        assert_ne!(image_options(&["colors=2"]).to_string(), image_options(&["colors=256"]).to_string());
+    }
+}
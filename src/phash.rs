@@ -0,0 +1,46 @@
+//! DCT based perceptual hash, similar images have hashes with a small hamming distance
+
+use image::GrayImage;
+
+/// The image is scaled to this size before the DCT
+pub const SIZE: usize = 32;
+/// Only the lowest frequencies are kept, giving a 64 bit hash
+const HASH_SIZE: usize = 8;
+
+/// The first `HASH_SIZE` coefficients of the one dimensional DCT-II of `values`
+fn dct(values: &[f64], cos_table: &[[f64; SIZE]; HASH_SIZE]) -> [f64; HASH_SIZE] {
+    let mut coefficients = [0.0; HASH_SIZE];
+    for (k, coefficient) in coefficients.iter_mut().enumerate() {
+        *coefficient = values.iter().zip(cos_table[k].iter()).map(|(value, cos)| value * cos).sum();
+    }
+    coefficients
+}
+
+/// Hashes a `SIZE`x`SIZE` grayscale image
+pub fn phash(img: &GrayImage) -> u64 {
+    assert_eq!((img.width() as usize, img.height() as usize), (SIZE, SIZE));
+    let mut cos_table = [[0.0; SIZE]; HASH_SIZE];
+    for (k, row) in cos_table.iter_mut().enumerate() {
+        for (n, cos) in row.iter_mut().enumerate() {
+            *cos = (std::f64::consts::PI / SIZE as f64 * (n as f64 + 0.5) * k as f64).cos();
+        }
+    }
+    // The DCT is separable, so transform the rows first, then the columns of the result
+    let rows : Vec<[f64; HASH_SIZE]> = img.rows()
+        .map(|row| dct(&row.map(|pixel| pixel[0] as f64).collect::<Vec<_>>(), &cos_table))
+        .collect();
+    let mut low_frequencies = [0.0; HASH_SIZE * HASH_SIZE];
+    for x in 0..HASH_SIZE {
+        let column : Vec<f64> = rows.iter().map(|row| row[x]).collect();
+        for (y, coefficient) in dct(&column, &cos_table).into_iter().enumerate() {
+            low_frequencies[y * HASH_SIZE + x] = coefficient;
+        }
+    }
+    // The first coefficient is the average brightness, which would skew the median
+    let mut sorted = low_frequencies[1..].to_vec();
+    sorted.sort_unstable_by(|a, b| a.total_cmp(b));
+    let median = sorted[sorted.len() / 2];
+    low_frequencies.iter().enumerate()
+        .filter(|(_, coefficient)| **coefficient > median)
+        .fold(0, |hash, (i, _)| hash | 1 << i)
+}
@@ -0,0 +1,965 @@
+//! Turns decoded source images into the requested images and computes information from them
+
+use anyhow::anyhow;
+use image::{GenericImageView, DynamicImage, Rgb, RgbImage, Rgba, RgbaImage};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{self, FilterType};
+use crate::{color, exif, palette, phash};
+use crate::options::{Corner, CropRect, ImageOptions, Mask, OutputFormat, ResizeMode, Sharpen, Tint};
+
+/// Images are downscaled to fit within this size, before computing their blurhash
+const BLURHASH_IMAGE_SIZE: u32 = 64;
+/// Images are downscaled to this size, before finding their dominant colors
+const DOMINANT_COLOR_IMAGE_SIZE: u32 = 32;
+
+/// Returns the largest size with the aspect ratio of the source, that fits within the requested size
+fn fit_dimensions(src_width : u32, src_height : u32, width : u32, height : u32) -> (u32, u32) {
+    let (src_width, src_height) = (src_width as u64, src_height as u64);
+    let (width, height) = (width as u64, height as u64);
+    if src_width * height > width * src_height {
+        (width as u32, (src_height * width / src_width).max(1) as u32)
+    } else {
+        ((src_width * height / src_height).max(1) as u32, height as u32)
+    }
+}
+
+/// Returns the centered region (x, y, width, height) of the source, that has the aspect ratio of the requested size
+fn cover_region(src_width : u32, src_height : u32, width : u32, height : u32) -> (u32, u32, u32, u32) {
+    let (src_width, src_height) = (src_width as u64, src_height as u64);
+    let (width, height) = (width as u64, height as u64);
+    if src_width * height > width * src_height {
+        let crop_width = (src_height * width / height).max(1);
+        (((src_width - crop_width) / 2) as u32, 0, crop_width as u32, src_height as u32)
+    } else {
+        let crop_height = (src_width * height / width).max(1);
+        (0, ((src_height - crop_height) / 2) as u32, src_width as u32, crop_height as u32)
+    }
+}
+
+/// Rotates and flips the image, so it is displayed upright with the EXIF orientation, returns None if it already is
+pub fn orient_image(img : &DynamicImage, orientation : u16) -> Option<DynamicImage> {
+    match orientation {
+        2 => Some(img.fliph()),
+        3 => Some(img.rotate180()),
+        4 => Some(img.flipv()),
+        5 => Some(img.rotate90().fliph()),
+        6 => Some(img.rotate90()),
+        7 => Some(img.rotate270().fliph()),
+        8 => Some(img.rotate270()),
+        _ => None
+    }
+}
+
+/// Samples the image between pixels, pixels outside of it are transparent
+fn sample_bilinear(img : &RgbaImage, x : f64, y : f64) -> Rgba<u8> {
+    let (left, top) = (x.floor(), y.floor());
+    let (right_weight, bottom_weight) = (x - left, y - top);
+    let neighbours = [
+        (0, 0, (1.0 - right_weight) * (1.0 - bottom_weight)),
+        (1, 0, right_weight * (1.0 - bottom_weight)),
+        (0, 1, (1.0 - right_weight) * bottom_weight),
+        (1, 1, right_weight * bottom_weight)
+    ];
+    let mut sum = [0.0; 4];
+    for (offset_x, offset_y, weight) in neighbours {
+        let (sample_x, sample_y) = (left as i64 + offset_x, top as i64 + offset_y);
+        if sample_x < 0 || sample_y < 0 || sample_x >= img.width() as i64 || sample_y >= img.height() as i64 {continue;}
+        let pixel = img.get_pixel(sample_x as u32, sample_y as u32);
+        // Weighted by alpha, so transparent pixels don't darken the edges
+        let alpha = pixel[3] as f64 * weight;
+        for channel in 0..3 {
+            sum[channel] += pixel[channel] as f64 * alpha;
+        }
+        sum[3] += alpha;
+    }
+    if sum[3] == 0.0 {return Rgba([0, 0, 0, 0]);}
+    let channel = |channel : usize| (sum[channel] / sum[3]).round() as u8;
+    Rgba([channel(0), channel(1), channel(2), sum[3].round() as u8])
+}
+
+/// Rotates the image clockwise by an angle, which isn't a multiple of 90 degrees.
+/// The image is enlarged to fit the rotated image, its corners are transparent
+/// The size of the image once it is rotated by any angle, which is large enough to contain all of its corners
+fn rotated_dimensions(width : u32, height : u32, degrees : f32) -> (u32, u32) {
+    let (sin, cos) = (degrees as f64).to_radians().sin_cos();
+    let (width, height) = (width as f64, height as f64);
+    let rotated_width = (width * cos.abs() + height * sin.abs()).round().max(1.0) as u32;
+    let rotated_height = (width * sin.abs() + height * cos.abs()).round().max(1.0) as u32;
+    (rotated_width, rotated_height)
+}
+
+fn rotate_any(img : &DynamicImage, degrees : f32) -> DynamicImage {
+    let src_img = img.to_rgba8();
+    let (sin, cos) = (degrees as f64).to_radians().sin_cos();
+    let (src_width, src_height) = (src_img.width() as f64, src_img.height() as f64);
+    let (width, height) = rotated_dimensions(src_img.width(), src_img.height(), degrees);
+    let mut rotated_img = RgbaImage::new(width, height);
+    for (x, y, pixel) in rotated_img.enumerate_pixels_mut() {
+        // Rotates the center of the pixel back to find where it is in the source
+        let (dx, dy) = (x as f64 + 0.5 - width as f64 / 2.0, y as f64 + 0.5 - height as f64 / 2.0);
+        let src_x = dx * cos + dy * sin + src_width / 2.0 - 0.5;
+        let src_y = -dx * sin + dy * cos + src_height / 2.0 - 0.5;
+        *pixel = sample_bilinear(&src_img, src_x, src_y);
+    }
+    DynamicImage::ImageRgba8(rotated_img)
+}
+
+/// Flips and then rotates the image as requested by the options, returns None if nothing is requested
+fn transform_image(img : &DynamicImage, options : &ImageOptions) -> Option<DynamicImage> {
+    let mut transformed_img : Option<DynamicImage> = None;
+    if options.flip_h {
+        transformed_img = Some(transformed_img.as_ref().unwrap_or(img).fliph());
+    }
+    if options.flip_v {
+        transformed_img = Some(transformed_img.as_ref().unwrap_or(img).flipv());
+    }
+    let flipped_img = transformed_img.as_ref().unwrap_or(img);
+    let rotated_img = match options.rotate {
+        0.0 => None,
+        90.0 => Some(flipped_img.rotate90()),
+        180.0 => Some(flipped_img.rotate180()),
+        270.0 => Some(flipped_img.rotate270()),
+        degrees => Some(rotate_any(flipped_img, degrees))
+    };
+    rotated_img.or(transformed_img)
+}
+
+/// The size of the image once `orient_image` was applied
+pub fn oriented_dimensions(width : u32, height : u32, orientation : u16) -> (u32, u32) {
+    match orientation {
+        5..=8 => (height, width),
+        _ => (width, height)
+    }
+}
+
+/// Centers the image on a `width`x`height` canvas filled with `color`
+fn pad_image(img : &DynamicImage, width : u32, height : u32, color : Rgba<u8>) -> DynamicImage {
+    let (width, height) = (width.max(img.width()), height.max(img.height()));
+    let mut canvas = RgbaImage::from_pixel(width, height, color);
+    let (x, y) = ((width - img.width()) / 2, (height - img.height()) / 2);
+    // Blending onto a translucent background would make opaque pixels slightly transparent
+    if img.color().has_alpha() {
+        imageops::overlay(&mut canvas, &img.to_rgba8(), x, y);
+    } else {
+        imageops::replace(&mut canvas, &img.to_rgba8(), x, y);
+    }
+    let padded_img = DynamicImage::ImageRgba8(canvas);
+    // Keep images without transparency in the same color type as those that weren't padded
+    if color[3] == u8::MAX && !img.color().has_alpha() {
+        DynamicImage::ImageRgb8(padded_img.to_rgb8())
+    } else {
+        padded_img
+    }
+}
+
+/// Unsharp mask, which unlike `DynamicImage::unsharpen` also darkens the dark side of edges
+fn sharpen_image(img : &DynamicImage, sharpen : Sharpen) -> DynamicImage {
+    let mut sharpened_img = img.to_rgba8();
+    let blurred_img = imageops::blur(&sharpened_img, sharpen.sigma);
+    for (pixel, blurred_pixel) in sharpened_img.pixels_mut().zip(blurred_img.pixels()) {
+        // The alpha channel is kept
+        for channel in 0..3 {
+            let difference = pixel[channel] as i32 - blurred_pixel[channel] as i32;
+            if difference.abs() > sharpen.threshold {
+                pixel[channel] = (pixel[channel] as i32 + difference).clamp(0, u8::MAX as i32) as u8;
+            }
+        }
+    }
+    let sharpened_img = DynamicImage::ImageRgba8(sharpened_img);
+    if img.color().has_alpha() {
+        sharpened_img
+    } else {
+        DynamicImage::ImageRgb8(sharpened_img.to_rgb8())
+    }
+}
+
+/// Blends the color channels toward the tint color, the alpha channel is kept
+fn tint_image(img : &mut DynamicImage, tint : Tint) {
+    let blend = |channel : u8, target : u8| (channel as f32 + (target as f32 - channel as f32) * tint.strength).round() as u8;
+    let Rgb([red, green, blue]) = tint.color;
+    match img {
+        DynamicImage::ImageRgba8(rgba_img) => {
+            for pixel in rgba_img.pixels_mut() {
+                let Rgba([pixel_red, pixel_green, pixel_blue, alpha]) = *pixel;
+                *pixel = Rgba([blend(pixel_red, red), blend(pixel_green, green), blend(pixel_blue, blue), alpha]);
+            }
+        },
+        _ if img.color().has_alpha() => {
+            *img = DynamicImage::ImageRgba8(img.to_rgba8());
+            tint_image(img, tint);
+        },
+        _ => {
+            let mut rgb_img = img.to_rgb8();
+            for pixel in rgb_img.pixels_mut() {
+                let Rgb([pixel_red, pixel_green, pixel_blue]) = *pixel;
+                *pixel = Rgb([blend(pixel_red, red), blend(pixel_green, green), blend(pixel_blue, blue)]);
+            }
+            *img = DynamicImage::ImageRgb8(rgb_img);
+        }
+    }
+}
+
+/// Replaces the color channels of every pixel, the alpha channel is kept
+fn map_colors(img : &mut DynamicImage, map : impl Fn([u8; 3]) -> [u8; 3]) {
+    if img.color().has_alpha() {
+        let mut rgba_img = img.to_rgba8();
+        for pixel in rgba_img.pixels_mut() {
+            let Rgba([red, green, blue, alpha]) = *pixel;
+            let [red, green, blue] = map([red, green, blue]);
+            *pixel = Rgba([red, green, blue, alpha]);
+        }
+        *img = DynamicImage::ImageRgba8(rgba_img);
+    } else {
+        let mut rgb_img = img.to_rgb8();
+        for pixel in rgb_img.pixels_mut() {
+            pixel.0 = map(pixel.0);
+        }
+        *img = DynamicImage::ImageRgb8(rgb_img);
+    }
+}
+
+/// Raises every color channel, from 0 to 1, to the power of 1 / gamma
+fn gamma_image(img : &mut DynamicImage, gamma : f32) {
+    let mut table = [0u8; 256];
+    for (value, mapped) in table.iter_mut().enumerate() {
+        *mapped = ((value as f32 / 255.0).powf(1.0 / gamma) * 255.0).round() as u8;
+    }
+    map_colors(img, |[red, green, blue]| [table[red as usize], table[green as usize], table[blue as usize]]);
+}
+
+/// Hue in degrees, saturation and lightness from 0 to 1
+fn rgb_to_hsl([red, green, blue] : [u8; 3]) -> (f32, f32, f32) {
+    let (red, green, blue) = (red as f32 / 255.0, green as f32 / 255.0, blue as f32 / 255.0);
+    let max = red.max(green).max(blue);
+    let min = red.min(green).min(blue);
+    let lightness = (max + min) / 2.0;
+    let chroma = max - min;
+    if chroma == 0.0 {return (0.0, 0.0, lightness);}
+    let saturation = chroma / (1.0 - (2.0 * lightness - 1.0).abs());
+    let hue = if max == red {
+        ((green - blue) / chroma).rem_euclid(6.0)
+    } else if max == green {
+        (blue - red) / chroma + 2.0
+    } else {
+        (red - green) / chroma + 4.0
+    };
+    (hue * 60.0, saturation, lightness)
+}
+
+fn hsl_to_rgb(hue : f32, saturation : f32, lightness : f32) -> [u8; 3] {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let sector = hue.rem_euclid(360.0) / 60.0;
+    let second = chroma * (1.0 - (sector.rem_euclid(2.0) - 1.0).abs());
+    let (red, green, blue) = match sector as u32 {
+        0 => (chroma, second, 0.0),
+        1 => (second, chroma, 0.0),
+        2 => (0.0, chroma, second),
+        3 => (0.0, second, chroma),
+        4 => (second, 0.0, chroma),
+        _ => (chroma, 0.0, second)
+    };
+    let lowest = lightness - chroma / 2.0;
+    let to_channel = |value : f32| ((value + lowest) * 255.0).round().clamp(0.0, 255.0) as u8;
+    [to_channel(red), to_channel(green), to_channel(blue)]
+}
+
+/// Rotates the hue by `degrees` and scales the saturation by `saturation` percent, keeping the lightness
+fn adjust_hsl(img : &mut DynamicImage, degrees : f32, saturation : f32) {
+    map_colors(img, |rgb| {
+        let (hue, pixel_saturation, lightness) = rgb_to_hsl(rgb);
+        hsl_to_rgb(hue + degrees, (pixel_saturation * saturation / 100.0).min(1.0), lightness)
+    });
+}
+
+/// Grayscale tinted brown, keeping the alpha channel
+fn sepia_image(img : &DynamicImage) -> DynamicImage {
+    let mut sepia_img = if img.color().has_alpha() {
+        DynamicImage::ImageRgba8(img.grayscale().to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(img.grayscale().to_rgb8())
+    };
+    tint_image(&mut sepia_img, Tint::SEPIA);
+    sepia_img
+}
+
+/// Puts the watermark into the corner of the image given by the options, the parts of it outside of the image are cut off
+fn watermark_image(img : &DynamicImage, watermark : &DynamicImage, options : &ImageOptions) -> DynamicImage {
+    let mut watermark_img = watermark.to_rgba8();
+    if options.watermark_opacity < 1.0 {
+        for pixel in watermark_img.pixels_mut() {
+            pixel[3] = (pixel[3] as f32 * options.watermark_opacity).round() as u8;
+        }
+    }
+    let mut watermarked_img = img.to_rgba8();
+    let margin = options.watermark_margin;
+    let right = img.width().saturating_sub(watermark_img.width().saturating_add(margin));
+    let bottom = img.height().saturating_sub(watermark_img.height().saturating_add(margin));
+    let (x, y) = match options.watermark_corner {
+        Corner::TopLeft => (margin, margin),
+        Corner::TopRight => (right, margin),
+        Corner::BottomLeft => (margin, bottom),
+        Corner::BottomRight => (right, bottom)
+    };
+    imageops::overlay(&mut watermarked_img, &watermark_img, x, y);
+    let watermarked_img = DynamicImage::ImageRgba8(watermarked_img);
+    if img.color().has_alpha() {
+        watermarked_img
+    } else {
+        DynamicImage::ImageRgb8(watermarked_img.to_rgb8())
+    }
+}
+
+/// Every pixel is sampled this many times per axis, so the edges of masks are smooth
+const MASK_SAMPLES: u32 = 4;
+
+/// Whether a point of a `width`x`height` image is inside of the mask
+fn is_in_mask(mask : Mask, width : f32, height : f32, x : f32, y : f32) -> bool {
+    match mask {
+        Mask::Circle => {
+            let (radius_x, radius_y) = (width / 2.0, height / 2.0);
+            let (dx, dy) = ((x - radius_x) / radius_x, (y - radius_y) / radius_y);
+            dx * dx + dy * dy <= 1.0
+        },
+        Mask::Rounded(radius) => {
+            let radius = radius as f32;
+            // The distance to the center of the nearest corner circle, along each axis, if the point is in a corner
+            let dx = (radius - x).max(x - (width - radius)).max(0.0);
+            let dy = (radius - y).max(y - (height - radius)).max(0.0);
+            dx * dx + dy * dy <= radius * radius
+        }
+    }
+}
+
+fn mask_image(img : &DynamicImage, mask : Mask) -> anyhow::Result<DynamicImage> {
+    let mut masked_img = img.to_rgba8();
+    let (width, height) = masked_img.dimensions();
+    if let Mask::Rounded(radius) = mask {
+        if radius > width.min(height) / 2 {
+            return Err(anyhow!("Mask radius has to be at most half of the smaller side ({}), got {}", width.min(height) / 2, radius));
+        }
+    }
+    for (x, y, pixel) in masked_img.enumerate_pixels_mut() {
+        let inside = (0..MASK_SAMPLES * MASK_SAMPLES)
+            .filter(|sample| {
+                let sample_x = x as f32 + (sample % MASK_SAMPLES) as f32 / MASK_SAMPLES as f32 + 0.5 / MASK_SAMPLES as f32;
+                let sample_y = y as f32 + (sample / MASK_SAMPLES) as f32 / MASK_SAMPLES as f32 + 0.5 / MASK_SAMPLES as f32;
+                is_in_mask(mask, width as f32, height as f32, sample_x, sample_y)
+            })
+            .count() as u32;
+        pixel[3] = (pixel[3] as u32 * inside / (MASK_SAMPLES * MASK_SAMPLES)) as u8;
+    }
+    Ok(DynamicImage::ImageRgba8(masked_img))
+}
+
+fn crop_image(img : &DynamicImage, crop : CropRect) -> anyhow::Result<DynamicImage> {
+    if crop.x as u64 + crop.width as u64 > img.width() as u64 || crop.y as u64 + crop.height as u64 > img.height() as u64 {
+        return Err(anyhow!("Crop {} is outside of the image ({}x{})", crop, img.width(), img.height()));
+    }
+    Ok(img.crop_imm(crop.x, crop.y, crop.width, crop.height))
+}
+
+/// The size `resize_image` resizes a `src_width`x`src_height` image to
+fn resized_dimensions(src_width : u32, src_height : u32, width : u32, height : u32, options : &ImageOptions) -> (u32, u32) {
+    let (src_width, src_height, width, height) = match options.resize_mode {
+        ResizeMode::Exact => (src_width, src_height, width, height),
+        ResizeMode::Fit => {
+            let (width, height) = fit_dimensions(src_width, src_height, width, height);
+            (src_width, src_height, width, height)
+        },
+        // Only the covered region of the source is resized
+        ResizeMode::Cover => {
+            let (_, _, crop_width, crop_height) = cover_region(src_width, src_height, width, height);
+            (crop_width, crop_height, width, height)
+        }
+    };
+    if options.no_upscale {
+        (width.min(src_width), height.min(src_height))
+    } else {
+        (width, height)
+    }
+}
+
+fn resize_image(img : &DynamicImage, width : u32, height : u32, options : &ImageOptions) -> DynamicImage {
+    let (resized_width, resized_height) = resized_dimensions(img.width(), img.height(), width, height, options);
+    let cropped_img;
+    let img = match options.resize_mode {
+        ResizeMode::Cover => {
+            let (x, y, crop_width, crop_height) = cover_region(img.width(), img.height(), width, height);
+            cropped_img = img.crop_imm(x, y, crop_width, crop_height);
+            &cropped_img
+        },
+        ResizeMode::Exact | ResizeMode::Fit => img
+    };
+    if img.width() != resized_width || img.height() != resized_height {
+        match options.filter.for_size(resized_width, resized_height).filter_type() {
+            Some(filter_type) => img.resize_exact(resized_width, resized_height, filter_type),
+            None => img.thumbnail_exact(resized_width, resized_height)
+        }
+    } else {
+        img.clone()
+    }
+}
+
+/// The most pixels of the images `process_image` creates from a `src_width`x`src_height` source, so too large ones can be rejected before allocating them.
+/// The rotated, the resized and the padded image are the largest ones
+pub fn max_processed_pixels(src_width : u32, src_height : u32, orientation : u16, width : u32, height : u32, options : &ImageOptions) -> u64 {
+    let pixels = |(width, height) : (u32, u32)| width as u64 * height as u64;
+    let (src_width, src_height) = if options.ignore_orientation {(src_width, src_height)} else {oriented_dimensions(src_width, src_height, orientation)};
+    let rotated_size = match options.rotate {
+        0.0 | 180.0 => (src_width, src_height),
+        90.0 | 270.0 => (src_height, src_width),
+        degrees => rotated_dimensions(src_width, src_height, degrees)
+    };
+    let src_size = options.crop.map_or(rotated_size, |crop| (crop.width, crop.height));
+    let resized_size = resized_dimensions(src_size.0, src_size.1, width, height, options);
+    let padded_size = match options.pad {
+        Some(_) => (width.max(resized_size.0), height.max(resized_size.1)),
+        None => resized_size
+    };
+    pixels(rotated_size).max(pixels(resized_size)).max(pixels(padded_size))
+}
+
+/// Applies the orientation and all options except the format to the source image
+/// `watermark` is the image of `options.watermark`, it is put on the image after resizing it and before padding it
+pub fn process_image(img : &DynamicImage, orientation : u16, width : u32, height : u32, options : &ImageOptions, watermark : Option<&DynamicImage>) -> anyhow::Result<DynamicImage> {
+    let oriented_img;
+    let img = match orient_image(img, orientation).filter(|_| !options.ignore_orientation) {
+        Some(rotated_img) => {
+            oriented_img = rotated_img;
+            &oriented_img
+        },
+        None => img
+    };
+    let transformed_img;
+    let img = match transform_image(img, options) {
+        Some(changed_img) => {
+            transformed_img = changed_img;
+            &transformed_img
+        },
+        None => img
+    };
+    let cropped_img;
+    let img = match options.crop {
+        Some(crop) => {
+            cropped_img = crop_image(img, crop)?;
+            &cropped_img
+        },
+        None => img
+    };
+    let mut img = resize_image(img, width, height, options);
+    if options.brightness != 0 {
+        img = img.brighten(options.brightness);
+    }
+    if options.contrast != 0.0 {
+        img = img.adjust_contrast(options.contrast);
+    }
+    if options.gamma != 1.0 {
+        gamma_image(&mut img, options.gamma);
+    }
+    if options.hue_rotate != 0.0 || options.saturation != 100.0 {
+        adjust_hsl(&mut img, options.hue_rotate, options.saturation);
+    }
+    if options.sepia {
+        img = sepia_image(&img);
+    }
+    if let Some(tint) = options.tint {
+        tint_image(&mut img, tint);
+    }
+    if let Some(sharpen) = options.sharpen {
+        img = sharpen_image(&img, sharpen);
+    }
+    if let Some(sigma) = options.blur {
+        img = img.blur(sigma);
+    }
+    if let Some(watermark) = watermark {
+        img = watermark_image(&img, watermark, options);
+    }
+    if let Some(color) = options.pad {
+        img = pad_image(&img, width, height, color);
+    }
+    if options.grayscale {
+        img = img.grayscale();
+    }
+    if let Some(mask) = options.mask {
+        // The transparent corners would be put on the background again. `ImageOptions::parse` switches to PNG, so only options set up another way get here
+        if !options.format.has_alpha() {
+            return Err(anyhow!("Masks need a format with transparency, like png, not {}", options.format.name()));
+        }
+        img = mask_image(&img, mask)?;
+    }
+    Ok(img)
+}
+
+/// Places the cells in a grid of `columns` columns, from left to right and top to bottom, centered in their `cell_width`x`cell_height` cell.
+/// Missing cells stay transparent
+pub fn compose_grid(cells : &[Option<DynamicImage>], columns : u32, cell_width : u32, cell_height : u32) -> DynamicImage {
+    let rows = (cells.len() as u32).div_ceil(columns);
+    let mut grid_img = RgbaImage::new(columns * cell_width, rows * cell_height);
+    for (i, cell) in cells.iter().enumerate() {
+        let Some(cell) = cell else {continue};
+        let (column, row) = (i as u32 % columns, i as u32 / columns);
+        // Cells resized to fit can be smaller than the cell
+        let x = column * cell_width + cell_width.saturating_sub(cell.width()) / 2;
+        let y = row * cell_height + cell_height.saturating_sub(cell.height()) / 2;
+        imageops::overlay(&mut grid_img, &cell.to_rgba8(), x, y);
+    }
+    DynamicImage::ImageRgba8(grid_img)
+}
+
+/// Blends the image onto an opaque background, so formats without transparency don't just drop the alpha channel
+fn flatten_image(img : &DynamicImage, background : Rgb<u8>) -> DynamicImage {
+    let rgba_img = img.to_rgba8();
+    let flattened_img = RgbImage::from_fn(rgba_img.width(), rgba_img.height(), |x, y| {
+        let Rgba([red, green, blue, alpha]) = *rgba_img.get_pixel(x, y);
+        let blend = |channel : u8, background_channel : u8| {
+            ((channel as u32 * alpha as u32 + background_channel as u32 * (u8::MAX - alpha) as u32 + 127) / u8::MAX as u32) as u8
+        };
+        let Rgb([background_red, background_green, background_blue]) = background;
+        Rgb([blend(red, background_red), blend(green, background_green), blend(blue, background_blue)])
+    });
+    DynamicImage::ImageRgb8(flattened_img)
+}
+
+fn read_u32(bytes : &[u8], offset : usize, little_endian : bool) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?.try_into().ok()?;
+    Some(if little_endian {u32::from_le_bytes(bytes)} else {u32::from_be_bytes(bytes)})
+}
+
+/// The size of an uncompressed 24 bit BMP with bottom up rows, which is how BMP images are encoded
+fn plain_bmp_size(raw_img_bytes : &[u8]) -> Option<(u32, u32)> {
+    if !raw_img_bytes.starts_with(b"BM") || read_u32(raw_img_bytes, 14, true)? < 40 {return None;}
+    let bits_per_pixel = raw_img_bytes.get(28..30)?;
+    // Top down rows have a negative height
+    let is_plain = bits_per_pixel == [24, 0] && read_u32(raw_img_bytes, 30, true)? == 0 && (read_u32(raw_img_bytes, 22, true)? as i32) > 0;
+    is_plain.then_some((read_u32(raw_img_bytes, 18, true)?, read_u32(raw_img_bytes, 22, true)?))
+}
+
+/// The size of a non interlaced 8 bit RGB or RGBA PNG, which is how PNG images are encoded
+fn plain_png_size(raw_img_bytes : &[u8]) -> Option<(u32, u32)> {
+    if !raw_img_bytes.starts_with(b"\x89PNG\r\n\x1a\n") || raw_img_bytes.get(12..16)? != b"IHDR" {return None;}
+    let (bit_depth, color_type, interlaced) = (*raw_img_bytes.get(24)?, *raw_img_bytes.get(25)?, *raw_img_bytes.get(28)?);
+    let is_plain = bit_depth == 8 && (color_type == 2 || color_type == 6) && interlaced == 0;
+    is_plain.then_some((read_u32(raw_img_bytes, 16, false)?, read_u32(raw_img_bytes, 20, false)?))
+}
+
+/// Whether the source image already is what encoding it would return, because it has the requested size and format and the options don't change it
+pub fn is_unchanged_source(raw_img_bytes : &[u8], width : u32, height : u32, options : &ImageOptions) -> bool {
+    if options.changes_pixels() {return false;}
+    let size = match options.format {
+        OutputFormat::Bmp => plain_bmp_size(raw_img_bytes),
+        // Encoding drops the EXIF data and applies its orientation, so images with it are decoded
+        OutputFormat::Png => plain_png_size(raw_img_bytes).filter(|_| exif::png_exif(raw_img_bytes).is_none()),
+        _ => None
+    };
+    size == Some((width, height))
+}
+
+pub fn encode_image(img : &DynamicImage, options : &ImageOptions) -> anyhow::Result<Vec<u8>> {
+    let flattened_img;
+    let img = if img.color().has_alpha() && !options.format.has_alpha() {
+        flattened_img = flatten_image(img, options.background);
+        &flattened_img
+    } else {
+        img
+    };
+    if let Some(colors) = options.colors {
+        return match options.format {
+            OutputFormat::Bmp => Ok(palette::encode_bmp(&img.to_rgba8(), colors as usize)),
+            OutputFormat::Png => palette::encode_png(&img.to_rgba8(), colors as usize),
+            format => Err(anyhow!("Only BMP and PNG images can be reduced to a palette, not {}", format.name()))
+        };
+    }
+    let mut encoded_img_bytes = Vec::new();
+    match options.format {
+        OutputFormat::Jpeg => JpegEncoder::new_with_quality(&mut encoded_img_bytes, options.quality).encode_image(img)?,
+        // The encoder of `image` can't encode WebP images
+        #[cfg(feature = "webp")]
+        OutputFormat::Webp => {
+            let rgba_img = img.to_rgba8();
+            let encoder = webp::Encoder::from_rgba(rgba_img.as_raw(), rgba_img.width(), rgba_img.height());
+            encoded_img_bytes.extend_from_slice(&encoder.encode(options.quality as f32));
+        },
+        format => img.write_to(&mut encoded_img_bytes, format.image_format())?
+    }
+    Ok(encoded_img_bytes)
+}
+
+/// Packs PNG images, given with their width and height of at most 256, into a single ICO file
+pub fn pack_ico(icons : &[(u32, u32, Vec<u8>)]) -> Vec<u8> {
+    const HEADER_SIZE: usize = 6;
+    const ENTRY_SIZE: usize = 16;
+    let mut ico_bytes = Vec::new();
+    ico_bytes.extend_from_slice(&0u16.to_le_bytes()); // Reserved
+    ico_bytes.extend_from_slice(&1u16.to_le_bytes()); // Icon, not cursor
+    ico_bytes.extend_from_slice(&(icons.len() as u16).to_le_bytes());
+    let mut offset = HEADER_SIZE + ENTRY_SIZE * icons.len();
+    for (width, height, png_bytes) in icons {
+        // 0 means 256
+        ico_bytes.push(*width as u8);
+        ico_bytes.push(*height as u8);
+        ico_bytes.extend_from_slice(&[0, 0]); // No palette, reserved
+        ico_bytes.extend_from_slice(&1u16.to_le_bytes()); // Color planes
+        ico_bytes.extend_from_slice(&32u16.to_le_bytes()); // Bits per pixel
+        ico_bytes.extend_from_slice(&(png_bytes.len() as u32).to_le_bytes());
+        ico_bytes.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += png_bytes.len();
+    }
+    for (_, _, png_bytes) in icons {
+        ico_bytes.extend_from_slice(png_bytes);
+    }
+    ico_bytes
+}
+
+pub fn get_blurhash(img : &DynamicImage, components_x : u32, components_y : u32) -> anyhow::Result<String> {
+    // The hash only keeps a few colors, so a small image gives the same result much faster
+    let small_img = img.thumbnail(BLURHASH_IMAGE_SIZE, BLURHASH_IMAGE_SIZE).to_rgba8();
+    blurhash::encode(components_x, components_y, small_img.width(), small_img.height(), small_img.as_raw())
+        .map_err(|e| anyhow!("Cannot compute blurhash : {:?}", e))
+}
+
+/// Returns the dominant colors as comma separated `RRGGBB` hex colors, the most common first
+pub fn get_dominant_colors(img : &DynamicImage, count : usize) -> String {
+    // Sampling a small fixed size keeps the time bounded for any image size
+    let small_img = img.resize_exact(DOMINANT_COLOR_IMAGE_SIZE, DOMINANT_COLOR_IMAGE_SIZE, FilterType::Triangle).to_rgb8();
+    color::dominant_colors(&small_img, count).iter()
+        .map(|[red, green, blue]| format!("{:02x}{:02x}{:02x}", red, green, blue))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Returns the perceptual hash as 16 hex digits
+pub fn get_phash(img : &DynamicImage) -> String {
+    let small_img = img.resize_exact(phash::SIZE as u32, phash::SIZE as u32, FilterType::Triangle).to_luma8();
+    format!("{:016x}", phash::phash(&small_img))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::options::ResizeFilter;
+    use crate::test_util::*;
+    use super::*;
+
+    #[test]
+    fn encodes_the_requested_format() {
+        let img = solid_image(16, 8, [10, 200, 30]);
+        let magic_bytes : [(&str, &[u8]); 3] = [("bmp", b"BM"), ("png", b"\x89PNG\r\n\x1a\n"), ("jpeg", &[0xFF, 0xD8, 0xFF])];
+        for (format, magic_bytes) in magic_bytes {
+            let img_bytes = encode_image(&img, &image_options(&[format])).unwrap();
+            assert!(img_bytes.starts_with(magic_bytes), "{} starts with {:?}", format, &img_bytes[..4]);
+        }
+    }
+
+    #[test]
+    fn encodes_jpegs_of_lower_quality_smaller() {
+        let img = noise_image(64, 64);
+        let low_quality_bytes = encode_image(&img, &image_options(&["jpeg", "quality=20"])).unwrap();
+        let high_quality_bytes = encode_image(&img, &image_options(&["jpeg", "quality=90"])).unwrap();
+        assert!(low_quality_bytes.len() < high_quality_bytes.len(), "{} >= {}", low_quality_bytes.len(), high_quality_bytes.len());
+    }
+
+    #[test]
+    fn resizes_into_the_box_by_the_resize_mode() {
+        let img = solid_image(200, 100, [0, 0, 255]);
+        let resize_modes = [("resize=exact", (50, 50)), ("resize=fit", (50, 25)), ("resize=cover", (50, 50))];
+        for (resize_mode, dimensions) in resize_modes {
+            let resized_img = process_image(&img, 1, 50, 50, &image_options(&[resize_mode]), None).unwrap();
+            assert_eq!(resized_img.dimensions(), dimensions, "{}", resize_mode);
+        }
+        let padded_img = process_image(&img, 1, 50, 50, &image_options(&["resize=fit", "pad=ffffff"]), None).unwrap();
+        assert_eq!(padded_img.dimensions(), (50, 50));
+    }
+
+    #[test]
+    fn resizes_differently_with_every_filter() {
+        let img = noise_image(64, 64);
+        let mut resized_images : Vec<Vec<u8>> = Vec::new();
+        for filter in ["filter=thumbnail", "filter=nearest", "filter=triangle", "filter=catmull", "filter=gaussian", "filter=lanczos3"] {
+            let resized_img = process_image(&img, 1, 24, 24, &image_options(&[filter]), None).unwrap();
+            let resized_bytes = resized_img.to_rgb8().into_raw();
+            assert!(!resized_images.contains(&resized_bytes), "{} resizes like another filter", filter);
+            resized_images.push(resized_bytes);
+        }
+    }
+
+    #[test]
+    fn keeps_the_size_of_smaller_images_without_upscaling() {
+        let img = solid_image(32, 32, [0, 255, 0]);
+        assert_eq!(process_image(&img, 1, 256, 256, &image_options(&["no_upscale"]), None).unwrap().dimensions(), (32, 32));
+        assert_eq!(process_image(&img, 1, 16, 256, &image_options(&["no_upscale"]), None).unwrap().dimensions(), (16, 32));
+        assert_eq!(process_image(&img, 1, 256, 256, &ImageOptions::default(), None).unwrap().dimensions(), (256, 256));
+    }
+
+    #[test]
+    fn crops_before_resizing() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(100, 50, |x, _| if x < 50 {Rgb([255, 0, 0])} else {Rgb([0, 0, 255])}));
+        let cropped_img = process_image(&img, 1, 10, 10, &image_options(&["crop=50,0,50,50"]), None).unwrap();
+        assert_eq!(cropped_img.dimensions(), (10, 10));
+        assert!(cropped_img.to_rgb8().pixels().all(|pixel| pixel.0 == [0, 0, 255]));
+        let error = process_image(&img, 1, 10, 10, &image_options(&["crop=60,0,50,50"]), None).unwrap_err();
+        assert_eq!(error.to_string(), "Crop 60,0,50,50 is outside of the image (100x50)");
+    }
+
+    #[test]
+    fn turns_colors_into_their_luma() {
+        let img = solid_image(8, 8, [200, 100, 50]);
+        for format in ["bmp", "png"] {
+            let options = image_options(&["grayscale", format]);
+            let grayscale_img = process_image(&img, 1, 4, 4, &options, None).unwrap();
+            let img_bytes = encode_image(&grayscale_img, &options).unwrap();
+            let [red, green, blue] = decode(&img_bytes).to_rgb8().get_pixel(2, 2).0;
+            // 0.2126 * 200 + 0.7152 * 100 + 0.0722 * 50
+            assert!(red == green && green == blue && red.abs_diff(118) <= 1, "{} gives {:?}", format, [red, green, blue]);
+        }
+    }
+
+    fn variance(img: &DynamicImage) -> f64 {
+        let values : Vec<f64> = img.to_rgb8().into_raw().into_iter().map(f64::from).collect();
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        values.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / values.len() as f64
+    }
+
+    #[test]
+    fn blurs_away_the_variance_of_the_pixels() {
+        let img = noise_image(32, 32);
+        let sharp_img = process_image(&img, 1, 32, 32, &ImageOptions::default(), None).unwrap();
+        let blurred_img = process_image(&img, 1, 32, 32, &image_options(&["blur=3"]), None).unwrap();
+        assert!(variance(&blurred_img) < variance(&sharp_img) / 4.0, "{} and {}", variance(&blurred_img), variance(&sharp_img));
+    }
+
+    #[test]
+    fn pads_the_fitted_image_with_the_background() {
+        let img = solid_image(100, 50, [255, 0, 0]);
+        let padded_img = process_image(&img, 1, 100, 100, &image_options(&["resize=fit", "pad=00ff0080"]), None).unwrap().to_rgba8();
+        assert_eq!(padded_img.dimensions(), (100, 100));
+        for (x, y) in [(0, 0), (99, 0), (0, 99), (99, 99)] {
+            assert_eq!(padded_img.get_pixel(x, y).0, [0, 255, 0, 128], "{} {}", x, y);
+        }
+        assert_eq!(padded_img.get_pixel(50, 50).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn blurhashes_solid_colors() {
+        // The size flag of the components, how large the details are, the average color, then the details
+        assert_eq!(get_blurhash(&solid_image(128, 96, [255, 0, 0]), 1, 1).unwrap(), "00TI?r");
+        assert!(get_blurhash(&solid_image(128, 96, [255, 0, 0]), 4, 3).unwrap().starts_with("L6TI?r"));
+        assert_eq!(get_blurhash(&solid_image(128, 96, [0, 0, 0]), 4, 3).unwrap(), format!("L009jv{}", "fQ".repeat(11)));
+    }
+
+    fn hamming_distance(first_hash: &str, second_hash: &str) -> u32 {
+        (u64::from_str_radix(first_hash, 16).unwrap() ^ u64::from_str_radix(second_hash, 16).unwrap()).count_ones()
+    }
+
+    #[test]
+    fn hashes_resizes_of_an_image_alike() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(300, 300, |x, y| {
+            let in_circle = (x as i32 - 100).pow(2) + (y as i32 - 120).pow(2) < 60 * 60;
+            if in_circle {Rgb([250, 250, 250])} else {Rgb([(x * 255 / 300) as u8, 40, (y * 255 / 300) as u8])}
+        }));
+        let unrelated_img = DynamicImage::ImageRgb8(RgbImage::from_fn(300, 300, |x, y| if (x / 50 + y / 75) % 2 == 0 {Rgb([0, 0, 0])} else {Rgb([255, 255, 255])}));
+        let large_hash = get_phash(&img.resize_exact(200, 200, FilterType::Triangle));
+        let small_hash = get_phash(&img.resize_exact(120, 150, FilterType::CatmullRom));
+        let unrelated_hash = get_phash(&unrelated_img);
+        assert!(hamming_distance(&large_hash, &small_hash) <= 6, "{} and {}", large_hash, small_hash);
+        assert!(hamming_distance(&large_hash, &unrelated_hash) >= 20, "{} and {}", large_hash, unrelated_hash);
+    }
+
+    #[test]
+    fn flattens_transparency_onto_the_background() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(16, 16, Rgba([255, 0, 0, 128])));
+        let backgrounds = [(vec!["jpeg"], [255, 127, 127]), (vec!["jpeg", "background=0000ff"], [128, 0, 127]), (vec!["bmp", "background=0000ff"], [128, 0, 127])];
+        for (args, expected_color) in backgrounds {
+            let img_bytes = encode_image(&img, &image_options(&args)).unwrap();
+            let color = decode(&img_bytes).to_rgb8().get_pixel(8, 8).0;
+            assert!(color.iter().zip(expected_color).all(|(channel, expected)| channel.abs_diff(expected) <= 3), "{:?} gives {:?}", args, color);
+        }
+        let img_bytes = encode_image(&img, &image_options(&["png"])).unwrap();
+        assert_eq!(decode(&img_bytes).to_rgba8().get_pixel(8, 8).0, [255, 0, 0, 128]);
+    }
+
+    #[test]
+    #[cfg(feature = "webp")]
+    fn encodes_webps_of_the_requested_quality() {
+        let img = noise_image(64, 64);
+        let low_quality_bytes = encode_image(&img, &image_options(&["webp", "quality=20"])).unwrap();
+        let high_quality_bytes = encode_image(&img, &image_options(&["webp", "quality=90"])).unwrap();
+        for img_bytes in [&low_quality_bytes, &high_quality_bytes] {
+            assert!(&img_bytes[..4] == b"RIFF" && &img_bytes[8..12] == b"WEBP", "starts with {:?}", &img_bytes[..12]);
+        }
+        assert!(low_quality_bytes.len() < high_quality_bytes.len(), "{} >= {}", low_quality_bytes.len(), high_quality_bytes.len());
+        // Both are cached separately
+        assert_ne!(image_options(&["webp", "quality=20"]).to_string(), image_options(&["webp", "quality=90"]).to_string());
+    }
+
+    #[test]
+    fn sharpens_edges_after_resizing() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 16, |x, _| if x < 32 {Rgb([100, 100, 100])} else {Rgb([156, 156, 156])}));
+        // The difference across the edge in the middle of the resized image
+        let edge_contrast = |args: &[&str]| {
+            let resized_img = process_image(&img, 1, 32, 8, &image_options(args), None).unwrap().to_rgb8();
+            i32::from(resized_img.get_pixel(17, 4).0[0]) - i32::from(resized_img.get_pixel(14, 4).0[0])
+        };
+        let soft_contrast = edge_contrast(&[]);
+        let sharp_contrast = edge_contrast(&["sharpen=2,0"]);
+        assert!(sharp_contrast > soft_contrast, "{} <= {}", sharp_contrast, soft_contrast);
+        // Differences within the threshold are kept
+        assert_eq!(edge_contrast(&["sharpen=2,255"]), soft_contrast);
+        for sharpen in ["sharpen=0,1", "sharpen=2,256", "sharpen=NaN,1", "sharpen=2"] {
+            assert!(ImageOptions::parse(&[sharpen]).is_err(), "{}", sharpen);
+        }
+    }
+
+    #[test]
+    fn adjusts_the_brightness_before_the_contrast() {
+        let img = solid_image(8, 8, [100, 100, 100]);
+        let adjustments = [
+            (&["brightness=28"][..], 128),
+            (&["brightness=-200"][..], 0),
+            // ((100 / 255 - 0.5) * ((100 + 50) / 100)² + 0.5) * 255
+            (&["contrast=50"][..], 66),
+            (&["contrast=-100"][..], 128),
+            (&["brightness=20", "contrast=50"][..], 111)
+        ];
+        for (args, expected_value) in adjustments {
+            let adjusted_img = process_image(&img, 1, 4, 4, &image_options(args), None).unwrap();
+            let [red, green, blue] = adjusted_img.to_rgb8().get_pixel(2, 2).0;
+            assert!(red == green && green == blue && red.abs_diff(expected_value) <= 1, "{:?} gives {:?}", args, [red, green, blue]);
+        }
+        for arg in ["brightness=256", "contrast=101", "contrast=NaN"] {
+            assert!(ImageOptions::parse(&[arg]).is_err(), "{}", arg);
+        }
+    }
+
+    #[test]
+    fn flips_and_rotates_the_corners_of_the_image() {
+        // Only the top left corner is red
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(40, 20, |x, y| if x < 10 && y < 10 {Rgb([255, 0, 0])} else {Rgb([0, 0, 255])}));
+        assert!(transform_image(&img, &ImageOptions::default()).is_none());
+        let transforms = [
+            (&["rotate=90"][..], (20, 40), (19, 0)),
+            (&["rotate=180"][..], (40, 20), (39, 19)),
+            (&["rotate=270"][..], (20, 40), (0, 39)),
+            (&["rotate=-90"][..], (20, 40), (0, 39)),
+            (&["rotate=450"][..], (20, 40), (19, 0)),
+            (&["flip_h"][..], (40, 20), (39, 0)),
+            (&["flip_v"][..], (40, 20), (0, 19)),
+            (&["flip_h", "rotate=90"][..], (20, 40), (19, 39))
+        ];
+        for (args, dimensions, (red_x, red_y)) in transforms {
+            let transformed_img = transform_image(&img, &image_options(args)).unwrap().to_rgb8();
+            assert_eq!(transformed_img.dimensions(), dimensions, "{:?}", args);
+            assert_eq!(transformed_img.get_pixel(red_x, red_y).0, [255, 0, 0], "{:?}", args);
+            let red_count = transformed_img.pixels().filter(|pixel| pixel.0 == [255, 0, 0]).count();
+            assert_eq!(red_count, 100, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn enlarges_images_rotated_by_other_angles() {
+        let img = solid_image(40, 20, [0, 0, 255]);
+        let rotated_img = transform_image(&img, &image_options(&["rotate=45"])).unwrap().to_rgba8();
+        // √½ * (40 + 20)
+        assert_eq!(rotated_img.dimensions(), (42, 42));
+        for (x, y) in [(0, 0), (41, 0), (0, 41), (41, 41)] {
+            assert_eq!(rotated_img.get_pixel(x, y).0[3], 0, "{} {}", x, y);
+        }
+        assert_eq!(rotated_img.get_pixel(21, 21).0, [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn tints_pixels_toward_the_color_by_the_strength() {
+        let white_img = solid_image(8, 8, [255, 255, 255]);
+        let tints = [(&["tint=ff0000,0.5"][..], [255, 128, 128]), (&["tint=ff0000,0"][..], [255, 255, 255]), (&["tint=0000ff,1"][..], [0, 0, 255]), (&["sepia"][..], [198, 179, 161])];
+        for (args, expected_color) in tints {
+            let tinted_img = process_image(&white_img, 1, 4, 4, &image_options(args), None).unwrap();
+            assert_eq!(tinted_img.to_rgb8().get_pixel(2, 2).0, expected_color, "{:?}", args);
+        }
+        let translucent_img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 8, Rgba([255, 255, 255, 100])));
+        let tinted_img = process_image(&translucent_img, 1, 4, 4, &image_options(&["tint=ff0000,0.5"]), None).unwrap();
+        assert_eq!(tinted_img.to_rgba8().get_pixel(2, 2).0, [255, 128, 128, 100]);
+        for tint in ["tint=ff0000", "tint=ff00,0.5", "tint=ff000080,0.5", "tint=ff0000,1.5", "tint=ff0000,NaN"] {
+            assert!(ImageOptions::parse(&[tint]).is_err(), "{}", tint);
+        }
+    }
+
+    #[test]
+    fn makes_the_corners_of_masked_images_transparent() {
+        let img = solid_image(32, 32, [255, 0, 0]);
+        for args in [&["mask=circle"][..], &["jpeg", "mask=circle"]] {
+            let options = image_options(args);
+            assert_eq!(options.format, OutputFormat::Png, "{:?}", args);
+            let masked_img = decode(&encode_image(&process_image(&img, 1, 16, 16, &options, None).unwrap(), &options).unwrap()).to_rgba8();
+            for (x, y) in [(0, 0), (15, 0), (0, 15), (15, 15)] {
+                assert_eq!(masked_img.get_pixel(x, y).0[3], 0, "{} {}", x, y);
+            }
+            assert_eq!(masked_img.get_pixel(8, 8).0, [255, 0, 0, 255]);
+            // The middle of the edges touches the circle
+            assert!(masked_img.get_pixel(8, 0).0[3] > 0);
+        }
+        let rounded_img = process_image(&img, 1, 16, 16, &image_options(&["mask=rounded,4"]), None).unwrap().to_rgba8();
+        assert_eq!(rounded_img.get_pixel(0, 0).0[3], 0);
+        assert_eq!(rounded_img.get_pixel(8, 0).0[3], 255);
+        assert_eq!(rounded_img.get_pixel(0, 8).0[3], 255);
+        assert!(process_image(&img, 1, 16, 16, &image_options(&["mask=rounded,9"]), None).is_err());
+        assert!(ImageOptions::parse(&["mask=rounded,0"]).is_err());
+        let bmp_options = ImageOptions {mask: Some(Mask::Circle), ..Default::default()};
+        assert!(process_image(&img, 1, 16, 16, &bmp_options, None).is_err());
+    }
+
+    #[test]
+    fn corrects_the_gamma_and_adjusts_hue_and_saturation() {
+        let adjustments = [
+            // (128 / 255)^(1 / 2.2) * 255
+            ([128, 128, 128], &["gamma=2.2"][..], [186, 186, 186]),
+            ([128, 128, 128], &["gamma=0.4545"][..], [56, 56, 56]),
+            ([255, 0, 0], &["hue_rotate=180"][..], [0, 255, 255]),
+            ([0, 255, 255], &["hue_rotate=-180"][..], [255, 0, 0]),
+            ([255, 0, 0], &["hue_rotate=120"][..], [0, 255, 0]),
+            // The lightness of (200, 100, 50) is (200 + 50) / 2
+            ([200, 100, 50], &["saturation=0"][..], [125, 125, 125]),
+            ([200, 100, 50], &["saturation=100"][..], [200, 100, 50])
+        ];
+        for (color, args, expected_color) in adjustments {
+            let adjusted_img = process_image(&solid_image(8, 8, color), 1, 4, 4, &image_options(args), None).unwrap();
+            let adjusted_color = adjusted_img.to_rgb8().get_pixel(2, 2).0;
+            assert!(adjusted_color.iter().zip(expected_color).all(|(channel, expected)| channel.abs_diff(expected) <= 1), "{:?} gives {:?}", args, adjusted_color);
+        }
+        for arg in ["gamma=0", "gamma=11", "hue_rotate=inf", "saturation=-1", "saturation=501"] {
+            assert!(ImageOptions::parse(&[arg]).is_err(), "{}", arg);
+        }
+        // Each is cached separately
+        let cache_options : std::collections::HashSet<String> = [&["gamma=2.2"][..], &["hue_rotate=90"], &["saturation=50"], &[]].into_iter().map(|args| image_options(args).to_string()).collect();
+        assert_eq!(cache_options.len(), 4);
+    }
+
+    #[test]
+    fn resizes_measurably_differently_with_the_thumbnail_and_a_filter() {
+        let img = noise_image(64, 64);
+        let thumbnail_options = image_options(&["filter=thumbnail"]);
+        let filter_options = image_options(&["filter=catmull"]);
+        let thumbnail_img = process_image(&img, 1, 16, 16, &thumbnail_options, None).unwrap().to_rgb8();
+        let filtered_img = process_image(&img, 1, 16, 16, &filter_options, None).unwrap().to_rgb8();
+        assert_eq!(thumbnail_img.dimensions(), filtered_img.dimensions());
+        let difference : u64 = thumbnail_img.as_raw().iter().zip(filtered_img.as_raw()).map(|(a, b)| a.abs_diff(*b) as u64).sum();
+        let mean_difference = difference as f64 / thumbnail_img.as_raw().len() as f64;
+        assert!(mean_difference > 1.0, "The thumbnail and the filter only differ by {} per channel", mean_difference);
+
+        assert_ne!(thumbnail_options.to_string(), filter_options.to_string());
+        assert_ne!(thumbnail_options.to_string(), ImageOptions::default().to_string());
+        assert_eq!(ResizeFilter::Auto.for_size(16, 16), ResizeFilter::Thumbnail);
+        assert_eq!(ResizeFilter::Auto.for_size(1024, 1024), ResizeFilter::CatmullRom);
+    }
+
+    #[test]
+    fn cuts_off_watermarks_with_a_margin_larger_than_the_image() {
+        let img = solid_image(16, 16, [0, 0, 255]);
+        let watermark = solid_image(4, 4, [255, 0, 0]);
+        for corner in ["top_left", "top_right", "bottom_left", "bottom_right"] {
+            let options = image_options(&["watermark", &format!("watermark_corner={}", corner), "watermark_margin=4294967295"]);
+            let watermarked_img = process_image(&img, 1, 16, 16, &options, Some(&watermark)).unwrap();
+            let pixels : Vec<_> = watermarked_img.to_rgb8().pixels().map(|pixel| pixel.0).collect();
+            // Either cut off completely or pushed into the corner
+            let red_count = pixels.iter().filter(|pixel| **pixel == [255, 0, 0]).count();
+            assert!(red_count == 0 || red_count == 16, "{} red pixels at the {}", red_count, corner);
+        }
+    }
+}
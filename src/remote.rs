@@ -0,0 +1,266 @@
+//! Decides which URLs may be fetched, so clients can't make the server request hosts it shouldn't
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::anyhow;
+use reqwest::Url;
+use reqwest::blocking::{Client, ClientBuilder};
+use reqwest::header::{HeaderMap, CACHE_CONTROL, ETAG, LAST_MODIFIED};
+use reqwest::redirect::Policy;
+
+/// How many clients connecting to checked addresses are kept, each keeps its own connections open
+const MAX_PINNED_CLIENTS: usize = 64;
+
+/// What a server sent with an image, so it can be asked later whether the image changed since
+#[derive(Clone, Debug)]
+pub struct RemoteSource {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// How long after `validated` the image is used without asking the server, forever if `None`
+    pub max_age: Option<Duration>,
+    pub validated: Instant,
+    /// Images are cached with this instead of the modification time of local files, it changes whenever the server sends a changed image
+    pub version: Option<SystemTime>
+}
+
+impl RemoteSource {
+    /// `default_max_age` is used, if the server doesn't tell how long the image may be used
+    pub fn from_headers(headers: &HeaderMap, version: Option<SystemTime>, default_max_age: Option<Duration>) -> Self {
+        let header = |name| headers.get(name).and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok()).map(str::to_string);
+        Self {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+            max_age: max_age(headers).or(default_max_age),
+            validated: Instant::now(),
+            version
+        }
+    }
+
+    /// After the server replied that the image didn't change, it is fresh again
+    pub fn revalidated(self, headers: &HeaderMap) -> Self {
+        Self {max_age: max_age(headers).or(self.max_age), validated: Instant::now(), ..self}
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.max_age.is_none_or(|max_age| self.validated.elapsed() < max_age)
+    }
+}
+
+/// Limits how many images are downloaded at the same time, by all threads together
+pub struct DownloadSlots {
+    free: Mutex<usize>,
+    freed: Condvar
+}
+
+/// Frees its slot once dropped
+pub struct DownloadSlot<'a>(&'a DownloadSlots);
+
+impl DownloadSlots {
+    pub fn new(count: usize) -> Self {
+        Self {free: Mutex::new(count), freed: Condvar::new()}
+    }
+
+    /// Waits until fewer than `count` images are being downloaded
+    pub fn acquire(&self) -> DownloadSlot<'_> {
+        let free = self.free.lock().expect("Cannot lock download slots");
+        let mut free = self.freed.wait_while(free, |free| *free == 0).expect("Cannot lock download slots");
+        *free -= 1;
+        DownloadSlot(self)
+    }
+}
+
+impl Drop for DownloadSlot<'_> {
+    fn drop(&mut self) {
+        *self.0.free.lock().expect("Cannot lock download slots") += 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// How long the image may be used without asking the server again, from the `Cache-Control` header
+fn max_age(headers: &HeaderMap) -> Option<Duration> {
+    let cache_control = headers.get(CACHE_CONTROL)?.to_str().ok()?;
+    for directive in cache_control.split(',').map(str::trim) {
+        if directive.eq_ignore_ascii_case("no-cache") || directive.eq_ignore_ascii_case("no-store") {
+            return Some(Duration::ZERO);
+        }
+        if let Some(seconds) = directive.strip_prefix("max-age=").and_then(|seconds| seconds.parse().ok()) {
+            return Some(Duration::from_secs(seconds));
+        }
+    }
+    None
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [first, second, ..] = ip.octets();
+    // 0.0.0.0/8 means this network, which connects to this host
+    let is_this_network = first == 0;
+    // 100.64.0.0/10 is shared by carrier-grade NATs
+    let is_shared = first == 100 && (64..128).contains(&second);
+    // 198.18.0.0/15 is for benchmarking networks
+    let is_benchmarking = first == 198 && (second & 0xfe) == 18;
+    // 240.0.0.0/4 is reserved, including the broadcast address
+    let is_reserved = first >= 240;
+    !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_multicast()
+        || is_this_network || is_shared || is_benchmarking || is_reserved)
+}
+
+/// The IPv4 address embedded in IPv4-mapped, IPv4-compatible, NAT64 and 6to4 addresses, which might be connected to instead
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let from_segments = |high: u16, low: u16| Some(Ipv4Addr::from(((high as u32) << 16) | low as u32));
+    match segments {
+        // ::ffff:0:0/96 and the deprecated ::/96, except for the loopback and unspecified address
+        [0, 0, 0, 0, 0, 0xffff, high, low] => from_segments(high, low),
+        [0, 0, 0, 0, 0, 0, high, low] if !ip.is_loopback() && !ip.is_unspecified() => from_segments(high, low),
+        // 64:ff9b::/96 and the local use 64:ff9b:1::/48 of NAT64
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] | [0x64, 0xff9b, 1, _, _, _, high, low] => from_segments(high, low),
+        // 2002::/16 of 6to4
+        [0x2002, high, low, ..] => from_segments(high, low),
+        _ => None
+    }
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    if let Some(ipv4) = embedded_ipv4(ip) {
+        return is_public_ipv4(ipv4);
+    }
+    let first_segment = ip.segments()[0];
+    // fc00::/7 are unique local and fe80::/10 link local addresses
+    let is_unique_local = first_segment & 0xfe00 == 0xfc00;
+    let is_link_local = first_segment & 0xffc0 == 0xfe80;
+    !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || is_unique_local || is_link_local)
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => is_public_ipv6(ip)
+    }
+}
+
+/// Whether the path is a URL, which is fetched over HTTP or HTTPS. URLs with other schemes are rejected, instead of being read as local paths
+pub fn is_url(path: &str) -> anyhow::Result<bool> {
+    let Some((scheme, _)) = path.split_once("://") else {return Ok(false)};
+    let is_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    if !is_scheme {return Ok(false);}
+    match scheme.to_ascii_lowercase().as_str() {
+        "http" | "https" => Ok(true),
+        _ => Err(anyhow!("Unsupported scheme {} of {}, only http and https are supported", scheme, path))
+    }
+}
+
+/// Allows hosts in `allowed_hosts`, or if it is empty only hosts, which don't resolve to loopback or private addresses.
+/// Returns the checked addresses of host names, which have to be connected to instead of resolving the host again.
+/// Otherwise it could resolve to another address by the time it is connected to
+pub fn check_url(url: &Url, allowed_hosts: &[String]) -> anyhow::Result<Option<Vec<SocketAddr>>> {
+    let host = url.host_str().ok_or(anyhow!("URL {} has no host", url))?;
+    if !allowed_hosts.is_empty() {
+        if !allowed_hosts.iter().any(|allowed_host| allowed_host.eq_ignore_ascii_case(host)) {
+            return Err(anyhow!("Host {} is not allowed", host));
+        }
+        return Ok(None);
+    }
+    let addresses = url.socket_addrs(|| url.port_or_known_default()).map_err(|e| anyhow!("Cannot resolve {} : {}", host, e))?;
+    if addresses.is_empty() || addresses.iter().any(|address| !is_public_ip(address.ip())) {
+        return Err(anyhow!("Host {} is not a public address", host));
+    }
+    // IP addresses aren't resolved
+    Ok(Some(addresses).filter(|_| url.domain().is_some()))
+}
+
+/// How the HTTP clients are built
+#[derive(Clone)]
+pub struct HttpConfig {
+    pub pool_size: usize,
+    pub idle_timeout: Duration,
+    pub timeout: Duration
+}
+
+impl HttpConfig {
+    fn builder(&self) -> ClientBuilder {
+        Client::builder()
+            // Redirects are followed by `HttpClients::get`, so every one of them is checked and pinned
+            .redirect(Policy::none())
+            .pool_max_idle_per_host(self.pool_size)
+            .pool_idle_timeout(self.idle_timeout)
+            .timeout(self.timeout)
+    }
+}
+
+/// A client for hosts, which don't have to be pinned, and clients connecting only to the checked addresses of a host
+pub struct HttpClients {
+    config: HttpConfig,
+    client: Client,
+    /// By host and the addresses it resolved to, so they are reused as long as the host resolves to the same addresses
+    pinned: Mutex<HashMap<(String, Vec<SocketAddr>), Client>>
+}
+
+impl HttpClients {
+    pub fn new(config: HttpConfig) -> anyhow::Result<Self> {
+        Ok(Self {client: config.builder().build()?, config, pinned: Mutex::new(HashMap::new())})
+    }
+
+    /// The client to request `url` with, `addresses` are the ones `check_url` returned for it
+    pub fn client(&self, url: &Url, addresses: Option<Vec<SocketAddr>>) -> anyhow::Result<Client> {
+        let (Some(host), Some(mut addresses)) = (url.host_str(), addresses) else {return Ok(self.client.clone())};
+        addresses.sort_unstable();
+        let key = (host.to_ascii_lowercase(), addresses);
+        let mut pinned = self.pinned.lock().expect("Cannot lock HTTP clients");
+        if let Some(client) = pinned.get(&key) {
+            return Ok(client.clone());
+        }
+        if pinned.len() >= MAX_PINNED_CLIENTS {
+            pinned.clear();
+        }
+        let client = self.config.builder().resolve_to_addrs(&key.0, &key.1).build()?;
+        pinned.insert(key, client.clone());
+        Ok(client)
+    }
+}
+
+/// Where a response redirects to, if it is a redirect
+pub fn redirect_target(url: &Url, status: reqwest::StatusCode, headers: &HeaderMap) -> anyhow::Result<Option<Url>> {
+    if !status.is_redirection() || status == reqwest::StatusCode::NOT_MODIFIED {return Ok(None);}
+    let Some(location) = headers.get(reqwest::header::LOCATION) else {return Ok(None)};
+    let location = location.to_str().map_err(|e| anyhow!("Invalid redirect location : {}", e))?;
+    url.join(location).map(Some).map_err(|e| anyhow!("Invalid redirect location {} : {}", location, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(url: &str, allowed_hosts: &[&str]) -> anyhow::Result<Option<Vec<SocketAddr>>> {
+        let allowed_hosts : Vec<String> = allowed_hosts.iter().map(|host| host.to_string()).collect();
+        check_url(&Url::parse(url).unwrap(), &allowed_hosts)
+    }
+
+    #[test]
+    fn allows_only_the_allowed_hosts() {
+        assert!(check("https://images.example.com/a.png", &["cdn.example.com", "Images.Example.com"]).unwrap().is_none());
+        assert_eq!(check("https://evil.example.com/a.png", &["images.example.com"]).unwrap_err().to_string(), "Host evil.example.com is not allowed");
+    }
+
+    #[test]
+    fn blocks_private_addresses_without_allowed_hosts() {
+        for url in ["https://127.0.0.1/a.png", "http://10.0.0.1/a.png", "http://169.254.169.254/latest", "http://[::1]/a.png", "http://[::ffff:127.0.0.1]/a.png"] {
+            let error = check(url, &[]).unwrap_err().to_string();
+            assert!(error.ends_with("is not a public address"), "{} : {}", url, error);
+        }
+        assert!(check("https://93.184.216.34/a.png", &[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn fetches_only_http_and_https_urls() {
+        for url in ["http://example.com/a.png", "https://example.com/a.png", "HTTP://example.com/a.png"] {
+            assert!(is_url(url).unwrap(), "{}", url);
+        }
+        for path in ["images/a.png", "C:\\images\\a.png", "./a://b.png"] {
+            assert!(!is_url(path).unwrap_or(true), "{}", path);
+        }
+        assert_eq!(is_url("ftp://example.com/a.png").unwrap_err().to_string(), "Unsupported scheme ftp of ftp://example.com/a.png, only http and https are supported");
+    }
+}
@@ -0,0 +1,143 @@
+//! Helpers shared by the tests of the library
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use crate::{ImageOptions, PictoServer, SetupOptions};
+
+/// An empty directory named after the test, in the temporary directory
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join("picto-crab-tests").join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn solid_image(width: u32, height: u32, color: [u8; 3]) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, Rgb(color)))
+}
+
+/// An image of pseudo random pixels, which compresses badly
+pub fn noise_image(width: u32, height: u32) -> DynamicImage {
+    let mut state = 0x2545_F491u32;
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |_, _| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let [r, g, b, _] = state.to_le_bytes();
+        Rgb([r, g, b])
+    }))
+}
+
+pub fn encode(img: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    img.write_to(&mut bytes, format).unwrap();
+    bytes
+}
+
+/// TIFF data with only the EXIF orientation tag
+pub fn exif_tiff(orientation: u16) -> Vec<u8> {
+    let mut tiff = b"MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+    tiff.extend_from_slice(&orientation.to_be_bytes());
+    tiff.extend_from_slice(&[0; 6]);
+    tiff
+}
+
+/// The JPEG image with an EXIF segment telling the orientation, right after the start of image marker
+pub fn with_jpeg_orientation(jpeg_bytes: &[u8], orientation: u16) -> Vec<u8> {
+    let segment = [&b"Exif\0\0"[..], &exif_tiff(orientation)].concat();
+    let mut img_bytes = vec![0xFF, 0xD8, 0xFF, 0xE1];
+    img_bytes.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+    img_bytes.extend(segment);
+    img_bytes.extend_from_slice(&jpeg_bytes[2..]);
+    img_bytes
+}
+
+/// Writes the image to `dir` and returns its absolute path, so tests don't depend on the working directory
+pub fn write_image(dir: &Path, name: &str, img: &DynamicImage, format: ImageFormat) -> String {
+    let path = dir.join(name);
+    std::fs::write(&path, encode(img, format)).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+/// The setup options of the tests, with which images are always cached in memory
+pub fn setup_options() -> SetupOptions {
+    SetupOptions {thread_count: 2, min_available_memory: 0, ..Default::default()}
+}
+
+/// A server caching in the `cache` subdirectory of `dir`
+pub fn server(dir: &Path, options: &SetupOptions) -> PictoServer {
+    PictoServer::new(dir.join("cache").to_str().unwrap(), true, options).unwrap()
+}
+
+/// The image options of these arguments, like `get` would parse them
+pub fn image_options(args: &[&str]) -> ImageOptions {
+    let (options, options_count) = ImageOptions::parse(args).unwrap();
+    assert_eq!(options_count, args.len());
+    options
+}
+
+pub fn decode(img_bytes: &[u8]) -> DynamicImage {
+    image::load_from_memory(img_bytes).unwrap()
+}
+
+/// A complete response with this body, after which the connection stays open
+pub fn http_response(body: &[u8]) -> Vec<u8> {
+    let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+/// Setup options, with which images can be fetched from the local HTTP servers of the tests
+pub fn http_options() -> SetupOptions {
+    SetupOptions {allowed_hosts: vec!["127.0.0.1".to_string()], ..setup_options()}
+}
+
+/// A HTTP server on a free local port, answering every request with what `respond` returns for its request line and headers
+pub struct HttpServer {
+    address: String,
+    connection_count: Arc<AtomicUsize>
+}
+
+impl HttpServer {
+    pub fn new(respond: impl Fn(&str) -> Vec<u8> + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let respond = Arc::new(respond);
+        let connection_count = Arc::new(AtomicUsize::new(0));
+        let accepted_count = connection_count.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {continue};
+                accepted_count.fetch_add(1, Ordering::SeqCst);
+                let respond = respond.clone();
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    loop {
+                        let mut head = String::new();
+                        while !head.ends_with("\r\n\r\n") {
+                            if !matches!(reader.read_line(&mut head), Ok(1..)) {return;}
+                        }
+                        let response = respond(&head);
+                        if stream.write_all(&response).is_err() {return;}
+                        // Bodies, which are shorter than their length tells, end like this too
+                        if String::from_utf8_lossy(&response).contains("Connection: close") {return;}
+                    }
+                });
+            }
+        });
+        Self {address, connection_count}
+    }
+
+    /// How many connections were accepted so far
+    pub fn connection_count(&self) -> usize {
+        self.connection_count.load(Ordering::SeqCst)
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.address, path)
+    }
+}
@@ -0,0 +1,122 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// A connected client, commands are read from it and replies are written to it
+pub trait Connection: Read + Write + Send {
+    /// Identifies the client in log messages
+    fn client_name(&self) -> String;
+
+    /// Whether the client may be on another host, so it has to authenticate before changing the server
+    fn is_remote(&self) -> bool {
+        false
+    }
+}
+
+pub trait Listener: Send {
+    fn accept(&self) -> io::Result<Box<dyn Connection>>;
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::ffi::OsStr;
+    use std::io;
+    use interprocess::os::windows::named_pipe::{PipeListener, DuplexBytePipeStream, PipeListenerOptions, PipeMode};
+    use super::{Connection, Listener};
+
+    impl Connection for DuplexBytePipeStream {
+        fn client_name(&self) -> String {
+            match self.client_process_id() {
+                Ok(process_id) => format!("process {}", process_id),
+                Err(_) => "unknown process".to_string()
+            }
+        }
+    }
+
+    impl Listener for PipeListener<DuplexBytePipeStream> {
+        fn accept(&self) -> io::Result<Box<dyn Connection>> {
+            Ok(Box::new(PipeListener::accept(self)?))
+        }
+    }
+
+    /// Listens on the named pipe `\\.\pipe\{name}`
+    pub fn bind_local(name: &str) -> io::Result<Box<dyn Listener>> {
+        let listener : PipeListener<DuplexBytePipeStream> = PipeListenerOptions::new()
+            .name(OsStr::new(name))
+            .mode(PipeMode::Messages)
+            .create()?;
+        Ok(Box::new(listener))
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use std::io;
+    use interprocess::local_socket::{LocalSocketListener, LocalSocketStream, NameTypeSupport};
+    use super::{Connection, Listener};
+
+    impl Connection for LocalSocketStream {
+        fn client_name(&self) -> String {
+            match self.peer_pid() {
+                Ok(process_id) => format!("process {}", process_id),
+                Err(_) => "unknown process".to_string()
+            }
+        }
+    }
+
+    impl Listener for LocalSocketListener {
+        fn accept(&self) -> io::Result<Box<dyn Connection>> {
+            Ok(Box::new(LocalSocketListener::accept(self)?))
+        }
+    }
+
+    /// Listens on the Unix domain socket `@{name}` in the abstract namespace if supported, otherwise on `/tmp/{name}.sock`
+    pub fn bind_local(name: &str) -> io::Result<Box<dyn Listener>> {
+        let socket_name = match NameTypeSupport::query() {
+            NameTypeSupport::OnlyPaths => {
+                let socket_path = format!("/tmp/{}.sock", name);
+                // A socket file left behind by a previous run would make binding fail
+                let _ = std::fs::remove_file(&socket_path);
+                socket_path
+            },
+            NameTypeSupport::OnlyNamespaced | NameTypeSupport::Both => format!("@{}", name)
+        };
+        Ok(Box::new(LocalSocketListener::bind(socket_name)?))
+    }
+}
+
+pub use platform::bind_local;
+
+impl Connection for TcpStream {
+    fn client_name(&self) -> String {
+        match self.peer_addr() {
+            Ok(address) => address.to_string(),
+            Err(_) => "unknown address".to_string()
+        }
+    }
+
+    fn is_remote(&self) -> bool {
+        true
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> io::Result<Box<dyn Connection>> {
+        let (stream, _) = TcpListener::accept(self)?;
+        // Replies are written in small pieces, which should not wait for each other
+        stream.set_nodelay(true)?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// The address to listen on for `address`, which is only a port like `7878` to listen on `127.0.0.1`
+pub fn tcp_bind_address(address: &str) -> String {
+    match address.trim_start_matches(':').parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{}", port),
+        Err(_) => address.to_string()
+    }
+}
+
+/// Listens for TCP connections on `address`, for example `127.0.0.1:7878`
+pub fn bind_tcp(address: &str) -> io::Result<Box<dyn Listener>> {
+    Ok(Box::new(TcpListener::bind(tcp_bind_address(address))?))
+}